@@ -0,0 +1,85 @@
+//! Measures how many instructions per second `Core::step` runs on the full `Bus`,
+//! and `Core::run` with the block cache.
+//!
+//! Run with `cargo bench --bench instructions`.
+//! The CPU test ROMs in `gb-test-roms/cpu_instrs/individual` are benchmarked if present.
+
+use std::fs;
+use std::path::Path;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use good_boi::{Core, Bus, Cartridge};
+
+/// Instructions per iteration of the loop benchmark
+const LOOP_INSTRUCTIONS: u64 = 100_000;
+
+/// Test ROMs are run until they stop or for this many instructions
+const ROM_INSTRUCTIONS: u64 = 1_000_000;
+
+/// Fills WRAM in a loop, mixing ROM fetches with RAM and HRAM accesses
+const PROGRAM: &[u8] = &[
+    0x21, 0x00, 0xC0, // 0100: ld hl, $C000
+    0x22,             // 0103: ld [hl+], a
+    0xC6, 0x03,       // 0104: add a, $03
+    0xE0, 0x80,       // 0106: ldh [$FF80], a
+    0x7C,             // 0108: ld a, h
+    0xFE, 0xD0,       // 0109: cp $D0
+    0xF0, 0x80,       // 010B: ldh a, [$FF80]
+    0x20, 0xF4,       // 010D: jr nz, $0103
+    0xC3, 0x00, 0x01, // 010F: jp $0100
+];
+
+fn load(rom: Vec<u8>) -> Core {
+    Core::new(Bus::new(Cartridge::load(rom).unwrap()))
+}
+
+/// Number of instructions executed before the ROM stops
+fn run(core: &mut Core, limit: u64) -> u64 {
+    (0..limit).take_while(|_| core.step().is_ok()).count() as u64
+}
+
+fn bench_loop(c: &mut Criterion) {
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x100 + PROGRAM.len()].copy_from_slice(PROGRAM);
+
+    let mut core = load(rom.clone());
+    let mut blocks = load(rom);
+    let mut group = c.benchmark_group("loop");
+
+    blocks.set_block_cache(true);
+
+    group.throughput(Throughput::Elements(LOOP_INSTRUCTIONS));
+    group.bench_function("step", |b| b.iter(|| run(&mut core, LOOP_INSTRUCTIONS)));
+    group.bench_function("blocks", |b| b.iter(|| blocks.run(LOOP_INSTRUCTIONS).unwrap()));
+    group.finish();
+}
+
+fn bench_test_roms(c: &mut Criterion) {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("gb-test-roms/cpu_instrs/individual");
+    let mut roms = match fs::read_dir(&dir) {
+        Ok(entries) => entries.map(|entry| entry.unwrap().path()).collect::<Vec<_>>(),
+        Err(_) => return,
+    };
+    roms.sort();
+
+    let mut group = c.benchmark_group("cpu_instrs");
+    group.sample_size(10);
+
+    for path in roms {
+        let rom = fs::read(&path).unwrap();
+        let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+        let instructions = run(&mut load(rom.clone()), ROM_INSTRUCTIONS);
+
+        group.throughput(Throughput::Elements(instructions));
+        // Only running is timed, not loading the ROM
+        group.bench_function(name, |b| b.iter_batched(
+            || load(rom.clone()),
+            |mut core| run(&mut core, instructions),
+            BatchSize::LargeInput,
+        ));
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_loop, bench_test_roms);
+criterion_main!(benches);
@@ -0,0 +1,442 @@
+extern crate good_boi;
+
+use std::collections::{BTreeMap, HashSet};
+use std::error::Error;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+use good_boi::{disassemble, Disassembly, Instruction, Operand, Reg8, Reg16, Cond};
+
+const BANK_SIZE: usize = 0x4000;
+const RST_VECTORS: [u16; 8] = [0x00, 0x08, 0x10, 0x18, 0x20, 0x28, 0x30, 0x38];
+const INTERRUPT_VECTORS: [(u16, &str); 5] = [
+    (0x40, "VBlankInterrupt"),
+    (0x48, "LCDCInterrupt"),
+    (0x50, "TimerInterrupt"),
+    (0x58, "SerialInterrupt"),
+    (0x60, "JoypadInterrupt"),
+];
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args = std::env::args().collect::<Vec<String>>();
+    let (rom_path, out_dir) = match &args[1..] {
+        [rom] => (PathBuf::from(rom), PathBuf::from(".")),
+        [rom, out_dir] => (PathBuf::from(rom), PathBuf::from(out_dir)),
+        _ => return Err("Usage: good_boi-disasm <rom.gb> [output dir]".into()),
+    };
+
+    let rom = fs::read(&rom_path)?;
+    let mut tracer = Tracer::new(&rom);
+
+    tracer.add_entry(0x100, "Entry");
+
+    for &vector in &RST_VECTORS {
+        tracer.add_entry(vector, &format!("Rst_{:02X}", vector));
+    }
+
+    for &(vector, name) in &INTERRUPT_VECTORS {
+        tracer.add_entry(vector, name);
+    }
+
+    tracer.trace();
+    tracer.write(&out_dir)?;
+
+    Ok(())
+}
+
+/// Where execution was found to continue, and the ROMX bank
+/// that is believed to be mapped at that point.
+#[derive(Debug, Copy, Clone)]
+struct Location {
+    bank: usize,
+    addr: u16,
+    romx: Option<usize>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum LabelKind {
+    Relative,
+    Jump,
+    Call,
+    Named,
+}
+
+struct Label {
+    kind: LabelKind,
+    name: String,
+}
+
+/// Known register contents, used to infer bank switches
+#[derive(Debug, Default, Copy, Clone)]
+struct Constants {
+    a: Option<u8>,
+    hl: Option<u16>,
+}
+
+struct Tracer<'a> {
+    rom: &'a [u8],
+    bank_count: usize,
+    worklist: Vec<Location>,
+    /// Instructions found per bank, keyed by address
+    code: Vec<BTreeMap<u16, Disassembly>>,
+    /// ROMX bank in effect for each instruction in bank 0
+    romx: BTreeMap<u16, Option<usize>>,
+    labels: Vec<BTreeMap<u16, Label>>,
+    visited: HashSet<(usize, u16)>,
+}
+
+impl<'a> Tracer<'a> {
+    fn new(rom: &'a [u8]) -> Self {
+        let bank_count = rom.len().div_ceil(BANK_SIZE).max(1);
+
+        Self {
+            rom,
+            bank_count,
+            worklist: Vec::new(),
+            code: (0..bank_count).map(|_| BTreeMap::new()).collect(),
+            romx: BTreeMap::new(),
+            labels: (0..bank_count).map(|_| BTreeMap::new()).collect(),
+            visited: HashSet::new(),
+        }
+    }
+
+    fn add_entry(&mut self, addr: u16, name: &str) {
+        // With only two banks, bank 1 can't be switched out
+        let romx = if self.bank_count == 2 { Some(1) } else { None };
+
+        self.add_label(0, addr, LabelKind::Named, name.into());
+        self.worklist.push(Location { bank: 0, addr, romx });
+    }
+
+    fn add_label(&mut self, bank: usize, addr: u16, kind: LabelKind, name: String) {
+        let label = self.labels[bank].entry(addr).or_insert(Label { kind, name: name.clone() });
+
+        if kind > label.kind {
+            *label = Label { kind, name };
+        }
+    }
+
+    /// Bank that `addr` refers to, if it can be determined
+    fn bank_for(&self, addr: u16, romx: Option<usize>) -> Option<usize> {
+        match addr {
+            0x0000..=0x3FFF => Some(0),
+            0x4000..=0x7FFF => romx,
+            _ => None,
+        }
+    }
+
+    fn offset(&self, bank: usize, addr: u16) -> usize {
+        bank * BANK_SIZE + (addr as usize % BANK_SIZE)
+    }
+
+    fn read(&self, bank: usize, addr: u16) -> Option<u8> {
+        if bank != 0 && addr < 0x4000 {
+            return None;
+        }
+
+        self.rom.get(self.offset(bank, addr)).copied()
+    }
+
+    fn trace(&mut self) {
+        while let Some(location) = self.worklist.pop() {
+            self.trace_from(location);
+        }
+    }
+
+    fn trace_from(&mut self, mut location: Location) {
+        let mut constants = Constants::default();
+
+        loop {
+            let Location { bank, addr, romx } = location;
+
+            if !self.visited.insert((bank, addr)) {
+                return;
+            }
+
+            let disassembly = disassemble(addr, |addr| self.read(bank, addr).unwrap_or(0));
+            let end = addr as usize + disassembly.len() as usize;
+
+            // Instructions must not run off the end of their bank or the ROM
+            let bank_end = if addr < 0x4000 { 0x4000 } else { 0x8000 };
+            if end > bank_end || self.offset(bank, addr) + disassembly.len() as usize > self.rom.len() {
+                return;
+            }
+
+            if disassembly.instruction == Instruction::Invalid {
+                return;
+            }
+
+            let romx = self.track_bank_switch(&disassembly, &mut constants).or(romx);
+
+            if bank == 0 {
+                self.romx.insert(addr, romx);
+            }
+
+            self.follow_target(&disassembly, bank, romx);
+
+            let falls_through = !matches!(
+                disassembly.instruction,
+                Instruction::Jp(Cond::Always, _) | Instruction::Jr(Cond::Always, _)
+                    | Instruction::Ret(Cond::Always) | Instruction::Reti
+            );
+
+            let next = disassembly.next_addr();
+            self.code[bank].insert(addr, disassembly);
+
+            if !falls_through || next as usize >= bank_end {
+                return;
+            }
+
+            location = Location { bank, addr: next, romx };
+        }
+    }
+
+    fn follow_target(&mut self, disassembly: &Disassembly, bank: usize, romx: Option<usize>) {
+        let target = match disassembly.target() {
+            Some(target) => target,
+            None => return,
+        };
+
+        // Code in a ROMX bank runs with that bank mapped
+        let romx = if bank != 0 { Some(bank) } else { romx };
+
+        let target_bank = match self.bank_for(target, romx) {
+            Some(target_bank) if target_bank < self.bank_count => target_bank,
+            _ => return,
+        };
+
+        let kind = match disassembly.instruction {
+            Instruction::Jr(..) => LabelKind::Relative,
+            Instruction::Jp(..) => LabelKind::Jump,
+            Instruction::Rst(_) => LabelKind::Named,
+            _ => LabelKind::Call,
+        };
+
+        let name = match kind {
+            LabelKind::Relative => format!("jr_{:02X}_{:04X}", target_bank, target),
+            LabelKind::Jump => format!("Jump_{:02X}_{:04X}", target_bank, target),
+            LabelKind::Call => format!("Call_{:02X}_{:04X}", target_bank, target),
+            LabelKind::Named => format!("Rst_{:02X}", target),
+        };
+
+        self.add_label(target_bank, target, kind, name);
+        self.worklist.push(Location { bank: target_bank, addr: target, romx });
+    }
+
+    /// Keeps track of constant values in A and HL to spot
+    /// MBC ROM bank selection (writes to 0x2000-0x3FFF).
+    /// Returns the newly selected bank, if any.
+    fn track_bank_switch(&self, disassembly: &Disassembly, constants: &mut Constants) -> Option<usize> {
+        let imm8 = || disassembly.bytes.get(1).copied();
+        let imm16 = || Some(u16::from_le_bytes([*disassembly.bytes.get(1)?, *disassembly.bytes.get(2)?]));
+        let is_bank_register = |addr: Option<u16>| addr.is_some_and(|addr| (0x2000..=0x3FFF).contains(&addr));
+
+        let written = match disassembly.instruction {
+            Instruction::Ld(Operand::Reg8(Reg8::A), Operand::Imm8) => {
+                constants.a = imm8();
+                None
+            },
+            Instruction::Ld(Operand::Reg16(Reg16::HL), Operand::Imm16) => {
+                constants.hl = imm16();
+                None
+            },
+            Instruction::Ld(Operand::Imm16Ref, Operand::Reg8(Reg8::A)) if is_bank_register(imm16()) => constants.a,
+            Instruction::Ld(Operand::RegRef16(Reg16::HL), Operand::Reg8(Reg8::A)) if is_bank_register(constants.hl) => constants.a,
+            Instruction::Ld(Operand::RegRef16(Reg16::HL), Operand::Imm8) if is_bank_register(constants.hl) => imm8(),
+            // Calls may change anything
+            Instruction::Call(..) | Instruction::Rst(_) => {
+                *constants = Constants::default();
+                None
+            },
+            _ => {
+                if writes_a(disassembly.instruction) {
+                    constants.a = None;
+                }
+
+                if writes_hl(disassembly.instruction) {
+                    constants.hl = None;
+                }
+
+                None
+            },
+        };
+
+        written.map(|value| {
+            let bank = match value & 0x1F {
+                0 => 1,
+                bank => bank as usize,
+            };
+
+            bank % self.bank_count
+        })
+    }
+
+    fn write(&self, out_dir: &Path) -> Result<(), Box<dyn Error>> {
+        fs::create_dir_all(out_dir)?;
+
+        let layouts = (0..self.bank_count)
+            .map(|bank| self.layout(bank))
+            .collect::<Vec<_>>();
+
+        // Labels can only be placed where an item starts
+        let placed = layouts.iter().enumerate()
+            .map(|(bank, items)| items.iter()
+                .map(Item::addr)
+                .filter(|addr| self.labels[bank].contains_key(addr))
+                .collect::<HashSet<u16>>())
+            .collect::<Vec<_>>();
+
+        let mut main = String::new();
+
+        writeln!(main, "; Disassembled by good_boi-disasm")?;
+        writeln!(main, "; Build with: rgbasm -o game.o game.asm && rgblink -o game.gb game.o")?;
+        writeln!(main)?;
+
+        for (bank, items) in layouts.iter().enumerate() {
+            let file_name = format!("bank_{:02X}.asm", bank);
+
+            fs::write(out_dir.join(&file_name), self.render_bank(bank, items, &placed)?)?;
+            writeln!(main, "INCLUDE \"{}\"", file_name)?;
+        }
+
+        fs::write(out_dir.join("game.asm"), main)?;
+
+        Ok(())
+    }
+
+    fn render_bank(&self, bank: usize, items: &[Item], placed: &[HashSet<u16>]) -> Result<String, Box<dyn Error>> {
+        let mut out = String::new();
+
+        if bank == 0 {
+            writeln!(out, "SECTION \"ROM Bank $000\", ROM0[$0000]")?;
+        } else {
+            writeln!(out, "SECTION \"ROM Bank ${:03X}\", ROMX[$4000], BANK[${:03X}]", bank, bank)?;
+        }
+
+        for item in items {
+            if let Some(label) = self.labels[bank].get(&item.addr()).filter(|_| placed[bank].contains(&item.addr())) {
+                writeln!(out)?;
+                writeln!(out, "{}:", label.name)?;
+            }
+
+            match item {
+                Item::Code(disassembly) => {
+                    let romx = if bank == 0 { self.romx.get(&disassembly.addr).copied().flatten() } else { Some(bank) };
+                    let text = disassembly.render(|addr| self.label_name(addr, romx, placed));
+
+                    writeln!(out, "    {:<24} ; ${:04X}", text, disassembly.addr)?;
+                },
+                Item::Data(addr, bytes) => {
+                    let bytes = bytes.iter()
+                        .map(|byte| format!("${:02X}", byte))
+                        .collect::<Vec<String>>()
+                        .join(", ");
+
+                    writeln!(out, "    db {:<40} ; ${:04X}", bytes, addr)?;
+                },
+            }
+        }
+
+        Ok(out)
+    }
+
+    /// Splits a bank into instructions and data.
+    /// Data runs are broken up wherever a label might be placed.
+    fn layout(&self, bank: usize) -> Vec<Item<'_>> {
+        let base = if bank == 0 { 0x0000 } else { 0x4000 };
+        let size = (self.rom.len() - bank * BANK_SIZE).min(BANK_SIZE);
+        let mut items = Vec::new();
+        let mut data: Option<(u16, Vec<u8>)> = None;
+        let mut offset = 0;
+
+        while offset < size {
+            let addr = base + offset as u16;
+
+            // If instructions overlap, only the first one is emitted.
+            // Labels inside of it can't be placed and are referenced numerically instead.
+            if let Some(disassembly) = self.code[bank].get(&addr).filter(|d| reassembles(d)) {
+                items.extend(data.take().map(|(addr, bytes)| Item::Data(addr, bytes)));
+                items.push(Item::Code(disassembly));
+                offset += disassembly.len() as usize;
+                continue;
+            }
+
+            let byte = self.rom[bank * BANK_SIZE + offset];
+            let split = self.labels[bank].contains_key(&addr)
+                || data.as_ref().is_some_and(|(_, bytes)| bytes.len() == 8);
+
+            if split {
+                items.extend(data.take().map(|(addr, bytes)| Item::Data(addr, bytes)));
+            }
+
+            data.get_or_insert_with(|| (addr, Vec::new())).1.push(byte);
+            offset += 1;
+        }
+
+        items.extend(data.take().map(|(addr, bytes)| Item::Data(addr, bytes)));
+        items
+    }
+
+    fn label_name(&self, addr: u16, romx: Option<usize>, placed: &[HashSet<u16>]) -> Option<String> {
+        let target_bank = self.bank_for(addr, romx)?;
+
+        if !placed.get(target_bank)?.contains(&addr) {
+            return None;
+        }
+
+        self.labels[target_bank].get(&addr).map(|label| label.name.clone())
+    }
+}
+
+enum Item<'a> {
+    Code(&'a Disassembly),
+    Data(u16, Vec<u8>),
+}
+
+impl Item<'_> {
+    fn addr(&self) -> u16 {
+        match self {
+            Item::Code(disassembly) => disassembly.addr,
+            Item::Data(addr, _) => *addr,
+        }
+    }
+}
+
+/// Whether RGBDS assembles the rendered instruction back into the same bytes
+fn reassembles(disassembly: &Disassembly) -> bool {
+    match disassembly.instruction {
+        // RGBDS always emits `stop` as 10 00
+        Instruction::Stop => disassembly.bytes[1] == 0x00,
+        // RGBDS may optimise these into the shorter `ldh`
+        Instruction::Ld(Operand::Imm16Ref, Operand::Reg8(Reg8::A))
+        | Instruction::Ld(Operand::Reg8(Reg8::A), Operand::Imm16Ref) => disassembly.bytes[2] != 0xFF,
+        Instruction::Invalid => false,
+        _ => true,
+    }
+}
+
+fn writes_a(instruction: Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::Ld(Operand::Reg8(Reg8::A), _) | Instruction::Ldh(Operand::Reg8(Reg8::A), _)
+            | Instruction::Inc(Operand::Reg8(Reg8::A)) | Instruction::Dec(Operand::Reg8(Reg8::A))
+            | Instruction::Add(Operand::Reg8(Reg8::A), _) | Instruction::Adc(_) | Instruction::Sub(_) | Instruction::Sbc(..)
+            | Instruction::And(_) | Instruction::Xor(_) | Instruction::Or(_)
+            | Instruction::Rlca | Instruction::Rrca | Instruction::Rla | Instruction::Rra | Instruction::Daa | Instruction::Cpl
+            | Instruction::Pop(Reg16::AF) | Instruction::Extended
+    )
+}
+
+fn writes_hl(instruction: Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::Ld(Operand::Reg16(Reg16::HL), _) | Instruction::Ldhl
+            | Instruction::Ld(Operand::Reg8(Reg8::H), _) | Instruction::Ld(Operand::Reg8(Reg8::L), _)
+            | Instruction::Ld(_, Operand::RegRef16(Reg16::HLInc)) | Instruction::Ld(_, Operand::RegRef16(Reg16::HLDec))
+            | Instruction::Ld(Operand::RegRef16(Reg16::HLInc), _) | Instruction::Ld(Operand::RegRef16(Reg16::HLDec), _)
+            | Instruction::Inc(Operand::Reg16(Reg16::HL)) | Instruction::Dec(Operand::Reg16(Reg16::HL))
+            | Instruction::Inc(Operand::Reg8(Reg8::H)) | Instruction::Dec(Operand::Reg8(Reg8::H))
+            | Instruction::Inc(Operand::Reg8(Reg8::L)) | Instruction::Dec(Operand::Reg8(Reg8::L))
+            | Instruction::Add(Operand::Reg16(Reg16::HL), _) | Instruction::Pop(Reg16::HL) | Instruction::Extended
+    )
+}
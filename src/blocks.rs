@@ -0,0 +1,252 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+use crate::bus::MemoryBus;
+use crate::core::{Core, StepError};
+use crate::instruction::Instruction;
+use crate::symbols::BankAddr;
+
+/// Executes the instruction of one opcode at pc, see `Core::execute_opcode`
+pub(crate) type Handler<B> = fn(&mut Core<B>) -> Result<(), StepError>;
+
+/// Longest block that is translated at once
+pub(crate) const MAX_BLOCK_LEN: usize = 64;
+
+/// Entries of the direct-mapped table in front of the block map
+const RECENT_BLOCKS: usize = 1024;
+
+/// Blocks compared between memory checks in `find_divergence`
+const MEMORY_CHECK_INTERVAL: u64 = 4096;
+
+pub(crate) struct CachedInstruction<B> {
+    pub handler: Handler<B>,
+    pub instruction: Instruction,
+}
+
+/// Straight-line code, ending with the first instruction that may jump
+pub(crate) struct Block<B> {
+    pub location: BankAddr,
+    /// Address after the last instruction
+    pub end: u16,
+    /// Whether the code is in RAM and has to be dropped when written
+    pub writable: bool,
+    pub instructions: Vec<CachedInstruction<B>>,
+}
+
+/// Translated basic blocks, keyed by where they start.
+///
+/// ROM blocks stay valid as the key includes the bank.
+/// RAM blocks are dropped when one of their bytes is written.
+pub(crate) struct BlockCache<B> {
+    blocks: HashMap<BankAddr, Rc<Block<B>>>,
+    /// Recently run blocks by their lowest address bits, to skip hashing in loops
+    recent: Vec<Option<Rc<Block<B>>>>,
+    /// One bit per address that is part of a RAM block
+    code: Vec<u64>,
+}
+
+impl<B> BlockCache<B> {
+    pub fn new() -> Self {
+        Self {
+            blocks: HashMap::new(),
+            recent: vec![None; RECENT_BLOCKS],
+            code: vec![0; 0x10000 / 64],
+        }
+    }
+
+    pub fn get(&mut self, location: BankAddr) -> Option<Rc<Block<B>>> {
+        let slot = location.addr as usize % RECENT_BLOCKS;
+
+        if let Some(block) = &self.recent[slot] {
+            if block.location == location {
+                return Some(block.clone());
+            }
+        }
+
+        let block = self.blocks.get(&location)?.clone();
+        self.recent[slot] = Some(block.clone());
+        Some(block)
+    }
+
+    pub fn insert(&mut self, block: Block<B>) -> Rc<Block<B>> {
+        if block.writable {
+            mark_code(&mut self.code, &block);
+        }
+
+        let block = Rc::new(block);
+        self.blocks.insert(block.location, block.clone());
+        self.recent[block.location.addr as usize % RECENT_BLOCKS] = Some(block.clone());
+        block
+    }
+
+    /// Drops RAM blocks containing `addr`, which is about to be written.
+    /// Returns whether any were dropped.
+    pub fn invalidate(&mut self, addr: u16) -> bool {
+        let addr = match addr {
+            // Echo RAM
+            0xE000..=0xFDFF => addr - 0x2000,
+            _ => addr,
+        };
+
+        if self.code[addr as usize / 64] & 1 << (addr % 64) == 0 {
+            return false;
+        }
+
+        self.blocks.retain(|_, block| !block.writable || !(block.location.addr..block.end).contains(&addr));
+        self.recent.iter_mut().for_each(|block| *block = None);
+
+        // Overlapping blocks may still cover some of the dropped code
+        self.code.iter_mut().for_each(|word| *word = 0);
+
+        for block in self.blocks.values().filter(|block| block.writable) {
+            mark_code(&mut self.code, block);
+        }
+
+        true
+    }
+
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+        self.recent.iter_mut().for_each(|block| *block = None);
+        self.code.iter_mut().for_each(|word| *word = 0);
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+}
+
+fn mark_code<B>(code: &mut [u64], block: &Block<B>) {
+    for addr in block.location.addr..block.end {
+        code[addr as usize / 64] |= 1 << (addr % 64);
+    }
+}
+
+/// End of the memory region a block starting at `addr` must stay within,
+/// or `None` if code there is not cached.
+/// Regions are split where banks are switched.
+pub(crate) fn region_end(addr: u16) -> Option<u16> {
+    match addr {
+        0x0000..=0x3FFF => Some(0x4000),
+        0x4000..=0x7FFF => Some(0x8000),
+        0xC000..=0xDFFF => Some(0xE000),
+        0xFF80..=0xFFFE => Some(0xFFFF),
+        _ => None,
+    }
+}
+
+/// Whether a block has to end after `instruction`
+pub(crate) fn ends_block(instruction: Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::Jr(..)
+        | Instruction::Jp(..)
+        | Instruction::Call(..)
+        | Instruction::Ret(_)
+        | Instruction::Reti
+        | Instruction::Rst(_)
+        | Instruction::Halt
+        | Instruction::Stop
+        | Instruction::Invalid
+        | Instruction::Di
+        | Instruction::Ei
+    )
+}
+
+/// First point where block execution differs from the interpreter
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    /// Instructions both executed before
+    pub instructions: u64,
+    pub interpreter: String,
+    pub blocks: String,
+}
+
+/// Runs `interpreter` one instruction at a time and `blocks` a block at a time,
+/// for up to `limit` instructions or until both fail the same way.
+/// Registers are compared after every block, all memory every few thousand blocks and at the end.
+/// Both cores must start from the same state, the block cache is enabled on `blocks`.
+pub fn find_divergence<B: MemoryBus>(interpreter: &mut Core<B>, blocks: &mut Core<B>, limit: u64) -> Option<Divergence> {
+    blocks.set_block_cache(true);
+
+    let mut executed = 0;
+    let mut block_count = 0;
+
+    while executed < limit {
+        let start = blocks.instruction_count();
+        let blocks_result = blocks.run_block(limit - executed);
+        let count = blocks.instruction_count() - start;
+        let mut interpreter_result = Ok(());
+
+        for _ in 0..count {
+            interpreter_result = interpreter.step();
+
+            if interpreter_result.is_err() {
+                break;
+            }
+        }
+
+        // The instruction that failed in block mode must fail in the interpreter too
+        if interpreter_result.is_ok() && blocks_result.is_err() {
+            interpreter_result = interpreter.step();
+        }
+
+        executed += count;
+        block_count += 1;
+
+        if interpreter_result != blocks_result {
+            return Some(Divergence {
+                instructions: executed,
+                interpreter: describe_result(&interpreter_result),
+                blocks: describe_result(&blocks_result),
+            });
+        }
+
+        if let Some(divergence) = compare_registers(interpreter, blocks, executed) {
+            return Some(divergence);
+        }
+
+        if block_count % MEMORY_CHECK_INTERVAL == 0 {
+            if let Some(divergence) = compare_memory(interpreter, blocks, executed) {
+                return Some(divergence);
+            }
+        }
+
+        if blocks_result.is_err() || (count == 0 && blocks.is_locked_up()) {
+            break;
+        }
+    }
+
+    compare_memory(interpreter, blocks, executed)
+}
+
+fn describe_result(result: &Result<(), StepError>) -> String {
+    match result {
+        Ok(()) => "Ok".into(),
+        Err(err) => err.to_string(),
+    }
+}
+
+fn compare_registers<B: MemoryBus>(interpreter: &Core<B>, blocks: &Core<B>, executed: u64) -> Option<Divergence> {
+    let interpreter_line = interpreter.trace_line();
+    let blocks_line = blocks.trace_line();
+
+    if interpreter_line == blocks_line && interpreter.interrupts_enabled() == blocks.interrupts_enabled() {
+        return None;
+    }
+
+    Some(Divergence {
+        instructions: executed,
+        interpreter: format!("{} IME:{}", interpreter_line, interpreter.interrupts_enabled() as u8),
+        blocks: format!("{} IME:{}", blocks_line, blocks.interrupts_enabled() as u8),
+    })
+}
+
+fn compare_memory<B: MemoryBus>(interpreter: &Core<B>, blocks: &Core<B>, executed: u64) -> Option<Divergence> {
+    let addr = (0..=0xFFFF).find(|&addr| interpreter.peek_mem_u8(addr) != blocks.peek_mem_u8(addr))?;
+
+    Some(Divergence {
+        instructions: executed,
+        interpreter: format!("[{:04X}] = {:02X}", addr, interpreter.peek_mem_u8(addr)),
+        blocks: format!("[{:04X}] = {:02X}", addr, blocks.peek_mem_u8(addr)),
+    })
+}
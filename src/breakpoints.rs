@@ -0,0 +1,275 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::slice;
+use failure::{bail, Error, Fallible};
+use crate::core::Core;
+use crate::expr::Expr;
+use crate::instruction::Instruction;
+use crate::symbols::BankAddr;
+
+/// Stops execution at an address, optionally only while a specific bank is mapped.
+/// Shared by the debugger, the gdb stub and the debug adapter.
+pub struct Breakpoint {
+    /// Assigned by `Breakpoints`
+    pub id: u64,
+    pub addr: u16,
+    /// `None` matches whichever bank is mapped
+    pub bank: Option<usize>,
+    pub condition: Option<Expr>,
+    /// Stops on every hit if `None`
+    pub hit_condition: Option<HitCondition>,
+    /// Tracepoints report this expression instead of stopping
+    pub trace: Option<Expr>,
+    /// The source file the breakpoint was set in, if any
+    pub source: Option<PathBuf>,
+    /// How often the condition has been met
+    pub hits: u64,
+}
+
+impl Breakpoint {
+    pub fn new(location: BankAddr) -> Self {
+        Self {
+            bank: Some(location.bank),
+            ..Self::at_addr(location.addr)
+        }
+    }
+
+    /// A breakpoint at `addr` in any bank
+    pub fn at_addr(addr: u16) -> Self {
+        Self {
+            id: 0,
+            addr,
+            bank: None,
+            condition: None,
+            hit_condition: None,
+            trace: None,
+            source: None,
+            hits: 0,
+        }
+    }
+
+    pub fn location(&self) -> Option<BankAddr> {
+        self.bank.map(|bank| BankAddr { bank, addr: self.addr })
+    }
+
+    fn matches(&self, location: BankAddr) -> bool {
+        self.addr == location.addr && self.bank.map_or(true, |bank| bank == location.bank)
+    }
+}
+
+/// `5`, `>= 5`, `> 5`, `== 5` or `% 5`; a plain number stops from that hit on
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum HitCondition {
+    AtLeast(u64),
+    Greater(u64),
+    Equal(u64),
+    Multiple(u64),
+}
+
+impl HitCondition {
+    pub fn parse(text: &str) -> Fallible<Self> {
+        let text = text.trim();
+        let (constructor, number): (fn(u64) -> Self, &str) = if let Some(number) = text.strip_prefix(">=") {
+            (HitCondition::AtLeast, number)
+        } else if let Some(number) = text.strip_prefix('>') {
+            (HitCondition::Greater, number)
+        } else if let Some(number) = text.strip_prefix("==") {
+            (HitCondition::Equal, number)
+        } else if let Some(number) = text.strip_prefix('%') {
+            (HitCondition::Multiple, number)
+        } else {
+            (HitCondition::AtLeast, text)
+        };
+
+        Ok(constructor(number.trim().parse()?))
+    }
+
+    pub fn is_met(self, hits: u64) -> bool {
+        match self {
+            HitCondition::AtLeast(count) => hits >= count,
+            HitCondition::Greater(count) => hits > count,
+            HitCondition::Equal(count) => hits == count,
+            HitCondition::Multiple(count) => count != 0 && hits % count == 0,
+        }
+    }
+}
+
+impl fmt::Display for HitCondition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HitCondition::AtLeast(count) => write!(f, ">= {}", count),
+            HitCondition::Greater(count) => write!(f, "> {}", count),
+            HitCondition::Equal(count) => write!(f, "== {}", count),
+            HitCondition::Multiple(count) => write!(f, "% {}", count),
+        }
+    }
+}
+
+/// A breakpoint at pc whose condition is met
+pub enum Hit<'a> {
+    /// Execution should stop
+    Break(&'a Breakpoint),
+    /// A tracepoint with the value of its expression
+    Trace(&'a Breakpoint, Fallible<i64>),
+    /// The condition couldn't be evaluated. This stops, so that it can be fixed.
+    Error(&'a Breakpoint, Error),
+}
+
+impl<'a> Hit<'a> {
+    pub fn breakpoint(&self) -> &'a Breakpoint {
+        match *self {
+            Hit::Break(breakpoint) | Hit::Trace(breakpoint, _) | Hit::Error(breakpoint, _) => breakpoint,
+        }
+    }
+
+    pub fn stops(&self) -> bool {
+        !matches!(self, Hit::Trace(..))
+    }
+}
+
+pub struct Breakpoints {
+    breakpoints: Vec<Breakpoint>,
+    next_id: u64,
+}
+
+impl Default for Breakpoints {
+    fn default() -> Self {
+        Self {
+            breakpoints: Vec::new(),
+            next_id: 1,
+        }
+    }
+}
+
+impl Breakpoints {
+    /// Returns the id assigned to the breakpoint
+    pub fn add(&mut self, mut breakpoint: Breakpoint) -> u64 {
+        let id = self.next_id;
+
+        self.next_id += 1;
+        breakpoint.id = id;
+        self.breakpoints.push(breakpoint);
+
+        id
+    }
+
+    /// Removes the breakpoints matching `remove` and adds `breakpoints` in their place.
+    /// Returns the ids of the added breakpoints, in order.
+    pub fn replace(&mut self, mut remove: impl FnMut(&Breakpoint) -> bool, breakpoints: Vec<Breakpoint>) -> Vec<u64> {
+        self.breakpoints.retain(|breakpoint| !remove(breakpoint));
+
+        breakpoints.into_iter().map(|breakpoint| self.add(breakpoint)).collect()
+    }
+
+    /// Removes the breakpoint at `index` in `iter` order
+    pub fn remove(&mut self, index: usize) -> Option<Breakpoint> {
+        if index < self.breakpoints.len() {
+            Some(self.breakpoints.remove(index))
+        } else {
+            None
+        }
+    }
+
+    pub fn retain(&mut self, keep: impl FnMut(&Breakpoint) -> bool) {
+        self.breakpoints.retain(keep);
+    }
+
+    pub fn iter(&self) -> slice::Iter<'_, Breakpoint> {
+        self.breakpoints.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.breakpoints.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.breakpoints.is_empty()
+    }
+
+    /// Counts hits at pc. Tracepoints are evaluated.
+    pub fn check(&mut self, core: &Core) -> Vec<Hit<'_>> {
+        let location = core.bank_addr(core.pc());
+        let mut hits = Vec::new();
+
+        for breakpoint in &mut self.breakpoints {
+            if !breakpoint.matches(location) {
+                continue;
+            }
+
+            let condition = breakpoint.condition.as_ref().map_or(Ok(true), |condition| condition.is_true(core));
+
+            match condition {
+                Ok(true) => breakpoint.hits += 1,
+                Ok(false) => continue,
+                Err(err) => {
+                    hits.push(Hit::Error(breakpoint, err));
+                    continue;
+                },
+            }
+
+            if !breakpoint.hit_condition.map_or(true, |hit_condition| hit_condition.is_met(breakpoint.hits)) {
+                continue;
+            }
+
+            hits.push(match &breakpoint.trace {
+                Some(trace) => Hit::Trace(breakpoint, trace.eval(core)),
+                None => Hit::Break(breakpoint),
+            });
+        }
+
+        hits
+    }
+
+    /// Whether a breakpoint would stop at pc, ignoring hit conditions since they only apply going forward.
+    /// Neither counts hits nor evaluates tracepoints.
+    pub fn would_stop(&self, core: &Core) -> bool {
+        let location = core.bank_addr(core.pc());
+
+        self.breakpoints.iter()
+            .filter(|breakpoint| breakpoint.matches(location) && breakpoint.trace.is_none())
+            .any(|breakpoint| breakpoint.condition.as_ref().map_or(true, |condition| condition.is_true(core).unwrap_or(true)))
+    }
+}
+
+/// How far execution should go before stopping again
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RunMode {
+    Continue,
+    /// Until a `call` or `rst` returned
+    StepOver { return_addr: u16, sp: u16 },
+    /// Until the call stack is shallower than `depth`
+    StepOut { depth: usize },
+}
+
+impl RunMode {
+    /// Steps over a `call` or `rst` at pc.
+    /// `None` for other instructions, which are simply stepped into.
+    pub fn step_over(core: &Core) -> Option<Self> {
+        let disassembly = core.disassemble(core.pc());
+
+        match disassembly.instruction {
+            Instruction::Call(..) | Instruction::Rst(_) => Some(RunMode::StepOver {
+                return_addr: disassembly.next_addr(),
+                sp: core.sp(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Runs until the innermost tracked call has returned
+    pub fn step_out(core: &Core) -> Fallible<Self> {
+        match core.call_stack().depth() {
+            0 => bail!("Not inside a tracked call"),
+            depth => Ok(RunMode::StepOut { depth }),
+        }
+    }
+
+    /// Whether execution has arrived, checked after every instruction
+    pub fn is_done(self, core: &Core) -> bool {
+        match self {
+            RunMode::Continue => false,
+            RunMode::StepOver { return_addr, sp } => core.pc() == return_addr && core.sp() >= sp,
+            RunMode::StepOut { depth } => core.call_stack().depth() < depth,
+        }
+    }
+}
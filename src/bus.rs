@@ -1,68 +1,598 @@
-use parking_lot::Mutex;
+use failure::Fallible;
+use std::cell::{Cell, RefCell};
+use std::mem;
 use crate::cartridge::Cartridge;
+use crate::hdma::{Hdma, HdmaMode, HDMA_BLOCK_SIZE, HDMA_BLOCK_CYCLES};
+use crate::joypad::{Joypad, Button};
+use crate::sgb::{Sgb, SGB_TRANSFER_SIZE};
+use crate::state::{SaveState, StateWriter, StateReader, StateHeader};
+use crate::watchpoint::{Watchpoint, WatchKind, WatchHit};
 use crate::constants::*;
 
+/// T-cycles the LCD takes per line, including HBlank
+const LINE_CYCLES: u32 = 456;
+/// Lines per frame, 144 visible ones followed by VBlank
+const FRAME_LINES: u8 = 154;
+/// T-cycles into a visible line where HBlank (mode 0) starts,
+/// after OAM search (80) and pixel transfer (172 at the shortest)
+const HBLANK_START: u32 = 252;
+
 pub struct Bus {
-    serial: Mutex<Serial>,
-    cartridge: Mutex<Cartridge>,
-    low_ram: Mutex<Ram>,
-    hi_ram: Mutex<Ram>,
-    unimplemented_warning: Mutex<UnimplementedWarning>,
+    joypad: Joypad,
+    sgb: Option<Sgb>,
+    serial: Serial,
+    cartridge: Cartridge,
+    vram: Vram,
+    low_ram: Ram,
+    hi_ram: Ram,
+    lcdc: Register,
+    hdma: Hdma,
+    stall_cycles: u32,
+    /// Current LCD line and the T-cycles spent in it
+    line: u8,
+    line_cycles: u32,
+    /// Frames the LCD has completed
+    frames: u64,
+    /// What LY reads when stubbed, see `stub_ly`
+    ly_stub: Option<Register>,
+    unimplemented_warning: UnimplementedWarning,
+    watchpoints: Vec<Watchpoint>,
+    watch_hit: Cell<Option<WatchHit>>,
 }
 
 impl Bus {
     pub fn new(cartridge: Cartridge) -> Self {
+        let sgb = if cartridge.supports_sgb() {
+            Some(Sgb::new())
+        } else {
+            None
+        };
+
         Self {
-            serial: Mutex::new(Serial::default()),
-            cartridge: Mutex::new(cartridge),
-            low_ram: Mutex::new(Ram::new(LO_RAM_SIZE)),
-            hi_ram: Mutex::new(Ram::new(HI_RAM_SIZE)),
-            unimplemented_warning: Mutex::new(UnimplementedWarning),
+            joypad: Joypad::new(),
+            sgb,
+            serial: Serial::default(),
+            cartridge,
+            vram: Vram::new(),
+            low_ram: Ram::new(LO_RAM_SIZE),
+            hi_ram: Ram::new(HI_RAM_SIZE),
+            lcdc: Register::new(0x91),
+            hdma: Hdma::new(),
+            stall_cycles: 0,
+            line: 0,
+            line_cycles: 0,
+            frames: 0,
+            ly_stub: None,
+            unimplemented_warning: UnimplementedWarning,
+            watchpoints: Vec::new(),
+            watch_hit: Cell::new(None),
         }
     }
 
+    /// Reads open bus (0xFF) where nothing is mapped
     pub fn read(&self, addr: u16) -> u8 {
-        match addr {
-            // TODO: map to boot rom initially
-            0x0000..=0x7FFF => self.cartridge.read(addr),
-            0xC000..=0xDFFF => self.low_ram.read(addr - 0xC000),
-            0xE000..=0xFDFF => self.low_ram.read(addr - 0xE000),
-            VRAM_START..=VRAM_END => self.unimplemented_warning.read(addr),
-            0xFF01..=0xFF02 => self.serial.read(addr),
-            0xFF07..=0xFF7F => self.unimplemented_warning.read(addr),
-            0xFF80..=0xFFFE => self.hi_ram.read(addr - 0xFF80),
-            0xFFFF => self.unimplemented_warning.read(addr),
-            _ => panic!("Invalid read @ 0x{:02X}", addr),
+        self.try_read(addr).unwrap_or(0xFF)
+    }
+
+    /// Returns `None` if nothing is mapped at `addr`
+    pub fn try_read(&self, addr: u16) -> Option<u8> {
+        let (device, device_addr) = self.device_at(addr)?;
+        let value = device.read(device_addr);
+
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(WatchKind::Read, addr, value);
+        }
+
+        Some(value)
+    }
+
+    /// Reads without any side effects, open bus (0xFF) where nothing is mapped.
+    /// Neither devices nor watchpoints notice, so debuggers can inspect any address.
+    pub fn peek(&self, addr: u16) -> u8 {
+        match self.device_at(addr) {
+            Some((device, device_addr)) => device.peek(device_addr),
+            None => 0xFF,
         }
     }
 
+    /// Whether reads and writes at `addr` reach a device
+    pub fn is_mapped(&self, addr: u16) -> bool {
+        self.device_at(addr).is_some()
+    }
+
+    /// The device that is read at `addr`, and the address it expects
+    fn device_at(&self, addr: u16) -> Option<(&dyn Device, u16)> {
+        if let (IO_REG_LY, Some(ly)) = (addr, &self.ly_stub) {
+            return Some((ly, addr));
+        }
+
+        Some(match addr {
+            // TODO: map to boot rom initially
+            0x0000..=0x7FFF => (&self.cartridge, addr),
+            CART_RAM_START..=CART_RAM_END => (&self.cartridge, addr),
+            0xC000..=0xDFFF => (&self.low_ram, addr - 0xC000),
+            0xE000..=0xFDFF => (&self.low_ram, addr - 0xE000),
+            VRAM_START..=VRAM_END => (&self.vram, addr),
+            IO_REG_JOYP => (&self.joypad, addr),
+            0xFF01..=0xFF02 => (&self.serial, addr),
+            IO_REG_LCDC => (&self.lcdc, addr),
+            IO_REG_VBK => (&self.vram, addr),
+            IO_REG_HDMA1..=IO_REG_HDMA5 => (&self.hdma, addr),
+            0xFF07..=0xFF7F => (&self.unimplemented_warning, addr),
+            0xFF80..=0xFFFE => (&self.hi_ram, addr - 0xFF80),
+            0xFFFF => (&self.unimplemented_warning, addr),
+            _ => return None,
+        })
+    }
+
+    /// Writes where nothing is mapped are dropped
     pub fn write(&mut self, addr: u16, value: u8) {
+        self.try_write(addr, value);
+    }
+
+    /// Returns `false` if nothing is mapped at `addr`
+    pub fn try_write(&mut self, addr: u16, value: u8) -> bool {
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(WatchKind::Write, addr, value);
+        }
+
         match addr {
             0x0000..=0x7FFF => self.cartridge.write(addr, value),
+            CART_RAM_START..=CART_RAM_END => self.cartridge.write(addr, value),
             0xC000..=0xDFFF => self.low_ram.write(addr - 0xC000, value),
             0xE000..=0xFDFF => self.low_ram.write(addr - 0xE000, value),
-            VRAM_START..=VRAM_END => self.unimplemented_warning.write(addr, value),
+            VRAM_START..=VRAM_END => self.vram.write(addr, value),
+            IO_REG_JOYP => self.write_joypad(value),
             0xFF01..=0xFF02 => self.serial.write(addr, value),
+            IO_REG_LCDC => self.lcdc.write(addr, value),
+            IO_REG_VBK => self.vram.write(addr, value),
+            IO_REG_HDMA1..=IO_REG_HDMA5 => self.write_hdma(addr, value),
             0xFF07..=0xFF7F => self.unimplemented_warning.write(addr, value),
             0xFF80..=0xFFFE => self.hi_ram.write(addr - 0xFF80, value),
             0xFFFF => self.unimplemented_warning.write(addr, value),
-            _ => panic!("Invalid write @ 0x{:02X}", addr),
+            _ => return false,
         }
+
+        true
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.push(watchpoint);
+    }
+
+    pub fn remove_watchpoint(&mut self, index: usize) -> Option<Watchpoint> {
+        if index < self.watchpoints.len() {
+            Some(self.watchpoints.remove(index))
+        } else {
+            None
+        }
+    }
+
+    /// Returns and resets the first watchpoint hit since the last call
+    pub fn take_watch_hit(&mut self) -> Option<WatchHit> {
+        self.watch_hit.take()
+    }
+
+    fn check_watchpoints(&self, access: WatchKind, addr: u16, value: u8) {
+        if self.watch_hit.get().is_some() {
+            return;
+        }
+
+        self.watch_hit.set(self.watchpoints.iter()
+            .find(|watchpoint| watchpoint.matches(access, addr, value))
+            .map(|&watchpoint| WatchHit { watchpoint, access, addr, value }));
+    }
+
+    /// Bank that is currently mapped at `addr`
+    pub fn bank_at(&self, addr: u16) -> usize {
+        match addr {
+            0x0000..=0x7FFF => self.cartridge.rom_bank(addr),
+            VRAM_START..=VRAM_END => self.vram.bank,
+            CART_RAM_START..=CART_RAM_END => self.cartridge.ram_bank(),
+            // The upper half of WRAM is bank 1 in RGBDS symbols, including its echo
+            0xD000..=LO_RAM_END | 0xF000..=0xFDFF => 1,
+            _ => 0,
+        }
+    }
+
+    /// Decodes a save state into a new bus, which shares the ROM, watchpoints and LY stub with this one.
+    /// `self` is left as it is, so a bad state can't leave it half loaded.
+    pub(crate) fn decode_state(&self, r: &mut StateReader) -> Fallible<Bus> {
+        let mut bus = Bus::new(self.cartridge.clone());
+        bus.watchpoints = self.watchpoints.clone();
+        bus.stub_ly(self.ly_stub.as_ref().map(|ly| ly.value));
+        bus.load_state(r)?;
+
+        Ok(bus)
+    }
+
+    /// Identifies the loaded ROM in save states
+    pub fn state_header(&self) -> StateHeader {
+        StateHeader {
+            title: self.cartridge.title(),
+            global_checksum: self.cartridge.global_checksum(),
+        }
+    }
+
+    pub fn set_pressed(&mut self, player: usize, button: Button, pressed: bool) {
+        self.joypad.set_pressed(player, button, pressed);
+    }
+
+    pub fn sgb_enabled(&self) -> bool {
+        self.sgb.is_some()
+    }
+
+    /// Enables or disables SGB mode.
+    /// By default it is enabled for cartridges that declare SGB support.
+    pub fn set_sgb_enabled(&mut self, enabled: bool) {
+        self.sgb = if enabled { Some(Sgb::new()) } else { None };
+        self.joypad.set_player_count(1);
+    }
+
+    /// Renders a 160x144 frame of shades (0-3) into the 256x224 SGB output, including the border.
+    /// Returns `None` when not in SGB mode.
+    pub fn render_sgb(&mut self, screen: &[u8]) -> Option<Vec<u32>> {
+        self.sgb.as_mut().map(|sgb| sgb.render(screen))
+    }
+
+    pub fn lcd_enabled(&self) -> bool {
+        self.lcdc.value & 0x80 != 0
+    }
+
+    /// Advances the LCD line timing by `cycles` T-cycles.
+    /// There is no PPU yet, only HBlank is signalled, to run HBlank DMA.
+    pub fn tick(&mut self, mut cycles: u32) {
+        // The LCD restarts at line 0 when it is turned back on
+        if !self.lcd_enabled() {
+            self.line = 0;
+            self.line_cycles = 0;
+            return;
+        }
+
+        while cycles > 0 {
+            let step = cycles.min(LINE_CYCLES - self.line_cycles);
+            let entered_hblank = self.line_cycles < HBLANK_START && self.line_cycles + step >= HBLANK_START;
+
+            self.line_cycles += step;
+            cycles -= step;
+
+            if entered_hblank && (self.line as usize) < SCREEN_HEIGHT {
+                self.hblank();
+            }
+
+            if self.line_cycles == LINE_CYCLES {
+                self.line_cycles = 0;
+                self.line = (self.line + 1) % FRAME_LINES;
+
+                if self.line == 0 {
+                    self.frames += 1;
+                }
+            }
+        }
+    }
+
+    /// Makes LY always read `value`, or unimplemented again for `None`.
+    /// Gameboy Doctor logs are made with LY stuck at $90, so that waiting for VBlank doesn't stall.
+    pub fn stub_ly(&mut self, value: Option<u8>) {
+        self.ly_stub = value.map(Register::new);
+    }
+
+    /// Frames the LCD has completed, not counting the time it was off
+    pub fn frame_count(&self) -> u64 {
+        self.frames
+    }
+
+    /// Called whenever the LCD enters HBlank (mode 0).
+    /// Copies the next block of an active HBlank DMA.
+    pub fn hblank(&mut self) {
+        if self.hdma.mode() == HdmaMode::HBlank {
+            self.transfer_hdma_block();
+        }
+    }
+
+    /// Returns and resets the number of cycles (T-states) the CPU
+    /// has to be stalled for because of DMA transfers.
+    pub fn take_stall_cycles(&mut self) -> u32 {
+        std::mem::replace(&mut self.stall_cycles, 0)
+    }
+
+    fn write_joypad(&mut self, value: u8) {
+        self.joypad.write(IO_REG_JOYP, value);
+
+        let (transfer, player_count) = match &mut self.sgb {
+            Some(sgb) => {
+                sgb.write_joypad(value);
+                (sgb.take_pending_transfer(), sgb.player_count())
+            },
+            None => return,
+        };
+
+        if self.joypad.player_count() != player_count {
+            self.joypad.set_player_count(player_count);
+        }
+
+        if let Some(transfer) = transfer {
+            let data = self.read_sgb_transfer();
+
+            if let Some(sgb) = &mut self.sgb {
+                sgb.complete_transfer(transfer, &data);
+            }
+        }
+    }
+
+    /// The SGB receives *_TRN data as the displayed screen.
+    /// Games show the tiles in order, so this reads the tile data
+    /// in the order the current BG addressing mode displays them.
+    fn read_sgb_transfer(&self) -> Vec<u8> {
+        let unsigned_addressing = self.lcdc.value & 0x10 != 0;
+        let start = if unsigned_addressing { 0x8000 } else { 0x9000 };
+
+        (0..SGB_TRANSFER_SIZE as u16)
+            .map(|offset| {
+                let mut addr = start + offset;
+
+                if addr > 0x97FF {
+                    addr -= 0x1000;
+                }
+
+                self.peek(addr)
+            })
+            .collect()
+    }
+
+    fn write_hdma(&mut self, addr: u16, value: u8) {
+        self.hdma.write(addr, value);
+
+        if addr != IO_REG_HDMA5 {
+            return;
+        }
+
+        let mode = self.hdma.mode();
+
+        match mode {
+            HdmaMode::General => {
+                while self.hdma.is_active() {
+                    self.transfer_hdma_block();
+                }
+            },
+            // There are no HBlanks while the LCD is off,
+            // so the first block is copied right away.
+            HdmaMode::HBlank if !self.lcd_enabled() => self.transfer_hdma_block(),
+            _ => {},
+        }
+    }
+
+    fn transfer_hdma_block(&mut self) {
+        let (source, destination) = (self.hdma.source(), self.hdma.destination());
+
+        // Not the CPU reading, so watchpoints don't see it
+        for offset in 0..HDMA_BLOCK_SIZE {
+            let value = self.peek(source.wrapping_add(offset));
+            self.vram.write(destination + offset, value);
+        }
+
+        self.hdma.finish_block();
+        self.stall_cycles += HDMA_BLOCK_CYCLES;
     }
 }
 
-pub trait Device: 'static {
-    fn read(&self, addr: u16) -> u8;
-    fn write(&mut self, addr: u16, value: u8);
+impl SaveState for Bus {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.joypad.save_state(w);
+
+        w.write_bool(self.sgb.is_some());
+        if let Some(sgb) = &self.sgb {
+            sgb.save_state(w);
+        }
+
+        self.serial.save_state(w);
+        self.cartridge.save_state(w);
+        self.vram.save_state(w);
+        self.low_ram.save_state(w);
+        self.hi_ram.save_state(w);
+        self.lcdc.save_state(w);
+        self.hdma.save_state(w);
+        w.write_u32(self.stall_cycles);
+        w.write_u8(self.line);
+        w.write_u32(self.line_cycles);
+        w.write_u64(self.frames);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Fallible<()> {
+        self.joypad.load_state(r)?;
+
+        self.sgb = if r.read_bool()? {
+            let mut sgb = Sgb::new();
+            sgb.load_state(r)?;
+            Some(sgb)
+        } else {
+            None
+        };
+
+        self.serial.load_state(r)?;
+        self.cartridge.load_state(r)?;
+        self.vram.load_state(r)?;
+        self.low_ram.load_state(r)?;
+        self.hi_ram.load_state(r)?;
+        self.lcdc.load_state(r)?;
+        self.hdma.load_state(r)?;
+        self.stall_cycles = r.read_u32()?;
+        self.line = r.read_u8()? % FRAME_LINES;
+        self.line_cycles = r.read_u32()? % LINE_CYCLES;
+        self.frames = r.read_u64()?;
+
+        Ok(())
+    }
 }
 
-impl<T: Device> Device for Mutex<T> {
-    fn read(&self, addr: u16) -> u8 {
-        self.lock().read(addr)
+impl MemoryBus for Bus {
+    fn try_read(&self, addr: u16) -> Option<u8> {
+        Bus::try_read(self, addr)
     }
 
-    fn write(&mut self, addr: u16, value: u8) {
-        self.lock().write(addr, value)
+    fn try_write(&mut self, addr: u16, value: u8) -> bool {
+        Bus::try_write(self, addr, value)
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        Bus::peek(self, addr)
+    }
+
+    fn is_mapped(&self, addr: u16) -> bool {
+        Bus::is_mapped(self, addr)
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        Bus::tick(self, cycles)
+    }
+
+    fn take_stall_cycles(&mut self) -> u32 {
+        Bus::take_stall_cycles(self)
+    }
+
+    fn bank_at(&self, addr: u16) -> usize {
+        Bus::bank_at(self, addr)
+    }
+
+    fn is_rom(&self, addr: u16) -> bool {
+        addr <= ROM_END
+    }
+
+    fn take_watch_hit(&mut self) -> Option<WatchHit> {
+        Bus::take_watch_hit(self)
+    }
+
+    fn has_watch_hit(&self) -> bool {
+        self.watch_hit.get().is_some()
+    }
+
+    fn has_watchpoints(&self) -> bool {
+        !self.watchpoints.is_empty()
+    }
+}
+
+/// Everything the CPU sees of the rest of the system
+pub trait MemoryBus {
+    /// Returns `None` if nothing is mapped at `addr`
+    fn try_read(&self, addr: u16) -> Option<u8>;
+
+    /// Returns `false` if nothing is mapped at `addr`
+    fn try_write(&mut self, addr: u16, value: u8) -> bool;
+
+    /// Reads without side effects for debuggers and disassembly,
+    /// open bus (0xFF) where nothing is mapped
+    fn peek(&self, addr: u16) -> u8;
+
+    /// Whether `try_read` and `try_write` succeed at `addr`
+    fn is_mapped(&self, _addr: u16) -> bool {
+        true
+    }
+
+    /// Called after every instruction with the T-cycles it took
+    fn tick(&mut self, cycles: u32);
+
+    /// Returns and resets the T-cycles the CPU has to wait for DMA transfers.
+    /// They are ticked along with the next instruction.
+    fn take_stall_cycles(&mut self) -> u32 {
+        0
+    }
+
+    /// Bank that is currently mapped at `addr`
+    fn bank_at(&self, _addr: u16) -> usize {
+        0
+    }
+
+    /// Whether `addr` is ROM, which writes can't change.
+    /// Writes there are taken as bank switches.
+    fn is_rom(&self, _addr: u16) -> bool {
+        false
+    }
+
+    /// Returns and resets the first watchpoint hit since the last call
+    fn take_watch_hit(&mut self) -> Option<WatchHit> {
+        None
+    }
+
+    /// Whether `take_watch_hit` would return a hit
+    fn has_watch_hit(&self) -> bool {
+        false
+    }
+
+    /// Whether any watchpoints are set.
+    /// The block cache doesn't fetch opcodes through the bus, so it leaves those to `step`.
+    fn has_watchpoints(&self) -> bool {
+        false
+    }
+}
+
+/// 64 KiB of plain RAM without any devices, for running the CPU in isolation.
+/// Reads and writes are recorded, see `take_accesses`.
+pub struct FlatBus {
+    memory: Vec<u8>,
+    cycles: u64,
+    accesses: RefCell<Vec<(WatchKind, u16, u8)>>,
+}
+
+impl FlatBus {
+    pub fn new() -> Self {
+        Self {
+            memory: vec![0; 0x10000],
+            cycles: 0,
+            accesses: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// T-cycles ticked so far
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Returns and forgets the reads and writes since the last call, oldest first
+    pub fn take_accesses(&mut self) -> Vec<(WatchKind, u16, u8)> {
+        mem::take(self.accesses.get_mut())
+    }
+}
+
+impl Default for FlatBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryBus for FlatBus {
+    fn try_read(&self, addr: u16) -> Option<u8> {
+        let value = self.memory[addr as usize];
+        self.accesses.borrow_mut().push((WatchKind::Read, addr, value));
+        Some(value)
+    }
+
+    fn try_write(&mut self, addr: u16, value: u8) -> bool {
+        self.memory[addr as usize] = value;
+        self.accesses.get_mut().push((WatchKind::Write, addr, value));
+        true
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        self.memory[addr as usize]
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        self.cycles += cycles as u64;
+    }
+}
+
+pub trait Device: 'static {
+    fn read(&self, addr: u16) -> u8;
+    fn write(&mut self, addr: u16, value: u8);
+
+    /// Like `read`, but must neither change state nor log.
+    /// Devices whose reads have side effects override this.
+    fn peek(&self, addr: u16) -> u8 {
+        self.read(addr)
     }
 }
 
@@ -78,30 +608,130 @@ impl Ram {
     }
 }
 
+impl SaveState for Ram {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.data);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Fallible<()> {
+        r.read_bytes_into(&mut self.data)
+    }
+}
+
 impl Device for Ram {
     fn read(&self, addr: u16) -> u8 {
-        self.data.get(addr as usize).copied().unwrap()
+        self.data.get(addr as usize).copied().unwrap_or(0xFF)
     }
 
     fn write(&mut self, addr: u16, value: u8) {
         if let Some(byte) = self.data.get_mut(addr as usize) {
             *byte = value;
-        } else {
-            panic!("Invalid write past end of RAM");
         }
     }
 }
 
+/// CGB VRAM with two switchable banks (VBK)
+struct Vram {
+    banks: [Ram; 2],
+    bank: usize,
+}
+
+impl Vram {
+    fn new() -> Self {
+        Self {
+            banks: [Ram::new(VRAM_SIZE), Ram::new(VRAM_SIZE)],
+            bank: 0,
+        }
+    }
+}
+
+impl SaveState for Vram {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.banks[0].save_state(w);
+        self.banks[1].save_state(w);
+        w.write_usize(self.bank);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Fallible<()> {
+        self.banks[0].load_state(r)?;
+        self.banks[1].load_state(r)?;
+        self.bank = r.read_usize()? & 1;
+        Ok(())
+    }
+}
+
+impl Device for Vram {
+    fn read(&self, addr: u16) -> u8 {
+        match addr {
+            VRAM_START..=VRAM_END => self.banks[self.bank].read(addr - VRAM_START),
+            IO_REG_VBK => 0xFE | self.bank as u8,
+            _ => panic!(),
+        }
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            VRAM_START..=VRAM_END => self.banks[self.bank].write(addr - VRAM_START, value),
+            IO_REG_VBK => self.bank = (value & 1) as usize,
+            _ => panic!(),
+        }
+    }
+}
+
+/// A plain read/write IO register
+struct Register {
+    value: u8,
+}
+
+impl Register {
+    fn new(value: u8) -> Self {
+        Self { value }
+    }
+}
+
+impl SaveState for Register {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.value);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Fallible<()> {
+        self.value = r.read_u8()?;
+        Ok(())
+    }
+}
+
+impl Device for Register {
+    fn read(&self, _addr: u16) -> u8 {
+        self.value
+    }
+
+    fn write(&mut self, _addr: u16, value: u8) {
+        self.value = value;
+    }
+}
+
 #[derive(Default)]
 struct Serial {
     value: u8,
 }
 
+impl SaveState for Serial {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.value);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Fallible<()> {
+        self.value = r.read_u8()?;
+        Ok(())
+    }
+}
+
 impl Device for Serial {
     fn read(&self, addr: u16) -> u8 {
         match addr {
             0xFF01 => self.value,
-            0xFF02 => unimplemented!("Read from SC"),
+            // No transfer in progress, external clock
+            0xFF02 => 0x7E,
             _ => panic!(),
         }
     }
@@ -110,7 +740,7 @@ impl Device for Serial {
         match addr {
             0xFF01 => self.value = value,
             0xFF02 => {
-                println!("SERIAL DEBUG OUTPUT: {:?}", self.value as char);
+                eprintln!("SERIAL DEBUG OUTPUT: {:?}", self.value as char);
             },
             _ => panic!(),
         }
@@ -125,6 +755,10 @@ impl Device for UnimplementedWarning {
         0
     }
 
+    fn peek(&self, _addr: u16) -> u8 {
+        0
+    }
+
     fn write(&mut self, addr: u16, value: u8) {
         eprintln!("Unimplemented write of 0x{:02X} @ 0x{:02X}", value, addr)
     }
@@ -0,0 +1,53 @@
+use crate::symbols::BankAddr;
+
+/// Limits the damage of code that never returns, e.g. by resetting SP
+const MAX_FRAMES: usize = 1024;
+
+/// A function entered via `call` or `rst`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Frame {
+    /// Address of the `call`/`rst` instruction
+    pub call_site: BankAddr,
+    pub function: BankAddr,
+    pub return_addr: u16,
+    /// Where the return address was pushed to
+    pub sp: u16,
+}
+
+/// Call stack reconstructed from executed `call`, `rst` and `ret` instructions.
+///
+/// This is debugger state and not part of save states.
+#[derive(Debug, Default, Clone)]
+pub struct CallStack {
+    frames: Vec<Frame>,
+}
+
+impl CallStack {
+    /// Outermost frame first
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+
+    pub(crate) fn push(&mut self, frame: Frame) {
+        if self.frames.len() >= MAX_FRAMES {
+            self.frames.remove(0);
+        }
+
+        self.frames.push(frame);
+    }
+
+    /// Drops every frame whose return address has been popped off the stack
+    pub(crate) fn unwind(&mut self, sp: u16) {
+        while self.frames.last().is_some_and(|frame| frame.sp < sp) {
+            self.frames.pop();
+        }
+    }
+}
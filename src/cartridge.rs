@@ -1,8 +1,13 @@
+use std::rc::Rc;
 use failure::Fallible;
 use crate::bus::Device;
+use crate::state::{SaveState, StateWriter, StateReader, StateError};
 
 const MBC1_BANK_SIZE_16K: usize = 16 * 1024;
+const MBC1_RAM_BANK_SIZE_8K: usize = 8 * 1024;
 
+/// Clones share the ROM
+#[derive(Clone)]
 pub struct Cartridge {
     mapper: Mapper,
 }
@@ -11,10 +16,51 @@ impl Cartridge {
     pub fn load(rom: impl Into<Vec<u8>>) -> Fallible<Self> {
         eprintln!("TODO: detect correct cartridge type");
 
+        let rom = rom.into();
+        let ram_size = match rom.get(0x149) {
+            Some(1) => 2 * 1024,
+            Some(2) => 8 * 1024,
+            Some(3) => 32 * 1024,
+            _ => 0,
+        };
+
         Ok(Self {
-            mapper: Mapper::MBC1(MBC1::new(rom.into())),
+            mapper: Mapper::MBC1(MBC1::new(rom, ram_size)),
         })
     }
+
+    /// Checks the SGB flag (0x146) and old licensee code (0x14B) in the header
+    pub fn supports_sgb(&self) -> bool {
+        self.read(0x146) == 0x03 && self.read(0x14B) == 0x33
+    }
+
+    /// ROM bank mapped at `addr` (0x0000-0x7FFF)
+    pub fn rom_bank(&self, addr: u16) -> usize {
+        match &self.mapper {
+            Mapper::MBC1(m) => m.rom_bank(addr),
+        }
+    }
+
+    /// RAM bank mapped at 0xA000-0xBFFF
+    pub fn ram_bank(&self) -> usize {
+        match &self.mapper {
+            Mapper::MBC1(m) => m.ram_bank(),
+        }
+    }
+
+    /// Title from the header (0x134-0x143)
+    pub fn title(&self) -> String {
+        (0x134..=0x143)
+            .map(|addr| self.read(addr))
+            .take_while(|&byte| byte != 0)
+            .map(|byte| byte as char)
+            .collect()
+    }
+
+    /// Global checksum from the header (0x14E-0x14F)
+    pub fn global_checksum(&self) -> u16 {
+        (self.read(0x14E) as u16) << 8 | self.read(0x14F) as u16
+    }
 }
 
 impl Device for Cartridge {
@@ -31,58 +77,154 @@ impl Device for Cartridge {
     }
 }
 
+impl SaveState for Cartridge {
+    fn save_state(&self, w: &mut StateWriter) {
+        match &self.mapper {
+            Mapper::MBC1(m) => m.save_state(w),
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Fallible<()> {
+        match &mut self.mapper {
+            Mapper::MBC1(m) => m.load_state(r),
+        }
+    }
+}
+
+#[derive(Clone)]
 enum Mapper {
     MBC1(MBC1),
 }
 
+#[derive(Clone)]
 struct MBC1 {
     rom_bank: usize,
-    rom: Vec<u8>,
+    /// 2 bit register, used as RAM bank or upper ROM bank bits
+    upper_bank: usize,
+    ram_enabled: bool,
+    advanced_banking: bool,
+    rom: Rc<[u8]>,
+    ram: Vec<u8>,
 }
 
 impl MBC1 {
-    pub fn new(rom: Vec<u8>) -> Self {
+    pub fn new(rom: Vec<u8>, ram_size: usize) -> Self {
         Self {
             rom_bank: 1,
-            rom,
+            upper_bank: 0,
+            ram_enabled: false,
+            advanced_banking: false,
+            rom: rom.into(),
+            ram: vec![0; ram_size],
         }
     }
 
     pub fn select_rom_bank(&mut self, mut bank: u8) {
-        // MBC1 only cares for the lowest 1 bytes
+        // MBC1 only cares for the lowest 5 bits
         bank &= 0b1_1111;
 
+        // Bank 0 can't be selected here. This also
+        // turns 0x20, 0x40 and 0x60 into 0x21, 0x41 and 0x61.
         self.rom_bank = match bank {
             0 => 1,
-            20 => 21,
-            40 => 41,
-            60 => 61,
             _ => bank as usize,
         };
     }
 
+    pub fn rom_bank(&self, addr: u16) -> usize {
+        match addr {
+            0x0000..=0x3FFF if self.advanced_banking => self.upper_bank << 5,
+            0x0000..=0x3FFF => 0,
+            _ => self.upper_bank << 5 | self.rom_bank,
+        }
+    }
+
+    pub fn ram_bank(&self) -> usize {
+        if self.advanced_banking { self.upper_bank } else { 0 }
+    }
+
+    /// Read fixed rom at base address 0x0000
+    pub fn read_rom0(&self, addr: u16) -> u8 {
+        let bank = self.rom_bank(addr);
+        let index = bank * MBC1_BANK_SIZE_16K + addr as usize;
+
+        self.rom.get(index).copied().unwrap_or(0)
+    }
+
     /// Read banked rom at base address 0x4000
     pub fn read_rom(&self, addr: u16) -> u8 {
+        let bank = self.rom_bank(addr);
         let addr = addr as usize - 0x4000;
-        let start = self.rom_bank * MBC1_BANK_SIZE_16K;
+        let start = bank * MBC1_BANK_SIZE_16K;
         let index = start + addr;
 
         self.rom.get(index).copied().unwrap_or(0)
     }
+
+    fn ram_index(&self, addr: u16) -> Option<usize> {
+        if !self.ram_enabled || self.ram.is_empty() {
+            return None;
+        }
+
+        let index = self.ram_bank() * MBC1_RAM_BANK_SIZE_8K + (addr as usize - 0xA000);
+
+        Some(index % self.ram.len())
+    }
 }
 
 impl Device for MBC1 {
     fn read(&self, addr: u16) -> u8 {
         match addr {
-            0x0000..=0x3FFF => self.rom.get(addr as usize).copied().unwrap_or(0),
+            0x0000..=0x3FFF => self.read_rom0(addr),
             0x4000..=0x7FFF => self.read_rom(addr),
+            0xA000..=0xBFFF => match self.ram_index(addr) {
+                Some(index) => self.ram[index],
+                None => 0xFF,
+            },
             _ => unimplemented!("MBC1 read @ 0x{:02X}", addr),
         }
     }
 
     fn write(&mut self, addr: u16, value: u8) {
         match addr {
+            0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+            0x2000..=0x3FFF => self.select_rom_bank(value),
+            0x4000..=0x5FFF => self.upper_bank = (value & 0b11) as usize,
+            0x6000..=0x7FFF => self.advanced_banking = value & 1 != 0,
+            0xA000..=0xBFFF => {
+                if let Some(index) = self.ram_index(addr) {
+                    self.ram[index] = value;
+                }
+            },
             _ => unimplemented!("MBC1 write 0x{:02X} @ 0x{:02X}", value, addr),
         }
     }
 }
+
+impl SaveState for MBC1 {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_usize(self.rom_bank);
+        w.write_usize(self.upper_bank);
+        w.write_bool(self.ram_enabled);
+        w.write_bool(self.advanced_banking);
+        w.write_bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Fallible<()> {
+        self.rom_bank = r.read_usize()?;
+        self.upper_bank = r.read_usize()?;
+
+        if !(1..=0x1F).contains(&self.rom_bank) {
+            return Err(StateError::Corrupted(format!("invalid MBC1 ROM bank {}", self.rom_bank)).into());
+        }
+
+        if self.upper_bank > 0b11 {
+            return Err(StateError::Corrupted(format!("invalid MBC1 upper bank {}", self.upper_bank)).into());
+        }
+
+        self.ram_enabled = r.read_bool()?;
+        self.advanced_banking = r.read_bool()?;
+        r.read_bytes_into(&mut self.ram)?;
+        Ok(())
+    }
+}
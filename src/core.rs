@@ -1,7 +1,117 @@
-use crate::instruction::{Instruction, ExtendedInstruction, Cond, Operand, Reg8, Reg16};
-use crate::bus::Bus;
+use std::fmt;
+use std::rc::Rc;
+use failure::Fallible;
+use crate::instruction::{self, Instruction, ExtendedInstruction, Cond, Operand, Reg8, Reg16};
+use crate::bus::{Bus, MemoryBus};
+use crate::disasm::{self, Disassembly};
+use crate::symbols::{Symbols, BankAddr};
+use crate::callstack::{CallStack, Frame};
+use crate::state::{SaveState, StateWriter, StateReader, StateError};
+use crate::watchpoint::WatchKind;
+use crate::trace::Tracer;
+use crate::blocks::{self, Block, BlockCache, CachedInstruction};
+
+/// Calls `handler::<opcode>()` for a runtime `opcode`,
+/// or with `fn Self::handler(opcode)` returns it as a function pointer.
+/// The match over all opcodes compiles to a jump table.
+macro_rules! dispatch {
+    (@arms [$self:ident.$handler:ident($opcode:expr)] $($code:literal)*) => {
+        match $opcode {
+            $($code => $self.$handler::<$code>(),)*
+        }
+    };
+    (@arms [fn Self::$handler:ident($opcode:expr)] $($code:literal)*) => {
+        match $opcode {
+            $($code => Self::$handler::<$code> as fn(&mut Self) -> Result<(), StepError>,)*
+        }
+    };
+    ($($call:tt)*) => {
+        dispatch!(@arms [$($call)*]
+            0x00 0x01 0x02 0x03 0x04 0x05 0x06 0x07 0x08 0x09 0x0A 0x0B 0x0C 0x0D 0x0E 0x0F
+            0x10 0x11 0x12 0x13 0x14 0x15 0x16 0x17 0x18 0x19 0x1A 0x1B 0x1C 0x1D 0x1E 0x1F
+            0x20 0x21 0x22 0x23 0x24 0x25 0x26 0x27 0x28 0x29 0x2A 0x2B 0x2C 0x2D 0x2E 0x2F
+            0x30 0x31 0x32 0x33 0x34 0x35 0x36 0x37 0x38 0x39 0x3A 0x3B 0x3C 0x3D 0x3E 0x3F
+            0x40 0x41 0x42 0x43 0x44 0x45 0x46 0x47 0x48 0x49 0x4A 0x4B 0x4C 0x4D 0x4E 0x4F
+            0x50 0x51 0x52 0x53 0x54 0x55 0x56 0x57 0x58 0x59 0x5A 0x5B 0x5C 0x5D 0x5E 0x5F
+            0x60 0x61 0x62 0x63 0x64 0x65 0x66 0x67 0x68 0x69 0x6A 0x6B 0x6C 0x6D 0x6E 0x6F
+            0x70 0x71 0x72 0x73 0x74 0x75 0x76 0x77 0x78 0x79 0x7A 0x7B 0x7C 0x7D 0x7E 0x7F
+            0x80 0x81 0x82 0x83 0x84 0x85 0x86 0x87 0x88 0x89 0x8A 0x8B 0x8C 0x8D 0x8E 0x8F
+            0x90 0x91 0x92 0x93 0x94 0x95 0x96 0x97 0x98 0x99 0x9A 0x9B 0x9C 0x9D 0x9E 0x9F
+            0xA0 0xA1 0xA2 0xA3 0xA4 0xA5 0xA6 0xA7 0xA8 0xA9 0xAA 0xAB 0xAC 0xAD 0xAE 0xAF
+            0xB0 0xB1 0xB2 0xB3 0xB4 0xB5 0xB6 0xB7 0xB8 0xB9 0xBA 0xBB 0xBC 0xBD 0xBE 0xBF
+            0xC0 0xC1 0xC2 0xC3 0xC4 0xC5 0xC6 0xC7 0xC8 0xC9 0xCA 0xCB 0xCC 0xCD 0xCE 0xCF
+            0xD0 0xD1 0xD2 0xD3 0xD4 0xD5 0xD6 0xD7 0xD8 0xD9 0xDA 0xDB 0xDC 0xDD 0xDE 0xDF
+            0xE0 0xE1 0xE2 0xE3 0xE4 0xE5 0xE6 0xE7 0xE8 0xE9 0xEA 0xEB 0xEC 0xED 0xEE 0xEF
+            0xF0 0xF1 0xF2 0xF3 0xF4 0xF5 0xF6 0xF7 0xF8 0xF9 0xFA 0xFB 0xFC 0xFD 0xFE 0xFF
+        )
+    };
+}
+
+/// Why an instruction could not be executed.
+/// The instruction has no effect: registers and memory are left as they were before it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StepError {
+    /// One of the opcodes that lock up the CPU on hardware
+    IllegalOpcode { location: BankAddr, opcode: u8 },
+    UnmappedAccess { location: BankAddr, access: WatchKind, addr: u16 },
+    Unimplemented { location: BankAddr, feature: String },
+}
+
+impl StepError {
+    /// The instruction that failed
+    pub fn location(&self) -> BankAddr {
+        match *self {
+            StepError::IllegalOpcode { location, .. } => location,
+            StepError::UnmappedAccess { location, .. } => location,
+            StepError::Unimplemented { location, .. } => location,
+        }
+    }
+}
+
+impl fmt::Display for StepError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let location = self.location();
+
+        match self {
+            StepError::IllegalOpcode { opcode, .. } => write!(f, "Illegal opcode {:02X}", opcode)?,
+            StepError::UnmappedAccess { access: WatchKind::Write, addr, .. } => write!(f, "Write to unmapped {:04X}", addr)?,
+            StepError::UnmappedAccess { addr, .. } => write!(f, "Read from unmapped {:04X}", addr)?,
+            StepError::Unimplemented { feature, .. } => write!(f, "Unimplemented: {}", feature)?,
+        }
+
+        write!(f, " at {:02X}:{:04X}", location.bank, location.addr)
+    }
+}
+
+impl std::error::Error for StepError {}
 
-pub struct Core {
+/// What happens on one of the opcodes that don't exist
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IllegalOpcodePolicy {
+    /// `Core::step` fails with `StepError::IllegalOpcode`, leaving pc at the opcode
+    Error,
+    /// Like hardware: the CPU stops fetching instructions for good,
+    /// while the rest of the system keeps running
+    LockUp,
+}
+
+/// T-cycles that pass per `Core::step` while the CPU is locked up
+const LOCKED_UP_CYCLES: u32 = 4;
+
+/// Registers saved before each instruction, to undo it if it fails
+#[derive(Copy, Clone)]
+struct Registers {
+    pc: u16,
+    sp: u16,
+    af: u16,
+    bc: u16,
+    de: u16,
+    hl: u16,
+    interrupts_enabled: bool,
+}
+
+/// The CPU, running on anything that implements `MemoryBus`
+pub struct Core<B = Bus> {
     pc: u16,
     sp: u16,
     reg_a: u8,
@@ -16,11 +126,23 @@ pub struct Core {
     // ram: Vec<u8>,
     interrupts_enabled: bool,
     // mapper: Mapper,
-    bus: Bus,
+    bus: B,
+    symbols: Symbols,
+    call_stack: CallStack,
+    /// Start of the instruction being executed, for error locations
+    instruction_addr: u16,
+    illegal_opcode_policy: IllegalOpcodePolicy,
+    locked_up: bool,
+    tracer: Option<Tracer>,
+    /// Set while the block cache is enabled
+    blocks: Option<BlockCache<B>>,
+    /// Set by writes that change code or banks, to end the running block
+    block_interrupted: bool,
+    instructions: u64,
 }
 
-impl Core {
-    pub fn new(bus: Bus) -> Self {
+impl<B: MemoryBus> Core<B> {
+    pub fn new(bus: B) -> Self {
         Self {
             pc: 0x100,
             sp: 0xFFFE,
@@ -37,6 +159,15 @@ impl Core {
             interrupts_enabled: true,
             // mapper: Mapper::Rom,
             bus,
+            symbols: Symbols::default(),
+            call_stack: CallStack::default(),
+            instruction_addr: 0x100,
+            illegal_opcode_policy: IllegalOpcodePolicy::Error,
+            locked_up: false,
+            tracer: None,
+            blocks: None,
+            block_interrupted: false,
+            instructions: 0,
         }
     }
 
@@ -44,19 +175,387 @@ impl Core {
         self.pc
     }
 
+    pub fn set_pc(&mut self, value: u16) {
+        self.pc = value;
+    }
+
+    pub fn sp(&self) -> u16 {
+        self.sp
+    }
+
+    pub fn set_sp(&mut self, value: u16) {
+        self.sp = value;
+    }
+
+    pub fn interrupts_enabled(&self) -> bool {
+        self.interrupts_enabled
+    }
+
+    pub fn set_interrupts_enabled(&mut self, enabled: bool) {
+        self.interrupts_enabled = enabled;
+    }
+
+    pub fn illegal_opcode_policy(&self) -> IllegalOpcodePolicy {
+        self.illegal_opcode_policy
+    }
+
+    pub fn set_illegal_opcode_policy(&mut self, policy: IllegalOpcodePolicy) {
+        self.illegal_opcode_policy = policy;
+    }
+
+    /// Whether an illegal opcode locked up the CPU.
+    /// Only loading a state from before that recovers.
+    pub fn is_locked_up(&self) -> bool {
+        self.locked_up
+    }
+
+    pub fn tracer(&self) -> Option<&Tracer> {
+        self.tracer.as_ref()
+    }
+
+    pub fn tracer_mut(&mut self) -> Option<&mut Tracer> {
+        self.tracer.as_mut()
+    }
+
+    /// Traces every instruction before it is executed
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer;
+    }
+
+    pub fn take_tracer(&mut self) -> Option<Tracer> {
+        self.tracer.take()
+    }
+
+    /// Enables caching translated blocks for `run_block`, or drops the cache
+    pub fn set_block_cache(&mut self, enabled: bool) {
+        match (enabled, &self.blocks) {
+            (true, None) => self.blocks = Some(BlockCache::new()),
+            (false, _) => self.blocks = None,
+            (true, Some(_)) => {},
+        }
+    }
+
+    pub fn block_cache_enabled(&self) -> bool {
+        self.blocks.is_some()
+    }
+
+    /// Number of translated blocks in the cache
+    pub fn cached_blocks(&self) -> usize {
+        self.blocks.as_ref().map_or(0, BlockCache::len)
+    }
+
+    /// Instructions executed successfully since the core was created
+    pub fn instruction_count(&self) -> u64 {
+        self.instructions
+    }
+
+    /// The state before the instruction at pc, in Gameboy Doctor format
+    pub fn trace_line(&self) -> String {
+        format!(
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+            self.reg_a, self.reg_f, self.reg_b, self.reg_c, self.reg_d, self.reg_e, self.reg_h, self.reg_l,
+            self.sp, self.pc,
+            self.peek_mem_u8(self.pc),
+            self.peek_mem_u8(self.pc.wrapping_add(1)),
+            self.peek_mem_u8(self.pc.wrapping_add(2)),
+            self.peek_mem_u8(self.pc.wrapping_add(3)),
+        )
+    }
+
+    pub fn call_stack(&self) -> &CallStack {
+        &self.call_stack
+    }
+
+    /// For restoring the call stack kept alongside a save state
+    pub(crate) fn set_call_stack(&mut self, call_stack: CallStack) {
+        self.call_stack = call_stack;
+    }
+
+    pub fn bus(&self) -> &B {
+        &self.bus
+    }
+
+    pub fn bus_mut(&mut self) -> &mut B {
+        &mut self.bus
+    }
+
     pub fn current_instruction(&self) -> Instruction {
         let code = self.peek_mem_u8(self.pc);
         Instruction::decode(code)
     }
 
     pub fn current_extended_instruction(&self) -> ExtendedInstruction {
-        let code = self.peek_mem_u8(self.pc + 1);
+        let code = self.peek_mem_u8(self.pc.wrapping_add(1));
         ExtendedInstruction::decode(code)
     }
 
-    pub fn step(&mut self) {
-        let instruction = self.current_instruction();
-        self.execute(instruction);
+    pub fn disassemble(&self, addr: u16) -> Disassembly {
+        disasm::disassemble(addr, |addr| self.peek_mem_u8(addr))
+    }
+
+    pub fn symbols(&self) -> &Symbols {
+        &self.symbols
+    }
+
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
+
+    /// Qualifies `addr` with the bank that is currently mapped there
+    pub fn bank_addr(&self, addr: u16) -> BankAddr {
+        BankAddr {
+            bank: self.bus.bank_at(addr),
+            addr,
+        }
+    }
+
+    /// Formats `addr` as `bank:addr`, followed by `label+offset` if there is a symbol for it
+    pub fn format_addr(&self, addr: u16) -> String {
+        self.format_location(self.bank_addr(addr))
+    }
+
+    pub fn format_location(&self, location: BankAddr) -> String {
+        match self.symbols.format(location) {
+            Some(label) => format!("{:02X}:{:04X} <{}>", location.bank, location.addr, label),
+            None => format!("{:02X}:{:04X}", location.bank, location.addr),
+        }
+    }
+
+    /// Renders a disassembled instruction, using symbols for jump targets and addresses
+    pub fn render_instruction(&self, disassembly: &Disassembly) -> String {
+        disassembly.render(|addr| {
+            self.symbols.name(self.bank_addr(addr)).map(str::to_owned)
+        })
+    }
+
+    pub fn step(&mut self) -> Result<(), StepError> {
+        if self.locked_up {
+            // Only the rest of the system keeps running
+            self.bus.take_watch_hit();
+            self.bus.tick(LOCKED_UP_CYCLES);
+            return Ok(());
+        }
+
+        if let Some(mut tracer) = self.tracer.take() {
+            let location = self.bank_addr(self.pc);
+
+            if tracer.wants(location) {
+                tracer.record(location, self.trace_line());
+            }
+
+            self.tracer = Some(tracer);
+        }
+
+        // Only report watchpoint hits caused by this instruction,
+        // not by a debugger writing memory in between
+        self.bus.take_watch_hit();
+
+        let pc = self.pc;
+        let sp = self.sp;
+        let registers = self.registers();
+
+        self.instruction_addr = pc;
+
+        let result = match self.read_mem_u8(pc) {
+            Ok(opcode) => dispatch!(self.execute_opcode(opcode)).map(|()| opcode),
+            Err(err) => Err(err),
+        };
+
+        let opcode = match result {
+            Ok(opcode) => opcode,
+            Err(err) => {
+                self.restore_registers(registers);
+
+                if let Some(tracer) = &self.tracer {
+                    tracer.dump_history();
+                }
+
+                return Err(err);
+            },
+        };
+
+        self.instructions += 1;
+        self.track_calls(Instruction::decode(opcode), pc, sp);
+
+        Ok(())
+    }
+
+    /// Executes the basic block at pc from the block cache, translating it first if needed,
+    /// but no more than `limit` instructions.
+    /// Stops early after an instruction that switched banks, wrote cached code or hit a watchpoint.
+    ///
+    /// Steps a single instruction instead if the cache is disabled, a tracer or watchpoints are set
+    /// or pc is outside of ROM, WRAM and HRAM or unmapped.
+    /// Writes through `bus_mut` are not seen by the cache.
+    pub fn run_block(&mut self, limit: u64) -> Result<(), StepError> {
+        let block = match &self.blocks {
+            Some(_) if !self.locked_up && self.tracer.is_none() && !self.bus.has_watchpoints() => self.block_at(self.pc),
+            _ => None,
+        };
+
+        let block = match block {
+            Some(block) => block,
+            None => return self.step(),
+        };
+
+        self.bus.take_watch_hit();
+        self.block_interrupted = false;
+
+        for cached in block.instructions.iter().take(limit as usize) {
+            let pc = self.pc;
+            let sp = self.sp;
+
+            let registers = self.registers();
+
+            if let Err(err) = (cached.handler)(self) {
+                self.restore_registers(registers);
+                return Err(err);
+            }
+
+            self.instructions += 1;
+            self.track_calls(cached.instruction, pc, sp);
+
+            if self.block_interrupted || self.bus.has_watch_hit() {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Runs blocks until `limit` instructions were executed or one fails.
+    /// While the CPU is locked up, each remaining instruction is a `step` that only clocks the bus.
+    pub fn run(&mut self, limit: u64) -> Result<(), StepError> {
+        let mut remaining = limit;
+
+        while remaining > 0 {
+            if self.locked_up {
+                self.step()?;
+                remaining -= 1;
+            } else {
+                let start = self.instructions;
+                self.run_block(remaining)?;
+                remaining -= self.instructions - start;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn block_at(&mut self, pc: u16) -> Option<Rc<Block<B>>> {
+        let end = blocks::region_end(pc)?;
+        let location = self.bank_addr(pc);
+
+        if let Some(block) = self.blocks.as_mut()?.get(location) {
+            return Some(block);
+        }
+
+        let block = self.translate(location, end)?;
+
+        Some(self.blocks.as_mut()?.insert(block))
+    }
+
+    /// Decodes the instructions from `start` up to the end of its block.
+    /// Only opcodes are cached, operands are still read when executing.
+    fn translate(&self, location: BankAddr, end: u16) -> Option<Block<B>> {
+        let start = location.addr;
+        let mut instructions = Vec::new();
+        let mut addr = start;
+
+        while instructions.len() < blocks::MAX_BLOCK_LEN {
+            let opcode = self.peek_mem_u8(addr);
+            let instruction = Instruction::decode(opcode);
+
+            // The rest of the instruction may be in another bank
+            if addr as u32 + instruction.len() as u32 > end as u32 {
+                break;
+            }
+
+            // Left to `step`, which reports fetching the opcode as an error
+            if !self.bus.is_mapped(addr) {
+                break;
+            }
+
+            instructions.push(CachedInstruction {
+                handler: dispatch!(fn Self::execute_opcode(opcode)),
+                instruction,
+            });
+            addr += instruction.len();
+
+            if blocks::ends_block(instruction) {
+                break;
+            }
+        }
+
+        if instructions.is_empty() {
+            return None;
+        }
+
+        Some(Block {
+            location,
+            end: addr,
+            writable: !self.bus.is_rom(start),
+            instructions,
+        })
+    }
+
+    /// Keeps the block cache in sync with a write to `addr`
+    #[inline(always)]
+    fn invalidate_blocks(&mut self, addr: u16) {
+        if self.blocks.is_some() {
+            self.invalidate_cached_blocks(addr);
+        }
+    }
+
+    #[inline(never)]
+    fn invalidate_cached_blocks(&mut self, addr: u16) {
+        if let Some(blocks) = &mut self.blocks {
+            // Writing to ROM switches banks
+            if self.bus.is_rom(addr) || blocks.invalidate(addr) {
+                self.block_interrupted = true;
+            }
+        }
+    }
+
+    fn registers(&self) -> Registers {
+        Registers {
+            pc: self.pc,
+            sp: self.sp,
+            af: self.reg_af(),
+            bc: self.reg_bc(),
+            de: self.reg_de(),
+            hl: self.reg_hl(),
+            interrupts_enabled: self.interrupts_enabled,
+        }
+    }
+
+    /// Undoes an instruction that failed.
+    /// Memory needs no undoing, since instructions only write once nothing can fail anymore.
+    fn restore_registers(&mut self, registers: Registers) {
+        self.pc = registers.pc;
+        self.sp = registers.sp;
+        self.set_reg_af(registers.af);
+        self.set_reg_bc(registers.bc);
+        self.set_reg_de(registers.de);
+        self.set_reg_hl(registers.hl);
+        self.interrupts_enabled = registers.interrupts_enabled;
+    }
+
+    fn track_calls(&mut self, instruction: Instruction, pc: u16, sp: u16) {
+        match instruction {
+            Instruction::Call(..) | Instruction::Rst(_) if self.sp == sp.wrapping_sub(2) => {
+                self.call_stack.push(Frame {
+                    call_site: self.bank_addr(pc),
+                    function: self.bank_addr(self.pc),
+                    return_addr: pc.wrapping_add(instruction.len()),
+                    sp: self.sp,
+                });
+            },
+            Instruction::Ret(_) | Instruction::Reti if self.sp == sp.wrapping_add(2) => {
+                self.call_stack.unwind(self.sp);
+            },
+            _ => {},
+        }
     }
 
     pub fn reg_hl(&self) -> u16 {
@@ -161,61 +660,109 @@ impl Core {
         eprintln!("de= {de:04X}", de = self.reg_de());
         eprintln!("hl= {hl:04X}", hl = self.reg_hl());
         eprintln!("sp= {sp:04X}", sp = self.sp);
-        eprintln!("pc= {sp:04X}", sp = self.pc);
-        eprintln!("nn= {nn:04X}", nn = self.peek_mem_u16(self.pc+1));
+        eprintln!("pc= {}", self.format_addr(self.pc));
+        eprintln!("nn= {nn:04X}", nn = self.peek_mem_u16(self.pc.wrapping_add(1)));
         eprintln!("ZNHC");
         eprintln!("{:04b}", self.reg_f >> 4);
 
-        let instruction = self.current_instruction();
-
-        eprintln!("→ {:?}", instruction);
+        eprintln!("→ {}", self.render_instruction(&self.disassemble(self.pc)));
+    }
 
-        if instruction == Instruction::Extended {
-            eprintln!("→ {:?}", self.current_extended_instruction());
-        }
+    /// `execute` specialised for one opcode.
+    /// The instruction is decoded at compile time, so its operands are resolved statically.
+    #[inline(never)]
+    fn execute_opcode<const OPCODE: u8>(&mut self) -> Result<(), StepError> {
+        self.execute(const { instruction::decode(OPCODE) })
     }
 
-    pub fn execute(&mut self, instruction: Instruction) {
-        self.pc += 1;
+    #[inline(always)]
+    pub fn execute(&mut self, instruction: Instruction) -> Result<(), StepError> {
+        let cycles = self.cycles(instruction);
+
+        self.instruction_addr = self.pc;
+        self.pc = self.pc.wrapping_add(1);
 
         match instruction {
             Instruction::Nop => {},
-            Instruction::Jr(cond, offset) => self.execute_jr(cond, offset),
-            Instruction::Jp(cond, addr) => self.execute_jp(cond, addr),
-            Instruction::Call(cond, addr) => self.execute_call(cond, addr),
+            Instruction::Jr(cond, offset) => self.execute_jr(cond, offset)?,
+            Instruction::Jp(cond, addr) => self.execute_jp(cond, addr)?,
+            Instruction::Call(cond, addr) => self.execute_call(cond, addr)?,
             Instruction::Di => self.execute_di(),
-            Instruction::Ld(target, source) => self.execute_ld(target, source),
-            Instruction::Ldh(target, source) => self.execute_ldh(target, source),
-            Instruction::Push(source) => self.execute_push(source),
-            Instruction::Pop(target) => self.execute_pop(target),
-            Instruction::Cp(source) => self.execute_cp(source),
-            Instruction::Add(target, value) => self.execute_add(target, value),
-            Instruction::Adc(value) => self.execute_add_carry(value),
-            Instruction::Sub(value) => self.execute_sub(value),
-            Instruction::Inc(target) => self.execute_inc(target),
-            Instruction::Dec(target) => self.execute_dec(target),
-            Instruction::Or(value) => self.execute_or(value),
-            Instruction::Xor(value) => self.execute_xor(value),
-            Instruction::And(value) => self.execute_and(value),
-            Instruction::Rra => self.execute_rotate_right_a(),
-            Instruction::Rla => self.execute_rotate_left_a(),
-            Instruction::Ret(cond) => self.execute_ret(cond),
+            Instruction::Ld(target, source) => self.execute_ld(target, source)?,
+            Instruction::Ldh(target, source) => self.execute_ldh(target, source)?,
+            Instruction::Push(source) => self.execute_push(source)?,
+            Instruction::Pop(target) => self.execute_pop(target)?,
+            Instruction::Cp(source) => self.execute_cp(source)?,
+            Instruction::Add(target, value) => self.execute_add(target, value)?,
+            Instruction::Adc(value) => self.execute_add_carry(value)?,
+            Instruction::Sub(value) => self.execute_sub(value)?,
+            Instruction::Inc(target) => self.execute_inc(target)?,
+            Instruction::Dec(target) => self.execute_dec(target)?,
+            Instruction::Or(value) => self.execute_or(value)?,
+            Instruction::Xor(value) => self.execute_xor(value)?,
+            Instruction::And(value) => self.execute_and(value)?,
+            Instruction::Rra => self.execute_rotate_right_a()?,
+            Instruction::Rla => self.execute_rotate_left_a()?,
+            Instruction::Ret(cond) => self.execute_ret(cond)?,
+            Instruction::Reti => self.execute_reti()?,
+            Instruction::Rst(addr) => self.execute_rst(addr)?,
             Instruction::Extended => {
-                let code = self.read_mem_u8(self.pc);
-                let instruction = ExtendedInstruction::decode(code);
-                self.execute_extended(instruction);
+                let code = self.read_mem_u8(self.pc)?;
+                dispatch!(self.execute_extended_opcode(code))?;
             }
-            _ => {
-                self.pc -= 1;
-                self.print_state();
-                unimplemented!("execute: {:?}", instruction)
+            Instruction::Invalid => match self.illegal_opcode_policy {
+                IllegalOpcodePolicy::Error => {
+                    return Err(StepError::IllegalOpcode {
+                        location: self.bank_addr(self.instruction_addr),
+                        opcode: self.peek_mem_u8(self.instruction_addr),
+                    });
+                },
+                IllegalOpcodePolicy::LockUp => {
+                    self.pc = self.instruction_addr;
+                    self.locked_up = true;
+                },
             },
+            _ => return Err(self.unimplemented(format!("{:?}", instruction))),
         }
+
+        // Time keeps running for the rest of the system while the CPU is stalled
+        let stall = self.bus.take_stall_cycles();
+        self.bus.tick(cycles as u32 + stall);
+
+        Ok(())
     }
 
-    fn execute_add(&mut self, target: Operand, value: Operand) {
-        let a = self.load_operand(target);
-        let b = self.load_operand(value);
+    /// T-cycles `instruction` at pc is going to take
+    #[inline(always)]
+    fn cycles(&self, instruction: Instruction) -> u8 {
+        match instruction {
+            Instruction::Extended => self.current_extended_instruction().cycles(),
+            Instruction::Jr(cond, _)
+            | Instruction::Jp(cond, _)
+            | Instruction::Call(cond, _)
+            | Instruction::Ret(cond) => instruction.cycles(self.evaluate_cond(cond)),
+            _ => instruction.cycles(true),
+        }
+    }
+
+    fn unimplemented(&self, feature: String) -> StepError {
+        StepError::Unimplemented {
+            location: self.bank_addr(self.instruction_addr),
+            feature,
+        }
+    }
+
+    fn unmapped(&self, access: WatchKind, addr: u16) -> StepError {
+        StepError::UnmappedAccess {
+            location: self.bank_addr(self.instruction_addr),
+            access,
+            addr,
+        }
+    }
+
+    fn execute_add(&mut self, target: Operand, value: Operand) -> Result<(), StepError> {
+        let a = self.load_operand(target)?;
+        let b = self.load_operand(value)?;
 
         match (a, b) {
             (Value::U8(a), Value::U8(b)) => {
@@ -227,139 +774,150 @@ impl Core {
                 self.set_flag_h(half_carry);
                 self.set_flag_c(carry);
 
-                self.store_operand_u8(target, value);
+                self.store_operand_u8(target, value)
             },
             (Value::U16(a), Value::U16(b)) => {
                 let (value, carry) = a.overflowing_add(b);
-                let (_, half_carry) = (a << 8).overflowing_add(b << 8);
+                // From bit 11, Z is left alone
+                let (_, half_carry) = (a << 4).overflowing_add(b << 4);
 
-                self.set_flag_z(value == 0);
                 self.set_flag_n(false);
                 self.set_flag_h(half_carry);
                 self.set_flag_c(carry);
 
-                self.store_operand_u16(target, value);
+                self.store_operand_u16(target, value)
             },
-            _ => {
-                unimplemented!("execute_add: {:?} + {:?}", target, value);
-            }
+            _ => Err(self.unimplemented(format!("execute_add: {:?} + {:?}", target, value))),
         }
     }
 
-    fn execute_add_carry(&mut self, operand: Operand) {
-        let operand = self.load_u8_operand(operand);
-        let mut total_carry = false;
-        let mut total_half_carry = false;
-
-        // Add operand
-        {
-            let (result, carry) = self.reg_a.overflowing_add(operand);
-            total_carry |= carry;
-
-            let (_, half_carry) = (self.reg_a << 4).overflowing_add(operand << 4);
-            total_half_carry |= half_carry;
-
-            self.reg_a = result;
-        }
-
-        // Add 1
-        {
-            let (result, carry) = self.reg_a.overflowing_add(1);
-            total_carry |= carry;
+    fn execute_add_carry(&mut self, operand: Operand) -> Result<(), StepError> {
+        let operand = self.load_u8_operand(operand)?;
+        let carry = self.flag_c() as u8;
+        let total = self.reg_a as u16 + operand as u16 + carry as u16;
+        let half_carry = (self.reg_a & 0xF) + (operand & 0xF) + carry > 0xF;
 
-            let (_, half_carry) = (self.reg_a << 4).overflowing_add(1 << 4);
-            total_half_carry |= half_carry;
-
-            self.reg_a = result;
-        }
+        self.reg_a = total as u8;
 
         self.set_flag_z(self.reg_a == 0);
         self.set_flag_n(false);
-        self.set_flag_h(total_half_carry);
-        self.set_flag_c(total_carry);
+        self.set_flag_h(half_carry);
+        self.set_flag_c(total > 0xFF);
+
+        Ok(())
     }
 
-    fn execute_sub(&mut self, value: Operand) {
+    fn execute_sub(&mut self, value: Operand) -> Result<(), StepError> {
         let a = self.reg_a;
-        let b = self.load_u8_operand(value);
+        let b = self.load_u8_operand(value)?;
         let (value, carry) = a.overflowing_sub(b);
         let (_, half_carry) = (a << 4).overflowing_sub(b << 4);
 
         self.set_flag_z(value == 0);
-        self.set_flag_n(false);
+        self.set_flag_n(true);
         self.set_flag_h(half_carry);
         self.set_flag_c(carry);
 
         self.reg_a = value;
+
+        Ok(())
     }
 
-    fn execute_or(&mut self, value: Operand) {
-        let value = self.load_u8_operand(value);
+    fn execute_or(&mut self, value: Operand) -> Result<(), StepError> {
+        let value = self.load_u8_operand(value)?;
         self.reg_a |= value;
 
         self.set_flag_z(self.reg_a == 0);
         self.set_flag_n(false);
         self.set_flag_h(false);
         self.set_flag_c(false);
+
+        Ok(())
     }
 
-    fn execute_xor(&mut self, value: Operand) {
-        let value = self.load_u8_operand(value);
+    fn execute_xor(&mut self, value: Operand) -> Result<(), StepError> {
+        let value = self.load_u8_operand(value)?;
         self.reg_a ^= value;
 
         self.set_flag_z(self.reg_a == 0);
         self.set_flag_n(false);
         self.set_flag_h(false);
         self.set_flag_c(false);
+
+        Ok(())
     }
 
-    fn execute_and(&mut self, value: Operand) {
-        let value = self.load_u8_operand(value);
+    fn execute_and(&mut self, value: Operand) -> Result<(), StepError> {
+        let value = self.load_u8_operand(value)?;
         self.reg_a &= value;
 
         self.set_flag_z(self.reg_a == 0);
         self.set_flag_n(false);
-        self.set_flag_h(false);
+        self.set_flag_h(true);
         self.set_flag_c(false);
+
+        Ok(())
     }
 
-    fn execute_ret(&mut self, cond: Cond) {
+    fn execute_ret(&mut self, cond: Cond) -> Result<(), StepError> {
         let cond = self.evaluate_cond(cond);
 
         if cond {
-            let addr = self.pop_u16();
+            let addr = self.pop_u16()?;
             self.pc = addr;
         }
+
+        Ok(())
+    }
+
+    fn execute_reti(&mut self) -> Result<(), StepError> {
+        self.pc = self.pop_u16()?;
+        self.interrupts_enabled = true;
+
+        Ok(())
+    }
+
+    fn execute_rst(&mut self, addr: u8) -> Result<(), StepError> {
+        self.push_u16(self.pc)?;
+        self.pc = addr as u16;
+
+        Ok(())
     }
 
-    fn execute_rotate_right_a(&mut self) {
-        self.execute_rotate_right(Operand::Reg8(Reg8::A));
+    fn execute_rotate_right_a(&mut self) -> Result<(), StepError> {
+        self.execute_rotate_right(Operand::Reg8(Reg8::A))?;
         self.set_flag_z(false);
+
+        Ok(())
     }
 
-    fn execute_rotate_left_a(&mut self) {
-        self.execute_rotate_left(Operand::Reg8(Reg8::A));
+    fn execute_rotate_left_a(&mut self) -> Result<(), StepError> {
+        self.execute_rotate_left(Operand::Reg8(Reg8::A))?;
         self.set_flag_z(false);
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    fn execute_extended_opcode<const OPCODE: u8>(&mut self) -> Result<(), StepError> {
+        self.execute_extended(const { instruction::decode_extended(OPCODE) })
     }
 
-    fn execute_extended(&mut self, instruction: ExtendedInstruction) {
-        self.pc += 1;
+    #[inline(always)]
+    fn execute_extended(&mut self, instruction: ExtendedInstruction) -> Result<(), StepError> {
+        self.pc = self.pc.wrapping_add(1);
 
         match instruction {
             ExtendedInstruction::Srl(target) => self.execute_shift_right_logical(target),
             ExtendedInstruction::Rr(target) => self.execute_rotate_right(target),
             ExtendedInstruction::Bit(bit, target) => self.execute_bit(bit, target),
             ExtendedInstruction::Rl(target) => self.execute_rotate_left(target),
-            _ => {
-                self.pc -= 2;
-                self.print_state();
-                unimplemented!("execute_extended: {:?}", instruction)
-            },
+            _ => Err(self.unimplemented(format!("{:?}", instruction))),
         }
     }
 
-    fn execute_shift_right_logical(&mut self, target: Operand) {
-        let value = self.load_u8_operand(target);
+    fn execute_shift_right_logical(&mut self, target: Operand) -> Result<(), StepError> {
+        let value = self.load_u8_operand(target)?;
         let carry = value & 1 == 1;
         let value = value >> 1;
 
@@ -367,26 +925,28 @@ impl Core {
         self.set_flag_n(false);
         self.set_flag_h(false);
         self.set_flag_c(carry);
-        self.store_operand_u8(target, value);
+        self.store_operand_u8(target, value)
     }
 
-    fn execute_rotate_right(&mut self, target: Operand) {
-        let value = self.load_u8_operand(target);
+    fn execute_rotate_right(&mut self, target: Operand) -> Result<(), StepError> {
+        let value = self.load_u8_operand(target)?;
         let carry = value & 1 == 1;
-        let value = value | self.flag_c() as u8;
-        let value = value.rotate_right(1);
+        let value = value >> 1 | (self.flag_c() as u8) << 7;
 
         self.set_flag_z(value == 0);
         self.set_flag_n(false);
         self.set_flag_h(false);
         self.set_flag_c(carry);
-        self.store_operand_u8(target, value);
+        self.store_operand_u8(target, value)
     }
 
+    /// Writes from outside of execution, e.g. by a debugger.
+    /// Writes to unmapped addresses are dropped.
     pub fn write_mem_u8(&mut self, addr: u16, value: u8) {
         // println!("${:04X} = {:02X}", addr, value);
 
-        self.bus.write(addr, value);
+        self.invalidate_blocks(addr);
+        self.bus.try_write(addr, value);
     }
 
     pub fn write_mem_u16(&mut self, addr: u16, value: u16) {
@@ -395,131 +955,161 @@ impl Core {
         let lo = value as u8;
         let hi = (value >> 8) as u8;
 
-        self.write_mem_u8(addr    , lo);
-        self.write_mem_u8(addr + 1, hi);
+        self.write_mem_u8(addr, lo);
+        self.write_mem_u8(addr.wrapping_add(1), hi);
     }
 
+    fn store_mem_u8(&mut self, addr: u16, value: u8) -> Result<(), StepError> {
+        self.invalidate_blocks(addr);
+
+        if !self.bus.try_write(addr, value) {
+            return Err(self.unmapped(WatchKind::Write, addr));
+        }
+
+        Ok(())
+    }
+
+    /// Fails before writing anything if nothing is mapped at `addr`,
+    /// for instructions that write more than one byte
+    fn check_writable(&self, addr: u16) -> Result<(), StepError> {
+        match self.bus.is_mapped(addr) {
+            true => Ok(()),
+            false => Err(self.unmapped(WatchKind::Write, addr)),
+        }
+    }
+
+    fn store_mem_u16(&mut self, addr: u16, value: u16) -> Result<(), StepError> {
+        self.check_writable(addr)?;
+        self.check_writable(addr.wrapping_add(1))?;
+        self.store_mem_u8(addr, value as u8)?;
+        self.store_mem_u8(addr.wrapping_add(1), (value >> 8) as u8)
+    }
+
+    /// Reads without side effects, e.g. for debuggers
     pub fn peek_mem_u8(&self, addr: u16) -> u8 {
-        // TODO: either remove peeking or add peeking to bus
-        // self.mapper.peek_u8(&self.bus, &self.rom, &self.ram, addr)
-        self.bus.read(addr)
+        self.bus.peek(addr)
     }
 
-    fn read_mem_u8(&self, addr: u16) -> u8 {
+    fn read_mem_u8(&self, addr: u16) -> Result<u8, StepError> {
         // self.mapper.read_u8(&self.bus, &self.rom, &self.ram, addr)
-        self.bus.read(addr)
+        self.bus.try_read(addr).ok_or_else(|| self.unmapped(WatchKind::Read, addr))
     }
 
     pub fn peek_mem_u16(&self, addr: u16) -> u16 {
         let lo = self.peek_mem_u8(addr) as u16;
-        let hi = self.peek_mem_u8(addr + 1) as u16;
+        let hi = self.peek_mem_u8(addr.wrapping_add(1)) as u16;
         hi << 8 | lo
     }
 
-    fn read_mem_u16(&mut self, addr: u16) -> u16 {
-        let lo = self.read_mem_u8(addr) as u16;
-        let hi = self.read_mem_u8(addr + 1) as u16;
-        hi << 8 | lo
+    fn read_mem_u16(&mut self, addr: u16) -> Result<u16, StepError> {
+        let lo = self.read_mem_u8(addr)? as u16;
+        let hi = self.read_mem_u8(addr.wrapping_add(1))? as u16;
+        Ok(hi << 8 | lo)
     }
 
-    pub fn execute_cp(&mut self, source: Operand) {
-        let value = self.load_u8_operand(source);
+    pub fn execute_cp(&mut self, source: Operand) -> Result<(), StepError> {
+        let value = self.load_u8_operand(source)?;
 
         self.set_flag_z(self.reg_a == value);
         self.set_flag_n(true);
-        self.set_flag_h(self.reg_a & 0xF > value & 0xF);
+        self.set_flag_h(self.reg_a & 0xF < value & 0xF);
         self.set_flag_c(self.reg_a < value);
+
+        Ok(())
     }
 
-    pub fn execute_jr(&mut self, cond: Cond, offset: Operand) {
+    pub fn execute_jr(&mut self, cond: Cond, offset: Operand) -> Result<(), StepError> {
         let cond = self.evaluate_cond(cond);
-        let offset = self.load_u8_operand(offset) as i8;
+        let offset = self.load_u8_operand(offset)? as i8;
 
         if cond {
-            if offset.is_positive() {
-                self.pc += offset as u16;
-            } else {
-                self.pc -= offset.abs() as u16;
-            }
+            self.pc = self.pc.wrapping_add(offset as u16);
         }
+
+        Ok(())
     }
 
-    pub fn execute_jp(&mut self, cond: Cond, addr: Operand) {
+    pub fn execute_jp(&mut self, cond: Cond, addr: Operand) -> Result<(), StepError> {
         let cond = self.evaluate_cond(cond);
-        let addr = self.load_u16_operand(addr);
+        let addr = self.load_u16_operand(addr)?;
 
         if cond {
             self.pc = addr;
         }
+
+        Ok(())
     }
 
-    pub fn execute_call(&mut self, cond: Cond, addr: Operand) {
+    pub fn execute_call(&mut self, cond: Cond, addr: Operand) -> Result<(), StepError> {
         let cond = self.evaluate_cond(cond);
-        let addr = self.load_u16_operand(addr);
+        let addr = self.load_u16_operand(addr)?;
 
         if cond {
-            self.push_u16(self.pc);
+            self.push_u16(self.pc)?;
             self.pc = addr;
         }
+
+        Ok(())
     }
 
     pub fn execute_di(&mut self) {
         self.interrupts_enabled = false;
     }
 
-    pub fn execute_ld(&mut self, target: Operand, source: Operand) {
-        let value = self.load_operand(source);
+    pub fn execute_ld(&mut self, target: Operand, source: Operand) -> Result<(), StepError> {
+        let value = self.load_operand(source)?;
 
-        self.store_operand(target, value);
+        self.store_operand(target, value)
     }
 
-    pub fn execute_ldh(&mut self, target: Operand, source: Operand) {
+    pub fn execute_ldh(&mut self, target: Operand, source: Operand) -> Result<(), StepError> {
         match (target, source) {
             (Operand::Reg8(Reg8::A), Operand::Imm8Ref) => {
-                let ptr = self.decode_imm8() as u16 + 0xFF00;
-                let value = self.read_mem_u8(ptr);
+                let ptr = self.decode_imm8()? as u16 + 0xFF00;
+                let value = self.read_mem_u8(ptr)?;
                 self.reg_a = value;
             }
             (Operand::Imm8Ref, Operand::Reg8(Reg8::A)) => {
-                let value = self.load_u8_operand(source);
-                let ptr = self.decode_imm8() as u16 + 0xFF00;
+                let value = self.load_u8_operand(source)?;
+                let ptr = self.decode_imm8()? as u16 + 0xFF00;
 
-                self.write_mem_u8(ptr, value);
-            },
-            _ => {
-                self.pc -= 1;
-                self.print_state();
-                unimplemented!("execute_ldh: {:?} <- {:?}", target, source)
+                self.store_mem_u8(ptr, value)?;
             },
+            _ => return Err(self.unimplemented(format!("execute_ldh: {:?} <- {:?}", target, source))),
         }
+
+        Ok(())
     }
 
-    pub fn execute_push(&mut self, source: Reg16) {
+    pub fn execute_push(&mut self, source: Reg16) -> Result<(), StepError> {
         let value = match source {
             Reg16::AF => self.reg_af(),
             Reg16::BC => self.reg_bc(),
             Reg16::DE => self.reg_de(),
             Reg16::HL => self.reg_hl(),
-            _ => unimplemented!("execute_push: {:?}", source),
+            _ => return Err(self.unimplemented(format!("execute_push: {:?}", source))),
         };
 
-        self.push_u16(value);
+        self.push_u16(value)
     }
 
-    pub fn execute_pop(&mut self, target: Reg16) {
-        let value = self.pop_u16();
+    pub fn execute_pop(&mut self, target: Reg16) -> Result<(), StepError> {
+        let value = self.pop_u16()?;
 
         match target {
-            Reg16::AF => self.set_reg_af(value),
+            // The low nibble of F always reads 0
+            Reg16::AF => self.set_reg_af(value & 0xFFF0),
             Reg16::BC => self.set_reg_bc(value),
             Reg16::DE => self.set_reg_de(value),
             Reg16::HL => self.set_reg_hl(value),
-            _ => unimplemented!("execute_pop: {:?}", target),
+            _ => return Err(self.unimplemented(format!("execute_pop: {:?}", target))),
         }
+
+        Ok(())
     }
 
-    pub fn execute_inc(&mut self, target: Operand) {
-        match self.load_operand(target) {
+    pub fn execute_inc(&mut self, target: Operand) -> Result<(), StepError> {
+        match self.load_operand(target)? {
             Value::U8(value) => {
                 let half_carry = value & 0xF == 0xF;
                 let value = value.wrapping_add(1);
@@ -529,20 +1119,13 @@ impl Core {
                 self.set_flag_h(half_carry);
                 self.store_operand_u8(target, value)
             },
-            Value::U16(value) => {
-                let half_carry = value & 0xFF == 0xFF;
-                let value = value.wrapping_add(1);
-
-                self.set_flag_z(value == 0);
-                self.set_flag_n(false);
-                self.set_flag_h(half_carry);
-                self.store_operand_u16(target, value)
-            },
+            // Affects no flags
+            Value::U16(value) => self.store_operand_u16(target, value.wrapping_add(1)),
         }
     }
 
-    pub fn execute_dec(&mut self, target: Operand) {
-        match self.load_operand(target) {
+    pub fn execute_dec(&mut self, target: Operand) -> Result<(), StepError> {
+        match self.load_operand(target)? {
             Value::U8(value) => {
                 let value = value.wrapping_sub(1);
                 let half_carry = value & 0xF == 0xF;
@@ -551,44 +1134,53 @@ impl Core {
                 self.set_flag_h(half_carry);
                 self.store_operand_u8(target, value)
             },
-            Value::U16(value) => self.store_operand_u16(target, value - 1),
+            Value::U16(value) => self.store_operand_u16(target, value.wrapping_sub(1)),
         }
     }
 
-    pub fn execute_bit(&mut self, bit: u8, target: Operand) {
-        let value = self.load_u8_operand(target);
+    pub fn execute_bit(&mut self, bit: u8, target: Operand) -> Result<(), StepError> {
+        let value = self.load_u8_operand(target)?;
         let res = value & (1 << bit);
         self.set_flag_z(res == 0);
         self.set_flag_n(false);
         self.set_flag_h(true);
+
+        Ok(())
     }
 
-    pub fn execute_rotate_left(&mut self, target: Operand) {
-        let value = self.load_u8_operand(target);
+    pub fn execute_rotate_left(&mut self, target: Operand) -> Result<(), StepError> {
+        let value = self.load_u8_operand(target)?;
         let carry = (value & 0x80) != 0;
 
         let res = value << 1 | self.flag_c() as u8;
-        self.store_operand_u8(target, res);
+        self.store_operand_u8(target, res)?;
         self.set_flag_z(res == 0);
         self.set_flag_n(false);
         self.set_flag_h(false);
         self.set_flag_c(carry);
+
+        Ok(())
     }
 
-    pub fn push_u16(&mut self, value: u16) {
-        self.write_mem_u8(self.sp, (value >> 8) as u8);
-        self.sp -= 1;
-        self.write_mem_u8(self.sp, value as u8);
-        self.sp -= 1;
+    /// Stores the high byte at sp - 1 and the low byte at sp - 2, in that order
+    pub fn push_u16(&mut self, value: u16) -> Result<(), StepError> {
+        self.check_writable(self.sp.wrapping_sub(1))?;
+        self.check_writable(self.sp.wrapping_sub(2))?;
+        self.sp = self.sp.wrapping_sub(1);
+        self.store_mem_u8(self.sp, (value >> 8) as u8)?;
+        self.sp = self.sp.wrapping_sub(1);
+        self.store_mem_u8(self.sp, value as u8)?;
+
+        Ok(())
     }
 
-    pub fn pop_u16(&mut self) -> u16 {
-        self.sp += 1;
-        let vh = self.read_mem_u8(self.sp) as u16;
-        self.sp += 1;
-        let vl = (self.read_mem_u8(self.sp) as u16) << 8;
+    pub fn pop_u16(&mut self) -> Result<u16, StepError> {
+        let lo = self.read_mem_u8(self.sp)? as u16;
+        self.sp = self.sp.wrapping_add(1);
+        let hi = self.read_mem_u8(self.sp)? as u16;
+        self.sp = self.sp.wrapping_add(1);
 
-        vl | vh
+        Ok(hi << 8 | lo)
     }
 
     pub fn evaluate_cond(&self, cond: Cond) -> bool {
@@ -601,24 +1193,26 @@ impl Core {
         }
     }
 
-    pub fn load_operand(&mut self, source: Operand) -> Value {
-        match source {
-            Operand::Imm8 => Value::U8(self.decode_imm8()),
-            Operand::Imm16 => Value::U16(self.decode_imm16()),
+    #[inline(always)]
+    pub fn load_operand(&mut self, source: Operand) -> Result<Value, StepError> {
+        Ok(match source {
+            Operand::Imm8 => Value::U8(self.decode_imm8()?),
+            Operand::Imm16 => Value::U16(self.decode_imm16()?),
             Operand::Reg8(reg8) => Value::U8(self.load_u8_register(reg8)),
             Operand::Reg16(reg16) => Value::U16(self.load_u16_register(reg16)),
             Operand::RegRef16(reg16) => {
                 let addr = self.load_u16_register(reg16);
-                Value::U8(self.read_mem_u8(addr))
+                Value::U8(self.read_mem_u8(addr)?)
             },
             Operand::Imm16Ref => {
-                let addr = self.decode_imm16();
-                Value::U8(self.read_mem_u8(addr))
+                let addr = self.decode_imm16()?;
+                Value::U8(self.read_mem_u8(addr)?)
             },
-            _ => unimplemented!("load_operand: {:?}", source),
-        }
+            _ => return Err(self.unimplemented(format!("load_operand: {:?}", source))),
+        })
     }
 
+    #[inline(always)]
     fn load_u8_register(&mut self, reg8: Reg8) -> u8 {
         match reg8 {
             Reg8::A => self.reg_a,
@@ -631,6 +1225,7 @@ impl Core {
         }
     }
 
+    #[inline(always)]
     fn load_u16_register(&mut self, reg16: Reg16) -> u16 {
         match reg16 {
             Reg16::AF => self.reg_af(),
@@ -643,14 +1238,16 @@ impl Core {
         }
     }
 
-    pub fn store_operand(&mut self, target: Operand, value: Value) {
+    #[inline(always)]
+    pub fn store_operand(&mut self, target: Operand, value: Value) -> Result<(), StepError> {
         match value {
             Value::U8(value) => self.store_operand_u8(target, value),
             Value::U16(value) => self.store_operand_u16(target, value),
         }
     }
 
-    pub fn store_operand_u8(&mut self, target: Operand, value: u8) {
+    #[inline(always)]
+    pub fn store_operand_u8(&mut self, target: Operand, value: u8) -> Result<(), StepError> {
         match target {
             Operand::Reg8(Reg8::A) => self.reg_a = value,
             Operand::Reg8(Reg8::B) => self.reg_b = value,
@@ -661,33 +1258,43 @@ impl Core {
             Operand::Reg8(Reg8::L) => self.reg_l = value,
             Operand::RegRef16(reg16) => {
                 let addr = self.load_u16_register(reg16);
-                self.write_mem_u8(addr, value);
+                self.store_mem_u8(addr, value)?;
             },
             Operand::Imm16Ref => {
-                let ptr = self.decode_imm16();
-                self.write_mem_u8(ptr, value);
+                let ptr = self.decode_imm16()?;
+                self.store_mem_u8(ptr, value)?;
             },
             Operand::RegRef8(reg) => {
                 let addr = 0xFF00 + self.load_u8_register(reg) as u16;
-                self.write_mem_u8(addr, value)
+                self.store_mem_u8(addr, value)?;
             }
-            _ => unimplemented!("store_operand_u8: {:?} <- {:?}", target, value)
+            _ => return Err(self.unimplemented(format!("store_operand_u8: {:?} <- {:?}", target, value))),
         }
+
+        Ok(())
     }
 
-    pub fn store_operand_u16(&mut self, target: Operand, value: u16) {
+    #[inline(always)]
+    pub fn store_operand_u16(&mut self, target: Operand, value: u16) -> Result<(), StepError> {
         match target {
             Operand::Reg16(Reg16::BC) => self.set_reg_bc(value),
             Operand::Reg16(Reg16::DE) => self.set_reg_de(value),
             Operand::Reg16(Reg16::HL) => self.set_reg_hl(value),
             Operand::Reg16(Reg16::SP) => self.sp = value,
-            _ => unimplemented!("store_operand_u16: {:?} <- {:?}", target, value)
+            Operand::Imm16Ref => {
+                let ptr = self.decode_imm16()?;
+                self.store_mem_u16(ptr, value)?;
+            },
+            _ => return Err(self.unimplemented(format!("store_operand_u16: {:?} <- {:?}", target, value))),
         }
+
+        Ok(())
     }
 
-    pub fn load_u8_operand(&mut self, operand: Operand) -> u8 {
-        match operand {
-            Operand::Imm8 => self.decode_imm8(),
+    #[inline(always)]
+    pub fn load_u8_operand(&mut self, operand: Operand) -> Result<u8, StepError> {
+        Ok(match operand {
+            Operand::Imm8 => self.decode_imm8()?,
             Operand::Reg8(Reg8::A) => self.reg_a,
             Operand::Reg8(Reg8::B) => self.reg_b,
             Operand::Reg8(Reg8::C) => self.reg_c,
@@ -697,37 +1304,98 @@ impl Core {
             Operand::Reg8(Reg8::L) => self.reg_l,
             Operand::RegRef16(reg16) => {
                 let addr = self.load_u16_register(reg16);
-                self.read_mem_u8(addr)
+                self.read_mem_u8(addr)?
             },
-            _ => unimplemented!("load_u8_operand: {:?}", operand),
-        }
+            _ => return Err(self.unimplemented(format!("load_u8_operand: {:?}", operand))),
+        })
     }
 
-    pub fn load_u16_operand(&mut self, operand: Operand) -> u16 {
-        match operand {
-            Operand::Imm16 => self.decode_imm16(),
+    #[inline(always)]
+    pub fn load_u16_operand(&mut self, operand: Operand) -> Result<u16, StepError> {
+        Ok(match operand {
+            Operand::Imm16 => self.decode_imm16()?,
+            Operand::Reg16(reg16) => self.load_u16_register(reg16),
             Operand::RegRef16(reg16) => {
                 let addr = self.load_u16_register(reg16);
-                self.read_mem_u16(addr)
+                self.read_mem_u16(addr)?
             },
-            _ => unimplemented!("load_u16_operand: {:?}", operand),
-        }
+            _ => return Err(self.unimplemented(format!("load_u16_operand: {:?}", operand))),
+        })
     }
 
-    pub fn decode_imm8(&mut self) -> u8 {
-        let value = self.read_mem_u8(self.pc);
+    pub fn decode_imm8(&mut self) -> Result<u8, StepError> {
+        let value = self.read_mem_u8(self.pc)?;
 
-        self.pc += 1;
+        self.pc = self.pc.wrapping_add(1);
 
-        value
+        Ok(value)
     }
 
-    pub fn decode_imm16(&mut self) -> u16 {
-        let value = self.read_mem_u16(self.pc);
+    pub fn decode_imm16(&mut self) -> Result<u16, StepError> {
+        let value = self.read_mem_u16(self.pc)?;
 
-        self.pc += 2;
+        self.pc = self.pc.wrapping_add(2);
 
-        value
+        Ok(value)
+    }
+}
+
+impl Core<Bus> {
+    /// Serializes the whole machine.
+    /// The state can only be loaded back with the same ROM.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+
+        self.bus.state_header().write(&mut w);
+        w.write_u16(self.pc);
+        w.write_u16(self.sp);
+        w.write_u16(self.reg_af());
+        w.write_u16(self.reg_bc());
+        w.write_u16(self.reg_de());
+        w.write_u16(self.reg_hl());
+        w.write_bool(self.interrupts_enabled);
+        w.write_bool(self.locked_up);
+        self.bus.save_state(&mut w);
+
+        w.into_bytes()
+    }
+
+    pub fn load_state(&mut self, state: &[u8]) -> Fallible<()> {
+        let mut r = StateReader::new(state);
+
+        self.bus.state_header().check(&mut r)?;
+        let pc = r.read_u16()?;
+        let sp = r.read_u16()?;
+        let af = r.read_u16()?;
+        let bc = r.read_u16()?;
+        let de = r.read_u16()?;
+        let hl = r.read_u16()?;
+        let interrupts_enabled = r.read_bool()?;
+        let locked_up = r.read_bool()?;
+        let bus = self.bus.decode_state(&mut r)?;
+
+        if !r.is_empty() {
+            return Err(StateError::Corrupted("trailing data".into()).into());
+        }
+
+        // Nothing is changed until the whole state is known to be good
+        self.pc = pc;
+        self.sp = sp;
+        self.set_reg_af(af);
+        self.set_reg_bc(bc);
+        self.set_reg_de(de);
+        self.set_reg_hl(hl);
+        self.interrupts_enabled = interrupts_enabled;
+        self.locked_up = locked_up;
+        self.bus = bus;
+        // The calls leading up to the loaded state are unknown
+        self.call_stack.clear();
+
+        if let Some(blocks) = &mut self.blocks {
+            blocks.clear();
+        }
+
+        Ok(())
     }
 }
 
@@ -736,12 +1404,3 @@ pub enum Value {
     U8(u8),
     U16(u16),
 }
-
-impl Value {
-    fn as_u16(self) -> u16 {
-        match self {
-            Value::U8(value) => value as u16,
-            Value::U16(value) => value,
-        }
-    }
-}
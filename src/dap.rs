@@ -0,0 +1,697 @@
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::fs;
+use std::thread;
+use failure::{bail, format_err, Fallible};
+use serde_json::{json, Value};
+use crate::core::{Core, StepError, IllegalOpcodePolicy};
+use crate::bus::Bus;
+use crate::cartridge::Cartridge;
+use crate::disasm::{self, Disassembly};
+use crate::expr::Expr;
+use crate::breakpoints::{Breakpoint, Breakpoints, HitCondition, Hit, RunMode};
+use crate::symbols::{Symbols, BankAddr};
+use crate::sourcemap::SourceMap;
+
+const THREAD_ID: u64 = 1;
+
+/// Instructions executed between checks for new requests while running
+const RUN_BATCH: usize = 10_000;
+
+const REGISTERS_REFERENCE: u64 = 1;
+const FLAGS_REFERENCE: u64 = 2;
+const IO_REFERENCE: u64 = 3;
+
+const IO_REGISTERS: &[(&str, u16)] = &[
+    ("JOYP", 0xFF00),
+    ("SB", 0xFF01),
+    ("SC", 0xFF02),
+    ("TAC", 0xFF07),
+    ("IF", 0xFF0F),
+    ("LCDC", 0xFF40),
+    ("STAT", 0xFF41),
+    ("SCY", 0xFF42),
+    ("SCX", 0xFF43),
+    ("LY", 0xFF44),
+    ("LYC", 0xFF45),
+    ("DMA", 0xFF46),
+    ("BGP", 0xFF47),
+    ("OBP0", 0xFF48),
+    ("OBP1", 0xFF49),
+    ("WY", 0xFF4A),
+    ("WX", 0xFF4B),
+    ("VBK", 0xFF4F),
+    ("HDMA5", 0xFF55),
+    ("IE", 0xFFFF),
+];
+
+/// Reads Debug Adapter Protocol messages (`Content-Length` framed JSON) on a background thread
+pub fn spawn_reader(input: impl BufRead + Send + 'static) -> Receiver<Value> {
+    let (sender, receiver) = mpsc::channel();
+
+    thread::spawn(move || {
+        let mut input = input;
+
+        while let Ok(Some(message)) = read_message(&mut input) {
+            if sender.send(message).is_err() {
+                break;
+            }
+        }
+    });
+
+    receiver
+}
+
+fn read_message(input: &mut impl BufRead) -> Fallible<Option<Value>> {
+    let mut content_length = None;
+
+    loop {
+        let mut line = String::new();
+
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+
+        let line = line.trim();
+
+        if line.is_empty() {
+            break;
+        }
+
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                content_length = Some(value.trim().parse::<usize>()?);
+            }
+        }
+    }
+
+    let mut body = vec![0; content_length.ok_or_else(|| format_err!("Missing Content-Length"))?];
+    input.read_exact(&mut body)?;
+
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+/// Why execution stopped, as reported to the client
+enum Stop {
+    Entry,
+    Step,
+    Pause,
+    Breakpoint(Vec<u64>),
+    Error(StepError),
+    LockUp,
+}
+
+/// A Debug Adapter Protocol server, so editors can debug ROMs in their assembly sources.
+///
+/// Launch arguments:
+/// - `program`: the ROM
+/// - `symbols`: `.sym`/`.map` file(s)
+/// - `sources`: assembly files or directories, which are matched with the symbols by label
+/// - `stopOnEntry`
+/// - `illegalOpcodes`: `"error"` (default) or `"lockUp"`
+pub struct DapServer<W> {
+    output: W,
+    seq: u64,
+    core: Option<Core>,
+    source_map: SourceMap,
+    /// Breakpoints with a source belong to that file, the others are instruction breakpoints
+    breakpoints: Breakpoints,
+    run_mode: Option<RunMode>,
+    /// Stop reported once the response to the current request has been sent
+    pending_stop: Option<Stop>,
+    stop_on_entry: bool,
+}
+
+impl<W: Write> DapServer<W> {
+    pub fn new(output: W) -> Self {
+        Self {
+            output,
+            seq: 1,
+            core: None,
+            source_map: SourceMap::default(),
+            breakpoints: Breakpoints::default(),
+            run_mode: None,
+            pending_stop: None,
+            stop_on_entry: false,
+        }
+    }
+
+    /// Handles requests until the client disconnects.
+    /// While the target is running, requests are checked between batches of instructions.
+    pub fn serve(mut self, requests: Receiver<Value>) -> Fallible<()> {
+        loop {
+            let request = if self.run_mode.is_some() {
+                match requests.try_recv() {
+                    Ok(request) => Some(request),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => break,
+                }
+            } else {
+                match requests.recv() {
+                    Ok(request) => Some(request),
+                    Err(_) => break,
+                }
+            };
+
+            if let Some(request) = request {
+                if !self.handle(&request)? {
+                    break;
+                }
+            }
+
+            if self.run_mode.is_some() {
+                self.run_batch()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns `false` once the session is over
+    fn handle(&mut self, request: &Value) -> Fallible<bool> {
+        let command = request["command"].as_str().unwrap_or("");
+        let args = &request["arguments"];
+
+        let body = match command {
+            "disconnect" | "terminate" => {
+                self.respond(request, Ok(Value::Null))?;
+
+                if command == "terminate" {
+                    self.send_event("terminated", json!({}))?;
+                }
+
+                return Ok(false);
+            },
+            "launch" => self.launch(args).map(|_| Value::Null),
+            _ => self.dispatch(command, args),
+        };
+
+        self.respond(request, body)?;
+
+        if let Some(stop) = self.pending_stop.take() {
+            self.stop(stop)?;
+        }
+
+        // Configuration requests may only follow once the program and its symbols are loaded
+        match command {
+            "launch" if self.core.is_some() => self.send_event("initialized", json!({}))?,
+            "configurationDone" if self.stop_on_entry => self.stop(Stop::Entry)?,
+            "configurationDone" => self.run_mode = Some(RunMode::Continue),
+            _ => {},
+        }
+
+        Ok(true)
+    }
+
+    fn dispatch(&mut self, command: &str, args: &Value) -> Fallible<Value> {
+        if command == "initialize" {
+            return Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsConditionalBreakpoints": true,
+                "supportsHitConditionalBreakpoints": true,
+                "supportsInstructionBreakpoints": true,
+                "supportsReadMemoryRequest": true,
+                "supportsDisassembleRequest": true,
+                "supportsEvaluateForHovers": true,
+                "supportsTerminateRequest": true,
+            }));
+        }
+
+        if self.core.is_none() {
+            bail!("No program has been launched");
+        }
+
+        Ok(match command {
+            "configurationDone" | "setExceptionBreakpoints" => Value::Null,
+            "setBreakpoints" => self.set_breakpoints(args)?,
+            "setInstructionBreakpoints" => self.set_instruction_breakpoints(args)?,
+            "threads" => json!({ "threads": [{ "id": THREAD_ID, "name": "SM83" }] }),
+            "stackTrace" => self.stack_trace(args),
+            "scopes" => json!({
+                "scopes": [
+                    { "name": "Registers", "variablesReference": REGISTERS_REFERENCE, "expensive": false },
+                    { "name": "Flags", "variablesReference": FLAGS_REFERENCE, "expensive": false },
+                    { "name": "IO Registers", "variablesReference": IO_REFERENCE, "expensive": false },
+                ],
+            }),
+            "variables" => self.variables(args)?,
+            "evaluate" => self.evaluate(args)?,
+            "readMemory" => self.read_memory(args)?,
+            "disassemble" => self.disassemble(args)?,
+            "continue" => {
+                self.run_mode = Some(RunMode::Continue);
+                json!({ "allThreadsContinued": true })
+            },
+            "next" => {
+                self.step_over();
+                Value::Null
+            },
+            "stepIn" => {
+                self.step_once();
+                Value::Null
+            },
+            "stepOut" => {
+                self.run_mode = Some(RunMode::step_out(self.core())?);
+                Value::Null
+            },
+            "pause" => {
+                self.stop_after_response(Stop::Pause);
+                Value::Null
+            },
+            _ => bail!("Unsupported request: {}", command),
+        })
+    }
+
+    fn core(&self) -> &Core {
+        self.core.as_ref().expect("no program launched")
+    }
+
+    fn core_mut(&mut self) -> &mut Core {
+        self.core.as_mut().expect("no program launched")
+    }
+
+    fn launch(&mut self, args: &Value) -> Fallible<()> {
+        let program = args["program"].as_str().ok_or_else(|| format_err!("Missing `program`"))?;
+        let cartridge = Cartridge::load(fs::read(program)?)?;
+        let mut core = Core::new(Bus::new(cartridge));
+        let mut symbols = Symbols::default();
+
+        for path in string_list(&args["symbols"]) {
+            symbols.extend(Symbols::load(path)?);
+        }
+
+        for path in string_list(&args["sources"]) {
+            self.source_map.add_path(path)?;
+        }
+
+        core.set_symbols(symbols);
+
+        match args["illegalOpcodes"].as_str() {
+            None | Some("error") => {},
+            Some("lockUp") => core.set_illegal_opcode_policy(IllegalOpcodePolicy::LockUp),
+            Some(policy) => bail!("Unknown `illegalOpcodes` policy: {}", policy),
+        }
+
+        self.core = Some(core);
+        self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+
+        Ok(())
+    }
+
+    /// Every breakpoint is parsed before any is replaced, so a bad one changes nothing
+    fn set_breakpoints(&mut self, args: &Value) -> Fallible<Value> {
+        let path = PathBuf::from(args["source"]["path"].as_str().ok_or_else(|| format_err!("Missing source path"))?);
+        let mut breakpoints = Vec::new();
+        let mut lines = Vec::new();
+
+        for requested in args["breakpoints"].as_array().into_iter().flatten() {
+            let line = requested["line"].as_u64().unwrap_or(0) as usize;
+
+            match self.source_map.location(self.core().symbols(), &path, line) {
+                Some((location, line)) => {
+                    let mut breakpoint = parse_breakpoint(location, requested)?;
+                    breakpoint.source = Some(path.clone());
+                    breakpoints.push(breakpoint);
+                    lines.push(Ok(line));
+                },
+                None => lines.push(Err(line)),
+            }
+        }
+
+        let mut ids = self.breakpoints.replace(|breakpoint| breakpoint.source.as_ref() == Some(&path), breakpoints).into_iter();
+        let replies = lines.into_iter()
+            .map(|line| match line {
+                Ok(line) => json!({ "id": ids.next(), "verified": true, "line": line }),
+                Err(line) => json!({ "verified": false, "line": line, "message": "No label at or after this line" }),
+            })
+            .collect::<Vec<Value>>();
+
+        Ok(json!({ "breakpoints": replies }))
+    }
+
+    fn set_instruction_breakpoints(&mut self, args: &Value) -> Fallible<Value> {
+        let mut breakpoints = Vec::new();
+        let mut addrs = Vec::new();
+
+        for requested in args["breakpoints"].as_array().into_iter().flatten() {
+            let addr = parse_reference(requested["instructionReference"].as_str().unwrap_or(""))?
+                .wrapping_add(requested["offset"].as_i64().unwrap_or(0) as u16);
+
+            breakpoints.push(parse_breakpoint(self.core().bank_addr(addr), requested)?);
+            addrs.push(addr);
+        }
+
+        let ids = self.breakpoints.replace(|breakpoint| breakpoint.source.is_none(), breakpoints);
+        let replies = ids.into_iter().zip(addrs)
+            .map(|(id, addr)| json!({ "id": id, "verified": true, "instructionReference": format_reference(addr) }))
+            .collect::<Vec<Value>>();
+
+        Ok(json!({ "breakpoints": replies }))
+    }
+
+    fn step_over(&mut self) {
+        match RunMode::step_over(self.core()) {
+            Some(run_mode) => self.run_mode = Some(run_mode),
+            None => self.step_once(),
+        }
+    }
+
+    fn step_once(&mut self) {
+        let stop = match self.core_mut().step() {
+            Ok(()) if self.core().is_locked_up() => Stop::LockUp,
+            Ok(()) => Stop::Step,
+            Err(err) => Stop::Error(err),
+        };
+
+        self.stop_after_response(stop);
+    }
+
+    fn run_batch(&mut self) -> Fallible<()> {
+        let run_mode = match self.run_mode {
+            Some(run_mode) => run_mode,
+            None => return Ok(()),
+        };
+
+        for _ in 0..RUN_BATCH {
+            if let Err(err) = self.core_mut().step() {
+                return self.stop(Stop::Error(err));
+            }
+
+            if self.core().is_locked_up() {
+                return self.stop(Stop::LockUp);
+            }
+
+            let hit = self.check_breakpoints();
+
+            if !hit.is_empty() {
+                return self.stop(Stop::Breakpoint(hit));
+            }
+
+            if run_mode.is_done(self.core()) {
+                return self.stop(Stop::Step);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Counts hits at the current pc and returns the ids of breakpoints that want to stop
+    fn check_breakpoints(&mut self) -> Vec<u64> {
+        let core = self.core.as_ref().expect("no program launched");
+
+        self.breakpoints.check(core).iter()
+            .filter(|hit| hit.stops())
+            .map(|hit| {
+                if let Hit::Error(breakpoint, err) = hit {
+                    eprintln!("dap: condition `{}`: {}", breakpoint.condition.as_ref().unwrap(), err);
+                }
+
+                hit.breakpoint().id
+            })
+            .collect()
+    }
+
+    fn stop_after_response(&mut self, stop: Stop) {
+        self.run_mode = None;
+        self.pending_stop = Some(stop);
+    }
+
+    fn stop(&mut self, stop: Stop) -> Fallible<()> {
+        let (reason, breakpoints, description) = match stop {
+            Stop::Entry => ("entry", Vec::new(), None),
+            Stop::Step => ("step", Vec::new(), None),
+            Stop::Pause => ("pause", Vec::new(), None),
+            Stop::Breakpoint(breakpoints) => ("breakpoint", breakpoints, None),
+            Stop::Error(err) => ("exception", Vec::new(), Some(err.to_string())),
+            Stop::LockUp => {
+                let location = self.core().format_addr(self.core().pc());
+                ("exception", Vec::new(), Some(format!("CPU locked up at {}", location)))
+            },
+        };
+        let mut body = json!({
+            "reason": reason,
+            "threadId": THREAD_ID,
+            "allThreadsStopped": true,
+            "hitBreakpointIds": breakpoints,
+        });
+
+        if let Some(description) = description {
+            body["description"] = json!(description);
+        }
+
+        self.run_mode = None;
+        self.send_event("stopped", body)
+    }
+
+    /// The innermost frame is at pc, the others at the call sites of the tracked calls
+    fn stack_trace(&self, args: &Value) -> Value {
+        let core = self.core();
+        let locations = std::iter::once(core.bank_addr(core.pc()))
+            .chain(core.call_stack().frames().iter().rev().map(|frame| frame.call_site))
+            .collect::<Vec<BankAddr>>();
+        let start = args["startFrame"].as_u64().unwrap_or(0) as usize;
+        let levels = match args["levels"].as_u64() {
+            Some(0) | None => locations.len(),
+            Some(levels) => levels as usize,
+        };
+
+        let frames = locations.iter()
+            .enumerate()
+            .skip(start)
+            .take(levels)
+            .map(|(id, &location)| {
+                let mut frame = json!({
+                    "id": id,
+                    "name": core.symbols().format(location).unwrap_or_else(|| format!("${:04X}", location.addr)),
+                    "line": 0,
+                    "column": 0,
+                    "instructionPointerReference": format_reference(location.addr),
+                });
+
+                if let Some((path, line)) = self.source_map.line(core.symbols(), location) {
+                    frame["source"] = source(path);
+                    frame["line"] = json!(line);
+                    frame["column"] = json!(1);
+                }
+
+                frame
+            })
+            .collect::<Vec<Value>>();
+
+        json!({ "stackFrames": frames, "totalFrames": locations.len() })
+    }
+
+    fn variables(&self, args: &Value) -> Fallible<Value> {
+        let core = self.core();
+        let byte = |name: &str, value: u16| json!({ "name": name, "value": format!("${:02X}", value), "variablesReference": 0 });
+        let word = |name: &str, value: u16| json!({ "name": name, "value": format!("${:04X}", value), "variablesReference": 0 });
+        let flag = |name: &str, set: bool| json!({ "name": name, "value": if set { "1" } else { "0" }, "variablesReference": 0 });
+
+        let variables = match args["variablesReference"].as_u64() {
+            Some(REGISTERS_REFERENCE) => vec![
+                byte("a", core.reg_af() >> 8),
+                byte("f", core.reg_af() & 0xFF),
+                word("bc", core.reg_bc()),
+                word("de", core.reg_de()),
+                word("hl", core.reg_hl()),
+                word("sp", core.sp()),
+                word("pc", core.pc()),
+            ],
+            Some(FLAGS_REFERENCE) => vec![
+                flag("z", core.flag_z()),
+                flag("n", core.flag_n()),
+                flag("h", core.flag_h()),
+                flag("c", core.flag_c()),
+            ],
+            Some(IO_REFERENCE) => IO_REGISTERS.iter()
+                .map(|&(name, addr)| {
+                    let mut variable = byte(name, core.peek_mem_u8(addr) as u16);
+                    variable["memoryReference"] = json!(format_reference(addr));
+                    variable
+                })
+                .collect(),
+            _ => bail!("Unknown variables reference"),
+        };
+
+        Ok(json!({ "variables": variables }))
+    }
+
+    fn evaluate(&self, args: &Value) -> Fallible<Value> {
+        let expression = args["expression"].as_str().unwrap_or("");
+        let value = Expr::parse(expression)?.eval(self.core())?;
+
+        Ok(json!({
+            "result": format!("${:X} ({})", value, value),
+            "variablesReference": 0,
+        }))
+    }
+
+    fn read_memory(&self, args: &Value) -> Fallible<Value> {
+        let addr = parse_reference(args["memoryReference"].as_str().unwrap_or(""))? as i64
+            + args["offset"].as_i64().unwrap_or(0);
+        let count = args["count"].as_i64().unwrap_or(0);
+        let start = addr.clamp(0, 0x10000);
+        let end = (addr + count).clamp(start, 0x10000);
+        let bytes = (start..end)
+            .map(|addr| self.core().peek_mem_u8(addr as u16))
+            .collect::<Vec<u8>>();
+
+        Ok(json!({
+            "address": format_reference(start as u16),
+            "unreadableBytes": count - bytes.len() as i64,
+            "data": base64(&bytes),
+        }))
+    }
+
+    fn disassemble(&self, args: &Value) -> Fallible<Value> {
+        let core = self.core();
+        let read = |addr| core.peek_mem_u8(addr);
+        let addr = parse_reference(args["memoryReference"].as_str().unwrap_or(""))?
+            .wrapping_add(args["offset"].as_i64().unwrap_or(0) as u16);
+        let offset = args["instructionOffset"].as_i64().unwrap_or(0);
+        let count = args["instructionCount"].as_u64().unwrap_or(0) as usize;
+
+        let mut instructions = Vec::new();
+        let mut next = addr;
+
+        if offset < 0 {
+            instructions = disasm::disassemble_before(addr, offset.unsigned_abs() as usize, read);
+        } else {
+            for _ in 0..offset {
+                next = disasm::disassemble(next, read).next_addr();
+            }
+        }
+
+        let remaining = count.saturating_sub(instructions.len());
+        instructions.extend(disasm::disassemble_range(next, remaining, read));
+        instructions.truncate(count);
+
+        let instructions = instructions.iter()
+            .map(|disassembly| self.render_instruction(disassembly))
+            .collect::<Vec<Value>>();
+
+        Ok(json!({ "instructions": instructions }))
+    }
+
+    fn render_instruction(&self, disassembly: &Disassembly) -> Value {
+        let core = self.core();
+        let location = core.bank_addr(disassembly.addr);
+        let bytes = disassembly.bytes.iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect::<Vec<String>>()
+            .join(" ");
+        let mut instruction = json!({
+            "address": format_reference(disassembly.addr),
+            "instructionBytes": bytes,
+            "instruction": core.render_instruction(disassembly),
+        });
+
+        if let Some(name) = core.symbols().name(location) {
+            instruction["symbol"] = json!(name);
+
+            if let Some((path, line)) = self.source_map.line(core.symbols(), location) {
+                instruction["location"] = source(path);
+                instruction["line"] = json!(line);
+            }
+        }
+
+        instruction
+    }
+
+    fn respond(&mut self, request: &Value, body: Fallible<Value>) -> Fallible<()> {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": body.is_ok(),
+        });
+
+        match body {
+            Ok(Value::Null) => {},
+            Ok(body) => response["body"] = body,
+            Err(err) => response["message"] = json!(err.to_string()),
+        }
+
+        self.send(response)
+    }
+
+    fn send_event(&mut self, event: &str, body: Value) -> Fallible<()> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+
+    fn send(&mut self, mut message: Value) -> Fallible<()> {
+        message["seq"] = json!(self.seq);
+        self.seq += 1;
+
+        let body = serde_json::to_vec(&message)?;
+
+        write!(self.output, "Content-Length: {}\r\n\r\n", body.len())?;
+        self.output.write_all(&body)?;
+        self.output.flush()?;
+
+        Ok(())
+    }
+}
+
+/// The `condition` and `hitCondition` of a requested breakpoint
+fn parse_breakpoint(location: BankAddr, requested: &Value) -> Fallible<Breakpoint> {
+    let mut breakpoint = Breakpoint::new(location);
+
+    breakpoint.condition = match requested["condition"].as_str() {
+        Some(condition) if !condition.trim().is_empty() => Some(Expr::parse(condition)?),
+        _ => None,
+    };
+    breakpoint.hit_condition = match requested["hitCondition"].as_str() {
+        Some(hit_condition) if !hit_condition.trim().is_empty() => Some(HitCondition::parse(hit_condition)?),
+        _ => None,
+    };
+
+    Ok(breakpoint)
+}
+
+/// Accepts a single string or an array of strings
+fn string_list(value: &Value) -> Vec<&str> {
+    match value {
+        Value::String(string) => vec![string.as_str()],
+        Value::Array(values) => values.iter().filter_map(Value::as_str).collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn source(path: &Path) -> Value {
+    json!({
+        "name": path.file_name().map(|name| name.to_string_lossy()),
+        "path": path.to_string_lossy(),
+    })
+}
+
+fn format_reference(addr: u16) -> String {
+    format!("0x{:04X}", addr)
+}
+
+fn parse_reference(text: &str) -> Fallible<u16> {
+    let digits = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")).unwrap_or(text);
+    Ok(u16::from_str_radix(digits, 16)?)
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::new();
+
+    for chunk in bytes.chunks(3) {
+        let bits = chunk.iter()
+            .enumerate()
+            .fold(0u32, |bits, (index, &byte)| bits | (byte as u32) << (16 - 8 * index));
+
+        for index in 0..4 {
+            if index <= chunk.len() {
+                encoded.push(ALPHABET[(bits >> (18 - 6 * index)) as usize & 0x3F] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+
+    encoded
+}
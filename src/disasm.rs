@@ -0,0 +1,239 @@
+use std::fmt;
+use crate::instruction::{Instruction, ExtendedInstruction, Cond, Operand, Reg8, Reg16};
+
+/// A decoded instruction together with its operand bytes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Disassembly {
+    pub addr: u16,
+    pub bytes: Vec<u8>,
+    pub instruction: Instruction,
+    pub extended: Option<ExtendedInstruction>,
+}
+
+/// Decodes the instruction at `addr`, reading memory through `read`.
+pub fn disassemble(addr: u16, read: impl Fn(u16) -> u8) -> Disassembly {
+    let instruction = Instruction::decode(read(addr));
+    let bytes = (0..instruction.len())
+        .map(|offset| read(addr.wrapping_add(offset)))
+        .collect::<Vec<u8>>();
+    let extended = match instruction {
+        Instruction::Extended => Some(ExtendedInstruction::decode(bytes[1])),
+        _ => None,
+    };
+
+    Disassembly {
+        addr,
+        bytes,
+        instruction,
+        extended,
+    }
+}
+
+/// Decodes `count` consecutive instructions starting at `addr`
+pub fn disassemble_range(addr: u16, count: usize, read: impl Fn(u16) -> u8) -> Vec<Disassembly> {
+    let mut addr = addr;
+
+    (0..count)
+        .map(|_| {
+            let disassembly = disassemble(addr, &read);
+            addr = disassembly.next_addr();
+            disassembly
+        })
+        .collect()
+}
+
+/// Decodes up to `count` instructions leading up to `addr`.
+///
+/// Instructions have different lengths, so decoding backwards is ambiguous.
+/// This uses the earliest start address whose instructions line up with `addr`,
+/// which usually lets the decoder resynchronize on real instruction boundaries.
+pub fn disassemble_before(addr: u16, count: usize, read: impl Fn(u16) -> u8) -> Vec<Disassembly> {
+    let max_distance = (count * 3).min(addr as usize) as u16;
+
+    for distance in (1..=max_distance).rev() {
+        let mut current = addr - distance;
+        let mut instructions = Vec::new();
+
+        while current < addr {
+            let disassembly = disassemble(current, &read);
+            current = current.saturating_add(disassembly.len());
+            instructions.push(disassembly);
+        }
+
+        if current == addr {
+            let skip = instructions.len().saturating_sub(count);
+            return instructions.split_off(skip);
+        }
+    }
+
+    Vec::new()
+}
+
+impl Disassembly {
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> u16 {
+        self.bytes.len() as u16
+    }
+
+    /// Address of the following instruction
+    pub fn next_addr(&self) -> u16 {
+        self.addr.wrapping_add(self.len())
+    }
+
+    fn imm8(&self) -> u8 {
+        self.bytes[1]
+    }
+
+    fn imm16(&self) -> u16 {
+        u16::from_le_bytes([self.bytes[1], self.bytes[2]])
+    }
+
+    /// Destination of jumps, calls and restarts with a fixed target
+    pub fn target(&self) -> Option<u16> {
+        match self.instruction {
+            Instruction::Jr(_, Operand::Imm8) => {
+                let offset = self.imm8() as i8;
+                Some(self.next_addr().wrapping_add(offset as u16))
+            },
+            Instruction::Jp(_, Operand::Imm16) | Instruction::Call(_, Operand::Imm16) => Some(self.imm16()),
+            Instruction::Rst(addr) => Some(addr as u16),
+            _ => None,
+        }
+    }
+
+    /// Renders RGBDS syntax.
+    /// `label` may name addresses, which are then used for jump targets and 16 bit operands.
+    pub fn render(&self, label: impl Fn(u16) -> Option<String>) -> String {
+        let addr16 = |addr: u16| label(addr).unwrap_or_else(|| format!("${:04X}", addr));
+        let operand = |operand: Operand| self.render_operand(operand, &addr16);
+        let with_cond = |mnemonic: &str, cond: Cond, rest: String| match cond_name(cond) {
+            Some(cond) => format!("{} {}, {}", mnemonic, cond, rest),
+            None => format!("{} {}", mnemonic, rest),
+        };
+
+        match self.instruction {
+            Instruction::Nop => "nop".into(),
+            Instruction::Ld(target @ Operand::RegRef8(_), source) | Instruction::Ld(target, source @ Operand::RegRef8(_)) => {
+                format!("ldh {}, {}", operand(target), operand(source))
+            },
+            Instruction::Ld(target, source) => format!("ld {}, {}", operand(target), operand(source)),
+            Instruction::Ldh(target, source) => format!("ldh {}, {}", operand(target), operand(source)),
+            Instruction::Ldhl => match self.imm8() as i8 {
+                offset if offset < 0 => format!("ld hl, sp-{}", offset.unsigned_abs()),
+                offset => format!("ld hl, sp+{}", offset),
+            },
+            Instruction::Inc(target) => format!("inc {}", operand(target)),
+            Instruction::Dec(target) => format!("dec {}", operand(target)),
+            Instruction::Add(Operand::Reg16(Reg16::SP), Operand::Imm8) => format!("add sp, {}", self.imm8() as i8),
+            Instruction::Add(target, value) => format!("add {}, {}", operand(target), operand(value)),
+            Instruction::Adc(value) => format!("adc a, {}", operand(value)),
+            Instruction::Sub(value) => format!("sub {}", operand(value)),
+            Instruction::Sbc(target, value) => format!("sbc {}, {}", operand(target), operand(value)),
+            Instruction::Mul(target, value) => format!("mul {}, {}", operand(target), operand(value)),
+            Instruction::Div(target, value) => format!("div {}, {}", operand(target), operand(value)),
+            Instruction::And(value) => format!("and {}", operand(value)),
+            Instruction::Xor(value) => format!("xor {}", operand(value)),
+            Instruction::Or(value) => format!("or {}", operand(value)),
+            Instruction::Cp(value) => format!("cp {}", operand(value)),
+            Instruction::Jr(cond, _) => with_cond("jr", cond, addr16(self.target().unwrap_or(0))),
+            Instruction::Jp(cond, target) => with_cond("jp", cond, operand(target)),
+            Instruction::Call(cond, target) => with_cond("call", cond, operand(target)),
+            Instruction::Ret(cond) => match cond_name(cond) {
+                Some(cond) => format!("ret {}", cond),
+                None => "ret".into(),
+            },
+            Instruction::Reti => "reti".into(),
+            Instruction::Pop(reg) => format!("pop {}", reg16_name(reg)),
+            Instruction::Push(reg) => format!("push {}", reg16_name(reg)),
+            Instruction::Stop => "stop".into(),
+            Instruction::Halt => "halt".into(),
+            Instruction::Rlca => "rlca".into(),
+            Instruction::Rrca => "rrca".into(),
+            Instruction::Rla => "rla".into(),
+            Instruction::Rra => "rra".into(),
+            Instruction::Daa => "daa".into(),
+            Instruction::Cpl => "cpl".into(),
+            Instruction::Scf => "scf".into(),
+            Instruction::Ccf => "ccf".into(),
+            Instruction::Rst(addr) => format!("rst ${:02X}", addr),
+            Instruction::Di => "di".into(),
+            Instruction::Ei => "ei".into(),
+            Instruction::Invalid => format!("db ${:02X}", self.bytes[0]),
+            Instruction::Extended => match self.extended {
+                Some(extended) => render_extended(extended, &operand),
+                None => "prefix cb".into(),
+            },
+        }
+    }
+
+    fn render_operand(&self, operand: Operand, addr16: &dyn Fn(u16) -> String) -> String {
+        match operand {
+            Operand::Reg8(reg) => reg8_name(reg).into(),
+            Operand::RegRef8(Reg8::C) => "[c]".into(),
+            Operand::RegRef8(reg) => format!("[$FF00+{}]", reg8_name(reg)),
+            Operand::Reg16(reg) => reg16_name(reg).into(),
+            Operand::RegRef16(reg) => format!("[{}]", reg16_name(reg)),
+            Operand::Imm8 => format!("${:02X}", self.imm8()),
+            Operand::Imm8Ref => format!("[${:04X}]", 0xFF00 | self.imm8() as u16),
+            Operand::Imm16 => addr16(self.imm16()),
+            Operand::Imm16Ref => format!("[{}]", addr16(self.imm16())),
+            Operand::Cond(cond) => cond_name(cond).unwrap_or_default().into(),
+        }
+    }
+}
+
+impl fmt::Display for Disassembly {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.render(|_| None))
+    }
+}
+
+fn render_extended(instruction: ExtendedInstruction, operand: &dyn Fn(Operand) -> String) -> String {
+    match instruction {
+        ExtendedInstruction::Rlc(target) => format!("rlc {}", operand(target)),
+        ExtendedInstruction::Rrc(target) => format!("rrc {}", operand(target)),
+        ExtendedInstruction::Rl(target) => format!("rl {}", operand(target)),
+        ExtendedInstruction::Rr(target) => format!("rr {}", operand(target)),
+        ExtendedInstruction::Sla(target) => format!("sla {}", operand(target)),
+        ExtendedInstruction::Sra(target) => format!("sra {}", operand(target)),
+        ExtendedInstruction::Swap(target) => format!("swap {}", operand(target)),
+        ExtendedInstruction::Srl(target) => format!("srl {}", operand(target)),
+        ExtendedInstruction::Bit(bit, target) => format!("bit {}, {}", bit, operand(target)),
+        ExtendedInstruction::Res(bit, target) => format!("res {}, {}", bit, operand(target)),
+        ExtendedInstruction::Set(bit, target) => format!("set {}, {}", bit, operand(target)),
+    }
+}
+
+fn reg8_name(reg: Reg8) -> &'static str {
+    match reg {
+        Reg8::A => "a",
+        Reg8::B => "b",
+        Reg8::C => "c",
+        Reg8::D => "d",
+        Reg8::E => "e",
+        Reg8::H => "h",
+        Reg8::L => "l",
+    }
+}
+
+fn reg16_name(reg: Reg16) -> &'static str {
+    match reg {
+        Reg16::AF => "af",
+        Reg16::BC => "bc",
+        Reg16::DE => "de",
+        Reg16::HL => "hl",
+        Reg16::HLInc => "hl+",
+        Reg16::HLDec => "hl-",
+        Reg16::SP => "sp",
+    }
+}
+
+fn cond_name(cond: Cond) -> Option<&'static str> {
+    match cond {
+        Cond::Always => None,
+        Cond::ZSet => Some("z"),
+        Cond::ZReset => Some("nz"),
+        Cond::CSet => Some("c"),
+        Cond::CReset => Some("nc"),
+    }
+}
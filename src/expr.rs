@@ -0,0 +1,373 @@
+use std::fmt;
+use failure::{bail, format_err, Fallible};
+use crate::core::Core;
+
+/// Expressions for conditional breakpoints and tracepoints, e.g. `a == 3 && [hl] > 0x10`.
+///
+/// Numbers are decimal unless prefixed with `0x`/`$` (hex) or `0b`/`%` (binary).
+/// Names are registers (`a`, `hl`, `sp`, `pc`, ...), flags (`zf`, `nf`, `hf`, `cf`)
+/// or symbols, which evaluate to their address.
+/// `[addr]` reads a byte and `w[addr]` a little-endian word.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expr {
+    source: String,
+    node: Node,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Node {
+    Number(i64),
+    Register(Register),
+    Flag(Flag),
+    Symbol(String),
+    Memory(Box<Node>),
+    MemoryWord(Box<Node>),
+    Unary(UnaryOp, Box<Node>),
+    Binary(BinaryOp, Box<Node>, Box<Node>),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Register {
+    A, F, B, C, D, E, H, L,
+    AF, BC, DE, HL, SP, PC,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Flag {
+    Z, N, H, C,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum UnaryOp {
+    Neg,
+    Not,
+    Complement,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum BinaryOp {
+    Or, And,
+    Eq, Ne, Lt, Le, Gt, Ge,
+    BitOr, BitXor, BitAnd,
+    Shl, Shr,
+    Add, Sub,
+    Mul, Div, Rem,
+}
+
+/// Operators from lowest to highest precedence
+const BINARY_OPS: &[&[(&str, BinaryOp)]] = &[
+    &[("||", BinaryOp::Or)],
+    &[("&&", BinaryOp::And)],
+    &[
+        ("==", BinaryOp::Eq), ("!=", BinaryOp::Ne),
+        ("<=", BinaryOp::Le), (">=", BinaryOp::Ge),
+        ("<", BinaryOp::Lt), (">", BinaryOp::Gt),
+    ],
+    &[("|", BinaryOp::BitOr)],
+    &[("^", BinaryOp::BitXor)],
+    &[("&", BinaryOp::BitAnd)],
+    &[("<<", BinaryOp::Shl), (">>", BinaryOp::Shr)],
+    &[("+", BinaryOp::Add), ("-", BinaryOp::Sub)],
+    &[("*", BinaryOp::Mul), ("/", BinaryOp::Div), ("%", BinaryOp::Rem)],
+];
+
+impl Expr {
+    pub fn parse(source: &str) -> Fallible<Self> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser { tokens: &tokens, pos: 0 };
+        let node = parser.parse_binary(0)?;
+
+        if let Some(token) = parser.peek() {
+            bail!("Unexpected {:?} in expression", token);
+        }
+
+        Ok(Self {
+            source: source.trim().to_owned(),
+            node,
+        })
+    }
+
+    pub fn eval(&self, core: &Core) -> Fallible<i64> {
+        self.node.eval(core)
+    }
+
+    /// Evaluates the expression as a condition, where any non-zero value is true
+    pub fn is_true(&self, core: &Core) -> Fallible<bool> {
+        self.eval(core).map(|value| value != 0)
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl Node {
+    fn eval(&self, core: &Core) -> Fallible<i64> {
+        Ok(match self {
+            Node::Number(value) => *value,
+            Node::Register(register) => register.get(core) as i64,
+            Node::Flag(flag) => flag.get(core) as i64,
+            Node::Symbol(name) => match core.symbols().lookup(name) {
+                Some(location) => location.addr as i64,
+                None if u16::from_str_radix(name, 16).is_ok() => bail!("Unknown symbol: {} (hex numbers need a 0x or $ prefix)", name),
+                None => bail!("Unknown symbol: {}", name),
+            },
+            Node::Memory(addr) => core.peek_mem_u8(addr.eval(core)? as u16) as i64,
+            Node::MemoryWord(addr) => core.peek_mem_u16(addr.eval(core)? as u16) as i64,
+            Node::Unary(op, operand) => {
+                let value = operand.eval(core)?;
+
+                match op {
+                    UnaryOp::Neg => value.wrapping_neg(),
+                    UnaryOp::Not => (value == 0) as i64,
+                    UnaryOp::Complement => !value,
+                }
+            },
+            // Short-circuit, so `[hl] > 0` can be guarded by a range check
+            Node::Binary(BinaryOp::Or, lhs, rhs) => (lhs.eval(core)? != 0 || rhs.eval(core)? != 0) as i64,
+            Node::Binary(BinaryOp::And, lhs, rhs) => (lhs.eval(core)? != 0 && rhs.eval(core)? != 0) as i64,
+            Node::Binary(op, lhs, rhs) => {
+                let lhs = lhs.eval(core)?;
+                let rhs = rhs.eval(core)?;
+
+                match op {
+                    BinaryOp::Or | BinaryOp::And => unreachable!(),
+                    BinaryOp::Eq => (lhs == rhs) as i64,
+                    BinaryOp::Ne => (lhs != rhs) as i64,
+                    BinaryOp::Lt => (lhs < rhs) as i64,
+                    BinaryOp::Le => (lhs <= rhs) as i64,
+                    BinaryOp::Gt => (lhs > rhs) as i64,
+                    BinaryOp::Ge => (lhs >= rhs) as i64,
+                    BinaryOp::BitOr => lhs | rhs,
+                    BinaryOp::BitXor => lhs ^ rhs,
+                    BinaryOp::BitAnd => lhs & rhs,
+                    BinaryOp::Shl => lhs.wrapping_shl(rhs as u32),
+                    BinaryOp::Shr => lhs.wrapping_shr(rhs as u32),
+                    BinaryOp::Add => lhs.wrapping_add(rhs),
+                    BinaryOp::Sub => lhs.wrapping_sub(rhs),
+                    BinaryOp::Mul => lhs.wrapping_mul(rhs),
+                    BinaryOp::Div => lhs.checked_div(rhs).ok_or_else(|| format_err!("Division by zero"))?,
+                    BinaryOp::Rem => lhs.checked_rem(rhs).ok_or_else(|| format_err!("Division by zero"))?,
+                }
+            },
+        })
+    }
+}
+
+impl Register {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "a" => Register::A,
+            "f" => Register::F,
+            "b" => Register::B,
+            "c" => Register::C,
+            "d" => Register::D,
+            "e" => Register::E,
+            "h" => Register::H,
+            "l" => Register::L,
+            "af" => Register::AF,
+            "bc" => Register::BC,
+            "de" => Register::DE,
+            "hl" => Register::HL,
+            "sp" => Register::SP,
+            "pc" => Register::PC,
+            _ => return None,
+        })
+    }
+
+    fn get(self, core: &Core) -> u16 {
+        match self {
+            Register::A => core.reg_af() >> 8,
+            Register::F => core.reg_af() & 0xFF,
+            Register::B => core.reg_bc() >> 8,
+            Register::C => core.reg_bc() & 0xFF,
+            Register::D => core.reg_de() >> 8,
+            Register::E => core.reg_de() & 0xFF,
+            Register::H => core.reg_hl() >> 8,
+            Register::L => core.reg_hl() & 0xFF,
+            Register::AF => core.reg_af(),
+            Register::BC => core.reg_bc(),
+            Register::DE => core.reg_de(),
+            Register::HL => core.reg_hl(),
+            Register::SP => core.sp(),
+            Register::PC => core.pc(),
+        }
+    }
+}
+
+impl Flag {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "zf" => Flag::Z,
+            "nf" => Flag::N,
+            "hf" => Flag::H,
+            "cf" => Flag::C,
+            _ => return None,
+        })
+    }
+
+    fn get(self, core: &Core) -> bool {
+        match self {
+            Flag::Z => core.flag_z(),
+            Flag::N => core.flag_n(),
+            Flag::H => core.flag_h(),
+            Flag::C => core.flag_c(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Number(i64),
+    Name(String),
+    Op(&'static str),
+}
+
+const OPERATORS: &[&str] = &[
+    "||", "&&", "==", "!=", "<=", ">=", "<<", ">>",
+    "<", ">", "|", "^", "&", "+", "-", "*", "/", "%", "!", "~", "(", ")", "[", "]",
+];
+
+fn tokenize(source: &str) -> Fallible<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut rest = source.trim_start();
+
+    while let Some(c) = rest.chars().next() {
+        let is_name_char = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '.';
+        let len = if c.is_ascii_digit() || c == '$' || (c == '%' && rest[1..].starts_with(['0', '1'])) {
+            let len = 1 + rest[1..].find(|c: char| !c.is_ascii_alphanumeric()).unwrap_or(rest.len() - 1);
+            tokens.push(Token::Number(parse_number(&rest[..len])?));
+            len
+        } else if is_name_char(c) {
+            let len = rest.find(|c: char| !is_name_char(c)).unwrap_or(rest.len());
+            tokens.push(Token::Name(rest[..len].to_owned()));
+            len
+        } else {
+            let op = OPERATORS.iter()
+                .find(|op| rest.starts_with(*op))
+                .ok_or_else(|| format_err!("Unexpected {:?} in expression", c))?;
+            tokens.push(Token::Op(op));
+            op.len()
+        };
+
+        rest = rest[len..].trim_start();
+    }
+
+    Ok(tokens)
+}
+
+fn parse_number(text: &str) -> Fallible<i64> {
+    let (digits, radix) = if let Some(digits) = text.strip_prefix("0x").or_else(|| text.strip_prefix('$')) {
+        (digits, 16)
+    } else if let Some(digits) = text.strip_prefix("0b").or_else(|| text.strip_prefix('%')) {
+        (digits, 2)
+    } else {
+        (text, 10)
+    };
+
+    i64::from_str_radix(digits, radix).map_err(|_| format_err!("Invalid number: {}", text))
+}
+
+struct Parser<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Fallible<&Token> {
+        let token = self.tokens.get(self.pos).ok_or_else(|| format_err!("Unexpected end of expression"))?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn eat(&mut self, op: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Op(found)) if *found == op) {
+            self.pos += 1;
+            return true;
+        }
+
+        false
+    }
+
+    fn expect(&mut self, op: &str) -> Fallible<()> {
+        if !self.eat(op) {
+            bail!("Expected {:?} in expression", op);
+        }
+
+        Ok(())
+    }
+
+    fn parse_binary(&mut self, level: usize) -> Fallible<Node> {
+        let ops = match BINARY_OPS.get(level) {
+            Some(ops) => ops,
+            None => return self.parse_unary(),
+        };
+
+        let mut lhs = self.parse_binary(level + 1)?;
+
+        'outer: loop {
+            for &(symbol, op) in ops.iter() {
+                if self.eat(symbol) {
+                    let rhs = self.parse_binary(level + 1)?;
+                    lhs = Node::Binary(op, Box::new(lhs), Box::new(rhs));
+                    continue 'outer;
+                }
+            }
+
+            break Ok(lhs);
+        }
+    }
+
+    fn parse_unary(&mut self) -> Fallible<Node> {
+        let op = if self.eat("-") {
+            UnaryOp::Neg
+        } else if self.eat("!") {
+            UnaryOp::Not
+        } else if self.eat("~") {
+            UnaryOp::Complement
+        } else {
+            return self.parse_primary();
+        };
+
+        Ok(Node::Unary(op, Box::new(self.parse_unary()?)))
+    }
+
+    fn parse_primary(&mut self) -> Fallible<Node> {
+        Ok(match self.next()?.clone() {
+            Token::Number(value) => Node::Number(value),
+            Token::Op("(") => {
+                let node = self.parse_binary(0)?;
+                self.expect(")")?;
+                node
+            },
+            Token::Op("[") => {
+                let addr = self.parse_binary(0)?;
+                self.expect("]")?;
+                Node::Memory(Box::new(addr))
+            },
+            Token::Name(ref name) if name == "w" && self.eat("[") => {
+                let addr = self.parse_binary(0)?;
+                self.expect("]")?;
+                Node::MemoryWord(Box::new(addr))
+            },
+            Token::Name(name) => {
+                let lower = name.to_ascii_lowercase();
+
+                if let Some(register) = Register::from_name(&lower) {
+                    Node::Register(register)
+                } else if let Some(flag) = Flag::from_name(&lower) {
+                    Node::Flag(flag)
+                } else {
+                    Node::Symbol(name)
+                }
+            },
+            Token::Op(op) => bail!("Unexpected {:?} in expression", op),
+        })
+    }
+}
@@ -0,0 +1,508 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use failure::{bail, format_err, Fallible};
+use crate::core::{Core, StepError};
+use crate::breakpoints::{Breakpoint, Breakpoints};
+use crate::watchpoint::{Watchpoint, WatchKind, WatchHit};
+
+/// Registers in the order of the `g` packet, all 16 bits wide
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.gnu.gdb.z80.cpu">
+    <flags id="af_flags" size="2">
+      <field name="C" start="4" end="4"/>
+      <field name="H" start="5" end="5"/>
+      <field name="N" start="6" end="6"/>
+      <field name="Z" start="7" end="7"/>
+    </flags>
+    <reg name="af" bitsize="16" type="af_flags" regnum="0"/>
+    <reg name="bc" bitsize="16" type="uint16"/>
+    <reg name="de" bitsize="16" type="data_ptr"/>
+    <reg name="hl" bitsize="16" type="data_ptr"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+const REGISTER_COUNT: usize = 6;
+
+/// How many instructions run between checks for a Ctrl-C from the client
+const INTERRUPT_POLL_INTERVAL: u32 = 4096;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGABRT: u8 = 6;
+const SIGSEGV: u8 = 11;
+
+/// A connection to a debugger that can be polled for interrupts while running
+pub trait Connection: Read + Write {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+#[cfg(unix)]
+impl Connection for UnixStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+/// Serves the GDB remote serial protocol for a single client.
+///
+/// Supports register and memory access, software and hardware breakpoints,
+/// watchpoints (mapped onto `Bus` watchpoints), single-stepping and continuing.
+pub struct GdbStub<'a, C> {
+    core: &'a mut Core,
+    connection: C,
+    breakpoints: Breakpoints,
+    no_ack: bool,
+    /// Bytes that arrived while polling for interrupts
+    received: VecDeque<u8>,
+}
+
+enum Flow {
+    Continue,
+    Exit,
+}
+
+impl<'a, C: Connection> GdbStub<'a, C> {
+    pub fn new(core: &'a mut Core, connection: C) -> Self {
+        Self {
+            core,
+            connection,
+            breakpoints: Breakpoints::default(),
+            no_ack: false,
+            received: VecDeque::new(),
+        }
+    }
+
+    /// Handles packets until the client detaches, kills the target or disconnects
+    pub fn serve(mut self) -> Fallible<()> {
+        while let Some(packet) = self.read_packet()? {
+            let (reply, flow) = match self.handle(&packet) {
+                Ok(result) => result,
+                Err(err) => {
+                    eprintln!("gdb: {}: {}", String::from_utf8_lossy(&packet), err);
+                    ("E01".to_owned(), Flow::Continue)
+                },
+            };
+
+            self.write_packet(&reply)?;
+
+            if let Flow::Exit = flow {
+                break;
+            }
+        }
+
+        self.remove_watchpoints();
+
+        Ok(())
+    }
+
+    fn handle(&mut self, packet: &[u8]) -> Fallible<(String, Flow)> {
+        let packet = std::str::from_utf8(packet)?;
+        let (command, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+
+        let reply = match command {
+            "?" => stop_reply(SIGTRAP),
+            "g" => self.read_registers(),
+            "G" => {
+                self.write_registers(args)?;
+                "OK".into()
+            },
+            "p" => format_u16(self.register(usize::from_str_radix(args, 16)?)?),
+            "P" => {
+                let (index, value) = split(args, '=')?;
+                self.set_register(usize::from_str_radix(index, 16)?, parse_u16_le(value)?)?;
+                "OK".into()
+            },
+            "m" => self.read_memory(args)?,
+            "M" => {
+                self.write_memory(args)?;
+                "OK".into()
+            },
+            "s" => {
+                self.set_pc(args)?;
+
+                if let Err(err) = self.core.step() {
+                    return Ok((error_reply(err), Flow::Continue));
+                }
+
+                if self.core.is_locked_up() {
+                    return Ok((stop_reply(SIGILL), Flow::Continue));
+                }
+
+                match self.core.bus_mut().take_watch_hit() {
+                    Some(hit) => watch_reply(hit),
+                    None => stop_reply(SIGTRAP),
+                }
+            },
+            "c" => {
+                self.set_pc(args)?;
+                self.resume()?
+            },
+            "Z" | "z" => self.update_breakpoint(command == "Z", args)?,
+            "H" => "OK".into(),
+            "D" => return Ok(("OK".into(), Flow::Exit)),
+            "k" => return Ok((String::new(), Flow::Exit)),
+            "q" | "Q" | "v" => self.handle_query(packet)?,
+            _ => String::new(),
+        };
+
+        Ok((reply, Flow::Continue))
+    }
+
+    fn handle_query(&mut self, packet: &str) -> Fallible<String> {
+        if packet.starts_with("qSupported") {
+            return Ok("PacketSize=4000;qXfer:features:read+;QStartNoAckMode+;swbreak+;hwbreak+".into());
+        }
+
+        if let Some(args) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let (offset, len) = split(args, ',')?;
+            let offset = usize::from_str_radix(offset, 16)?.min(TARGET_XML.len());
+            let len = usize::from_str_radix(len, 16)?;
+            let chunk = &TARGET_XML[offset..(offset + len).min(TARGET_XML.len())];
+            let more = offset + chunk.len() < TARGET_XML.len();
+
+            return Ok(format!("{}{}", if more { "m" } else { "l" }, chunk));
+        }
+
+        Ok(match packet {
+            "QStartNoAckMode" => {
+                self.no_ack = true;
+                "OK".into()
+            },
+            "qAttached" => "1".into(),
+            "qC" => "QC1".into(),
+            "qfThreadInfo" => "m1".into(),
+            "qsThreadInfo" => "l".into(),
+            _ => String::new(),
+        })
+    }
+
+    fn read_registers(&self) -> String {
+        (0..REGISTER_COUNT)
+            .map(|index| format_u16(self.register(index).unwrap()))
+            .collect()
+    }
+
+    fn write_registers(&mut self, data: &str) -> Fallible<()> {
+        let bytes = parse_hex_bytes(data)?;
+
+        if bytes.len() != REGISTER_COUNT * 2 {
+            bail!("Expected {} registers", REGISTER_COUNT);
+        }
+
+        for (index, value) in bytes.chunks(2).enumerate() {
+            self.set_register(index, u16::from_le_bytes([value[0], value[1]]))?;
+        }
+
+        Ok(())
+    }
+
+    fn register(&self, index: usize) -> Fallible<u16> {
+        Ok(match index {
+            0 => self.core.reg_af(),
+            1 => self.core.reg_bc(),
+            2 => self.core.reg_de(),
+            3 => self.core.reg_hl(),
+            4 => self.core.sp(),
+            5 => self.core.pc(),
+            _ => bail!("Invalid register {}", index),
+        })
+    }
+
+    fn set_register(&mut self, index: usize, value: u16) -> Fallible<()> {
+        match index {
+            0 => self.core.set_reg_af(value & 0xFFF0),
+            1 => self.core.set_reg_bc(value),
+            2 => self.core.set_reg_de(value),
+            3 => self.core.set_reg_hl(value),
+            4 => self.core.set_sp(value),
+            5 => self.core.set_pc(value),
+            _ => bail!("Invalid register {}", index),
+        }
+
+        Ok(())
+    }
+
+    fn read_memory(&self, args: &str) -> Fallible<String> {
+        let (addr, len) = parse_range(args)?;
+
+        Ok((0..len)
+            .map(|offset| format!("{:02x}", self.core.peek_mem_u8(addr.wrapping_add(offset))))
+            .collect())
+    }
+
+    fn write_memory(&mut self, args: &str) -> Fallible<()> {
+        let (range, data) = split(args, ':')?;
+        let (addr, len) = parse_range(range)?;
+        let bytes = parse_hex_bytes(data)?;
+
+        if bytes.len() != len as usize {
+            bail!("Expected {} bytes", len);
+        }
+
+        for (offset, byte) in bytes.into_iter().enumerate() {
+            self.core.write_mem_u8(addr.wrapping_add(offset as u16), byte);
+        }
+
+        Ok(())
+    }
+
+    /// `s` and `c` may carry an address to resume at
+    fn set_pc(&mut self, args: &str) -> Fallible<()> {
+        if !args.is_empty() {
+            self.core.set_pc(parse_addr(args)?);
+        }
+
+        Ok(())
+    }
+
+    /// Runs until a breakpoint or watchpoint is hit, or the client interrupts
+    fn resume(&mut self) -> Fallible<String> {
+        let mut steps = 0u32;
+
+        loop {
+            if let Err(err) = self.core.step() {
+                return Ok(error_reply(err));
+            }
+
+            // Nothing would ever happen again
+            if self.core.is_locked_up() {
+                return Ok(stop_reply(SIGILL));
+            }
+
+            if let Some(hit) = self.core.bus_mut().take_watch_hit() {
+                return Ok(watch_reply(hit));
+            }
+
+            if self.breakpoints.check(self.core).iter().any(|hit| hit.stops()) {
+                return Ok(format!("T{:02x}swbreak:;", SIGTRAP));
+            }
+
+            steps = steps.wrapping_add(1);
+
+            if steps % INTERRUPT_POLL_INTERVAL == 0 && self.poll_interrupt()? {
+                return Ok(stop_reply(SIGINT));
+            }
+        }
+    }
+
+    /// Checks for a Ctrl-C. Anything else the client sent is kept for `read_packet`.
+    fn poll_interrupt(&mut self) -> Fallible<bool> {
+        let mut buffer = [0; 64];
+
+        self.connection.set_nonblocking(true)?;
+        let result = self.connection.read(&mut buffer);
+        self.connection.set_nonblocking(false)?;
+
+        let received = match result {
+            Ok(0) => bail!("Connection closed"),
+            Ok(len) => &buffer[..len],
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(err) => return Err(err.into()),
+        };
+
+        let interrupted = received.contains(&0x03);
+        self.received.extend(received.iter().filter(|&&byte| byte != 0x03));
+
+        Ok(interrupted)
+    }
+
+    /// `Z<type>,<addr>,<kind>`: 0/1 are breakpoints, 2/3/4 write/read/access watchpoints
+    fn update_breakpoint(&mut self, insert: bool, args: &str) -> Fallible<String> {
+        let mut parts = args.split(',');
+        let (kind, addr, len) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(kind), Some(addr), Some(len)) => (kind, parse_addr(addr)?, u16::from_str_radix(len, 16)?),
+            _ => bail!("Malformed breakpoint"),
+        };
+
+        let watch_kind = match kind {
+            "0" | "1" => {
+                // gdb doesn't know about banks, so these stop in any of them
+                let exists = self.breakpoints.iter().any(|breakpoint| breakpoint.addr == addr);
+
+                if insert && !exists {
+                    self.breakpoints.add(Breakpoint::at_addr(addr));
+                } else if !insert {
+                    self.breakpoints.retain(|breakpoint| breakpoint.addr != addr);
+                }
+
+                return Ok("OK".into());
+            },
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            "4" => WatchKind::Access,
+            _ => return Ok(String::new()),
+        };
+
+        let watchpoint = Watchpoint {
+            kind: watch_kind,
+            start: addr,
+            end: addr.wrapping_add(len.max(1) - 1),
+            value: None,
+        };
+
+        if insert {
+            self.core.bus_mut().add_watchpoint(watchpoint);
+        } else {
+            let index = self.core.bus().watchpoints().iter().position(|&existing| existing == watchpoint);
+
+            if let Some(index) = index {
+                self.core.bus_mut().remove_watchpoint(index);
+            }
+        }
+
+        Ok("OK".into())
+    }
+
+    /// Watchpoints belong to the session, so they are removed when the client leaves.
+    /// Breakpoints only live in the stub anyway.
+    fn remove_watchpoints(&mut self) {
+        while self.core.bus_mut().remove_watchpoint(0).is_some() {}
+    }
+
+    /// Reads the next packet, skipping acks and stray interrupts.
+    /// Returns `None` when the client disconnected.
+    fn read_packet(&mut self) -> Fallible<Option<Vec<u8>>> {
+        loop {
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b'$') => break,
+                Some(_) => continue,
+            }
+        }
+
+        let mut packet = Vec::new();
+
+        loop {
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b'#') => break,
+                Some(b'}') => match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(byte) => packet.push(byte ^ 0x20),
+                },
+                Some(byte) => packet.push(byte),
+            }
+        }
+
+        // The checksum is only relevant for unreliable transports
+        for _ in 0..2 {
+            if self.read_byte()?.is_none() {
+                return Ok(None);
+            }
+        }
+
+        if !self.no_ack {
+            self.connection.write_all(b"+")?;
+        }
+
+        Ok(Some(packet))
+    }
+
+    fn read_byte(&mut self) -> Fallible<Option<u8>> {
+        if let Some(byte) = self.received.pop_front() {
+            return Ok(Some(byte));
+        }
+
+        let mut byte = [0];
+
+        match self.connection.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    fn write_packet(&mut self, data: &str) -> Fallible<()> {
+        let checksum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+
+        write!(self.connection, "${}#{:02x}", data, checksum)?;
+        self.connection.flush()?;
+
+        Ok(())
+    }
+}
+
+fn stop_reply(signal: u8) -> String {
+    format!("S{:02x}", signal)
+}
+
+/// Reports instructions that can't be executed like the corresponding signal on a real target
+fn error_reply(err: StepError) -> String {
+    eprintln!("gdb: {}", err);
+
+    stop_reply(match err {
+        StepError::IllegalOpcode { .. } => SIGILL,
+        StepError::UnmappedAccess { .. } => SIGSEGV,
+        StepError::Unimplemented { .. } => SIGABRT,
+    })
+}
+
+fn watch_reply(hit: WatchHit) -> String {
+    let kind = match hit.watchpoint.kind {
+        WatchKind::Write => "watch",
+        WatchKind::Read => "rwatch",
+        WatchKind::Access => "awatch",
+    };
+
+    format!("T{:02x}{}:{:x};", SIGTRAP, kind, hit.addr)
+}
+
+/// Registers are transferred in target byte order, i.e. little-endian
+fn format_u16(value: u16) -> String {
+    format!("{:02x}{:02x}", value as u8, value >> 8)
+}
+
+fn parse_u16_le(text: &str) -> Fallible<u16> {
+    match *parse_hex_bytes(text)? {
+        [lo, hi] => Ok(u16::from_le_bytes([lo, hi])),
+        _ => bail!("Expected a 16 bit value"),
+    }
+}
+
+/// Works on bytes, so a multibyte character is an invalid digit rather than a bad slice
+fn parse_hex_bytes(text: &str) -> Fallible<Vec<u8>> {
+    if text.len() % 2 != 0 {
+        bail!("Odd number of hex digits");
+    }
+
+    let digit = |byte: u8| match (byte as char).to_digit(16) {
+        Some(digit) => Ok(digit as u8),
+        None => Err(format_err!("Invalid hex digit: {:?}", text)),
+    };
+
+    text.as_bytes()
+        .chunks(2)
+        .map(|pair| Ok(digit(pair[0])? << 4 | digit(pair[1])?))
+        .collect()
+}
+
+/// GDB may use wider addresses, only the low 16 bits are meaningful
+fn parse_addr(text: &str) -> Fallible<u16> {
+    Ok(u64::from_str_radix(text, 16)? as u16)
+}
+
+fn parse_range(text: &str) -> Fallible<(u16, u16)> {
+    let (addr, len) = split(text, ',')?;
+    Ok((parse_addr(addr)?, u16::from_str_radix(len, 16)?))
+}
+
+fn split(text: &str, separator: char) -> Fallible<(&str, &str)> {
+    match text.split_once(separator) {
+        Some(parts) => Ok(parts),
+        None => bail!("Expected {:?} in {:?}", separator, text),
+    }
+}
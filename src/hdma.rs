@@ -0,0 +1,131 @@
+use failure::Fallible;
+use crate::bus::Device;
+use crate::state::{SaveState, StateWriter, StateReader, StateError};
+
+// The HDMA registers only keep track of the transfer.
+// Copying the bytes is done by the `Bus`, since it can see both the source and VRAM.
+
+/// Number of bytes copied per block.
+pub const HDMA_BLOCK_SIZE: u16 = 0x10;
+
+/// Cycles (T-states) the CPU is stalled for every block copied.
+pub const HDMA_BLOCK_CYCLES: u32 = 32;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum HdmaMode {
+    Idle,
+    /// General purpose DMA: everything is copied at once.
+    General,
+    /// HBlank DMA: one block is copied per HBlank.
+    HBlank,
+}
+
+pub struct Hdma {
+    source: u16,
+    destination: u16,
+    remaining_blocks: u8,
+    mode: HdmaMode,
+}
+
+impl Hdma {
+    pub fn new() -> Self {
+        Self {
+            source: 0,
+            destination: 0,
+            remaining_blocks: 0,
+            mode: HdmaMode::Idle,
+        }
+    }
+
+    pub fn mode(&self) -> HdmaMode {
+        self.mode
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.mode != HdmaMode::Idle
+    }
+
+    pub fn source(&self) -> u16 {
+        self.source
+    }
+
+    /// Destination as an absolute address in 0x8000..=0x9FF0
+    pub fn destination(&self) -> u16 {
+        0x8000 | (self.destination & 0x1FF0)
+    }
+
+    /// Advances the transfer after a block has been copied.
+    pub fn finish_block(&mut self) {
+        self.source = self.source.wrapping_add(HDMA_BLOCK_SIZE);
+        self.destination = self.destination.wrapping_add(HDMA_BLOCK_SIZE) & 0x1FF0;
+        self.remaining_blocks = self.remaining_blocks.saturating_sub(1);
+
+        if self.remaining_blocks == 0 {
+            self.mode = HdmaMode::Idle;
+        }
+    }
+}
+
+impl SaveState for Hdma {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u16(self.source);
+        w.write_u16(self.destination);
+        w.write_u8(self.remaining_blocks);
+        w.write_u8(self.mode as u8);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Fallible<()> {
+        self.source = r.read_u16()?;
+        self.destination = r.read_u16()?;
+        self.remaining_blocks = r.read_u8()?;
+        self.mode = match r.read_u8()? {
+            0 => HdmaMode::Idle,
+            1 => HdmaMode::General,
+            2 => HdmaMode::HBlank,
+            mode => return Err(StateError::Corrupted(format!("invalid HDMA mode {}", mode)).into()),
+        };
+        Ok(())
+    }
+}
+
+impl Device for Hdma {
+    fn read(&self, addr: u16) -> u8 {
+        match addr {
+            // HDMA1-HDMA4 are write-only
+            0xFF51..=0xFF54 => 0xFF,
+            0xFF55 => {
+                let blocks = self.remaining_blocks.wrapping_sub(1) & 0x7F;
+
+                if self.is_active() {
+                    blocks
+                } else {
+                    0x80 | blocks
+                }
+            },
+            _ => panic!("Invalid HDMA read @ 0x{:02X}", addr),
+        }
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            0xFF51 => self.source = (value as u16) << 8 | (self.source & 0x00FF),
+            0xFF52 => self.source = (self.source & 0xFF00) | (value & 0xF0) as u16,
+            0xFF53 => self.destination = ((value & 0x1F) as u16) << 8 | (self.destination & 0x00FF),
+            0xFF54 => self.destination = (self.destination & 0x1F00) | (value & 0xF0) as u16,
+            0xFF55 => {
+                let hblank = value & 0x80 != 0;
+
+                // Writing bit 7 = 0 during an HBlank transfer cancels it.
+                // The remaining length stays readable.
+                if self.mode == HdmaMode::HBlank && !hblank {
+                    self.mode = HdmaMode::Idle;
+                    return;
+                }
+
+                self.remaining_blocks = (value & 0x7F) + 1;
+                self.mode = if hblank { HdmaMode::HBlank } else { HdmaMode::General };
+            },
+            _ => panic!("Invalid HDMA write @ 0x{:02X}", addr),
+        }
+    }
+}
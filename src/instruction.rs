@@ -1,5 +1,5 @@
 
-pub fn decode(code: u8) -> Instruction {
+pub const fn decode(code: u8) -> Instruction {
     use Instruction::*;
     use Operand::*;
     use self::Reg8::*;
@@ -68,7 +68,7 @@ pub fn decode(code: u8) -> Instruction {
         0x37 => Scf,
         0x38 => Jr(CSet, Imm8),
         0x39 => Add(Reg16(HL), Reg16(SP)),
-        0x3A => Ld(Reg8(E), RegRef16(HLDec)),
+        0x3A => Ld(Reg8(A), RegRef16(HLDec)),
         0x3B => Dec(Reg16(SP)),
         0x3C => Inc(Reg8(A)),
         0x3D => Dec(Reg8(A)),
@@ -258,7 +258,7 @@ pub fn decode(code: u8) -> Instruction {
         0xE6 => And(Imm8),
         0xE7 => Rst(0x20),
         0xE8 => Add(Reg16(SP), Imm8),
-        0xE9 => Jp(Always, RegRef16(HL)),
+        0xE9 => Jp(Always, Reg16(HL)),
         0xEA => Ld(Imm16Ref, Reg8(A)),
         0xEB => Invalid,
         0xEC => Invalid,
@@ -285,7 +285,7 @@ pub fn decode(code: u8) -> Instruction {
     }
 }
 
-pub fn decode_extended(code: u8) -> ExtendedInstruction {
+pub const fn decode_extended(code: u8) -> ExtendedInstruction {
     use ExtendedInstruction::*;
     use Operand::*;
     use self::Reg8::*;
@@ -551,6 +551,32 @@ pub fn decode_extended(code: u8) -> ExtendedInstruction {
     }
 }
 
+/// `decode` of every opcode, computed at compile time
+pub static DECODE_TABLE: [Instruction; 256] = {
+    let mut table = [Instruction::Nop; 256];
+    let mut code = 0;
+
+    while code < table.len() {
+        table[code] = decode(code as u8);
+        code += 1;
+    }
+
+    table
+};
+
+/// `decode_extended` of every opcode, computed at compile time
+pub static EXTENDED_DECODE_TABLE: [ExtendedInstruction; 256] = {
+    let mut table = [ExtendedInstruction::Rlc(Operand::Reg8(Reg8::B)); 256];
+    let mut code = 0;
+
+    while code < table.len() {
+        table[code] = decode_extended(code as u8);
+        code += 1;
+    }
+
+    table
+};
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Instruction {
     Nop,
@@ -595,17 +621,19 @@ pub enum Instruction {
 
 impl Instruction {
     pub fn decode(code: u8) -> Self {
-        decode(code)
+        DECODE_TABLE[code as usize]
     }
 
+    /// Length in bytes, including the opcode
+    #[allow(clippy::len_without_is_empty)]
     pub fn len(&self) -> u16 {
-        match self {
-            Instruction::Nop => 1,
+        1 + match self {
+            Instruction::Nop => 0,
             Instruction::Ld(operand1, operand2) => operand1.len() + operand2.len(),
             Instruction::Inc(operand) => operand.len(),
             Instruction::Dec(operand) => operand.len(),
             Instruction::Add(operand1, operand2) => operand1.len() + operand2.len(),
-            Instruction::Adc(operand) => 1 + operand.len(),
+            Instruction::Adc(operand) => operand.len(),
             Instruction::Sub(operand) => operand.len(),
             Instruction::Sbc(operand1, operand2) => operand1.len() + operand2.len(),
             Instruction::Mul(operand1, operand2) => operand1.len() + operand2.len(),
@@ -617,29 +645,90 @@ impl Instruction {
             Instruction::Jr(_cond, operand) => operand.len(),
             Instruction::Jp(_cond, operand) => operand.len(),
             Instruction::Call(_cond, operand) => operand.len(),
-            Instruction::Ret(_cond) => 1,
-            Instruction::Reti => 1,
-            Instruction::Pop(_reg) => 1,
-            Instruction::Push(_reg) => 1,
+            Instruction::Ret(_cond) => 0,
+            Instruction::Reti => 0,
+            Instruction::Pop(_reg) => 0,
+            Instruction::Push(_reg) => 0,
+            // STOP is followed by an (ignored) padding byte
             Instruction::Stop => 1,
-            Instruction::Halt => 1,
-            Instruction::Rlca => 1,
-            Instruction::Rrca => 1,
-            Instruction::Rla => 1,
-            Instruction::Rra => 1,
-            Instruction::Daa => 1,
-            Instruction::Cpl => 1,
-            Instruction::Scf => 1,
-            Instruction::Ccf => 1,
-            Instruction::Rst(_addr) => 1,
+            Instruction::Halt => 0,
+            Instruction::Rlca => 0,
+            Instruction::Rrca => 0,
+            Instruction::Rla => 0,
+            Instruction::Rra => 0,
+            Instruction::Daa => 0,
+            Instruction::Cpl => 0,
+            Instruction::Scf => 0,
+            Instruction::Ccf => 0,
+            Instruction::Rst(_addr) => 0,
+            // The CB prefix is followed by the extended opcode
             Instruction::Extended => 1,
-            Instruction::Invalid => 1,
+            Instruction::Invalid => 0,
             Instruction::Ldh(operand1, operand2) => operand1.len() + operand2.len(),
-            Instruction::Di => 1,
-            Instruction::Ei => 1,
+            Instruction::Di => 0,
+            Instruction::Ei => 0,
             Instruction::Ldhl => 1,
         }
     }
+
+    /// Duration in T-cycles (4 per M-cycle), including the opcode fetch.
+    /// Conditional jumps, calls and returns take longer when `taken`.
+    /// `Extended` only covers the CB prefix, see `ExtendedInstruction::cycles`.
+    pub fn cycles(&self, taken: bool) -> u8 {
+        let branch = |taken_cycles, skipped_cycles| if taken { taken_cycles } else { skipped_cycles };
+
+        match *self {
+            Instruction::Ld(Operand::Reg16(Reg16::SP), Operand::Reg16(Reg16::HL)) => 8,
+            Instruction::Ld(Operand::Imm16Ref, Operand::Reg16(Reg16::SP)) => 20,
+            Instruction::Ld(operand1, operand2) => 4 + operand1.cycles() + operand2.cycles(),
+            Instruction::Ldh(operand1, operand2) => 4 + operand1.cycles() + operand2.cycles(),
+            Instruction::Inc(Operand::Reg16(_)) | Instruction::Dec(Operand::Reg16(_)) => 8,
+            // Read and write back
+            Instruction::Inc(Operand::RegRef16(_)) | Instruction::Dec(Operand::RegRef16(_)) => 12,
+            Instruction::Inc(_) | Instruction::Dec(_) => 4,
+            Instruction::Add(Operand::Reg16(Reg16::SP), _) => 16,
+            Instruction::Add(Operand::Reg16(_), _) => 8,
+            Instruction::Add(_, operand) => 4 + operand.cycles(),
+            Instruction::Adc(operand) => 4 + operand.cycles(),
+            Instruction::Sub(operand) => 4 + operand.cycles(),
+            Instruction::Sbc(_, operand) => 4 + operand.cycles(),
+            Instruction::And(operand) => 4 + operand.cycles(),
+            Instruction::Xor(operand) => 4 + operand.cycles(),
+            Instruction::Or(operand) => 4 + operand.cycles(),
+            Instruction::Cp(operand) => 4 + operand.cycles(),
+            // Not SM83 instructions, never decoded
+            Instruction::Mul(..) | Instruction::Div(..) => 0,
+            Instruction::Jr(Cond::Always, _) => 12,
+            Instruction::Jr(_, _) => branch(12, 8),
+            Instruction::Jp(_, Operand::Reg16(_)) => 4,
+            Instruction::Jp(Cond::Always, _) => 16,
+            Instruction::Jp(_, _) => branch(16, 12),
+            Instruction::Call(Cond::Always, _) => 24,
+            Instruction::Call(_, _) => branch(24, 12),
+            Instruction::Ret(Cond::Always) => 16,
+            Instruction::Ret(_) => branch(20, 8),
+            Instruction::Reti => 16,
+            Instruction::Pop(_) => 12,
+            Instruction::Push(_) => 16,
+            Instruction::Rst(_) => 16,
+            Instruction::Ldhl => 12,
+            Instruction::Nop
+            | Instruction::Stop
+            | Instruction::Halt
+            | Instruction::Rlca
+            | Instruction::Rrca
+            | Instruction::Rla
+            | Instruction::Rra
+            | Instruction::Daa
+            | Instruction::Cpl
+            | Instruction::Scf
+            | Instruction::Ccf
+            | Instruction::Extended
+            | Instruction::Invalid
+            | Instruction::Di
+            | Instruction::Ei => 4,
+        }
+    }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
@@ -659,7 +748,26 @@ pub enum ExtendedInstruction {
 
 impl ExtendedInstruction {
     pub fn decode(code: u8) -> Self {
-        decode_extended(code)
+        EXTENDED_DECODE_TABLE[code as usize]
+    }
+
+    /// Duration in T-cycles, including the CB prefix
+    pub fn cycles(&self) -> u8 {
+        match *self {
+            // Only reads [HL]
+            ExtendedInstruction::Bit(_, Operand::RegRef16(_)) => 12,
+            ExtendedInstruction::Rlc(Operand::RegRef16(_))
+            | ExtendedInstruction::Rrc(Operand::RegRef16(_))
+            | ExtendedInstruction::Rl(Operand::RegRef16(_))
+            | ExtendedInstruction::Rr(Operand::RegRef16(_))
+            | ExtendedInstruction::Sla(Operand::RegRef16(_))
+            | ExtendedInstruction::Sra(Operand::RegRef16(_))
+            | ExtendedInstruction::Swap(Operand::RegRef16(_))
+            | ExtendedInstruction::Srl(Operand::RegRef16(_))
+            | ExtendedInstruction::Res(_, Operand::RegRef16(_))
+            | ExtendedInstruction::Set(_, Operand::RegRef16(_)) => 16,
+            _ => 8,
+        }
     }
 }
 
@@ -677,6 +785,8 @@ pub enum Operand {
 }
 
 impl Operand {
+    /// Number of immediate bytes following the opcode
+    #[allow(clippy::len_without_is_empty)]
     pub fn len(&self) -> u16 {
         match self {
             Operand::Reg8(_reg8) => 0,
@@ -685,8 +795,23 @@ impl Operand {
             Operand::RegRef16(_reg16) => 0,
             Operand::Imm8 => 1,
             Operand::Imm8Ref => 1,
-            Operand::Imm16 => 1,
-            Operand::Imm16Ref => 1,
+            Operand::Imm16 => 2,
+            Operand::Imm16Ref => 2,
+            Operand::Cond(_cond) => 0,
+        }
+    }
+
+    /// T-cycles spent fetching immediates and accessing memory
+    pub fn cycles(&self) -> u8 {
+        match self {
+            Operand::Reg8(_reg8) => 0,
+            Operand::RegRef8(_reg8) => 4,
+            Operand::Reg16(_reg16) => 0,
+            Operand::RegRef16(_reg16) => 4,
+            Operand::Imm8 => 4,
+            Operand::Imm8Ref => 8,
+            Operand::Imm16 => 8,
+            Operand::Imm16Ref => 12,
             Operand::Cond(_cond) => 0,
         }
     }
@@ -0,0 +1,123 @@
+use failure::Fallible;
+use crate::bus::Device;
+use crate::state::{SaveState, StateWriter, StateReader, StateError};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+impl Button {
+    fn mask(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+pub struct Joypad {
+    select: u8,
+    /// Pressed buttons per player, one bit per `Button`
+    pressed: [u8; 4],
+    player_count: u8,
+    current_player: u8,
+}
+
+impl Joypad {
+    pub fn new() -> Self {
+        Self {
+            select: 0x30,
+            pressed: [0; 4],
+            player_count: 1,
+            current_player: 0,
+        }
+    }
+
+    /// Players 4 and up don't exist and are ignored
+    pub fn set_pressed(&mut self, player: usize, button: Button, pressed: bool) {
+        if let Some(buttons) = self.pressed.get_mut(player) {
+            if pressed {
+                *buttons |= button.mask();
+            } else {
+                *buttons &= !button.mask();
+            }
+        }
+    }
+
+    pub fn player_count(&self) -> u8 {
+        self.player_count
+    }
+
+    /// Number of joypads as requested via SGB MLT_REQ (1, 2 or 4)
+    pub fn set_player_count(&mut self, count: u8) {
+        self.player_count = count;
+        self.current_player = 0;
+    }
+}
+
+impl SaveState for Joypad {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.select);
+        w.write_raw(&self.pressed);
+        w.write_u8(self.player_count);
+        w.write_u8(self.current_player);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Fallible<()> {
+        self.select = r.read_u8()? & 0x30;
+        self.pressed.copy_from_slice(r.read_raw(4)?);
+        self.player_count = r.read_u8()?;
+        self.current_player = r.read_u8()?;
+
+        if ![1, 2, 4].contains(&self.player_count) {
+            return Err(StateError::Corrupted(format!("invalid player count {}", self.player_count)).into());
+        }
+
+        if self.current_player >= self.player_count {
+            return Err(StateError::Corrupted(format!("invalid current player {}", self.current_player)).into());
+        }
+
+        Ok(())
+    }
+}
+
+impl Device for Joypad {
+    fn read(&self, _addr: u16) -> u8 {
+        let pressed = self.pressed[self.current_player as usize];
+        let mut keys = 0x0F;
+
+        // P14 selects the direction keys
+        if self.select & 0x10 == 0 {
+            keys &= !pressed & 0x0F;
+        }
+
+        // P15 selects the button keys
+        if self.select & 0x20 == 0 {
+            keys &= !(pressed >> 4) & 0x0F;
+        }
+
+        // With multiple players and nothing selected,
+        // the low nibble holds the current joypad ID instead.
+        if self.select == 0x30 && self.player_count > 1 {
+            keys = 0x0F - self.current_player;
+        }
+
+        0xC0 | self.select | keys
+    }
+
+    fn write(&mut self, _addr: u16, value: u8) {
+        let select = value & 0x30;
+
+        // Deselecting both lines after P15 was low selects the next joypad
+        if select == 0x30 && self.select & 0x20 == 0 && self.player_count > 1 {
+            self.current_player = (self.current_player + 1) % self.player_count;
+        }
+
+        self.select = select;
+    }
+}
@@ -1,11 +1,44 @@
-pub use self::core::Core;
+pub use self::core::{Core, StepError, IllegalOpcodePolicy};
 pub use self::cartridge::Cartridge;
-pub use self::bus::Bus;
+pub use self::bus::{Bus, MemoryBus, FlatBus};
+pub use self::joypad::Button;
+pub use self::state::{StateError, STATE_VERSION};
+pub use self::rewind::{Rewind, encode_delta, decode_delta};
+pub use self::timeline::Timeline;
+pub use self::instruction::{Instruction, ExtendedInstruction, Operand, Reg8, Reg16, Cond};
+pub use self::disasm::{Disassembly, disassemble, disassemble_range, disassemble_before};
+pub use self::symbols::{Symbols, BankAddr, parse_bank_addr};
+pub use self::watchpoint::{Watchpoint, WatchKind, WatchHit};
+pub use self::expr::Expr;
+pub use self::callstack::{CallStack, Frame};
+pub use self::breakpoints::{Breakpoint, Breakpoints, HitCondition, Hit, RunMode};
+pub use self::gdb::{GdbStub, Connection};
+pub use self::sourcemap::SourceMap;
+pub use self::dap::{DapServer, spawn_reader};
+pub use self::trace::{Tracer, TRACE_REGISTERS, parse_trace_line};
+pub use self::blocks::{Divergence, find_divergence};
 
 mod instruction;
 mod core;
 mod bus;
 mod cartridge;
+mod hdma;
+mod joypad;
+mod sgb;
+mod state;
+mod rewind;
+mod timeline;
+mod disasm;
+mod symbols;
+mod watchpoint;
+mod expr;
+mod callstack;
+mod breakpoints;
+mod gdb;
+mod sourcemap;
+mod dap;
+mod trace;
+mod blocks;
 
 pub mod constants {
     pub const LO_RAM_SIZE: usize = 8 * 1024;
@@ -16,12 +49,23 @@ pub mod constants {
     pub const VRAM_START: u16 = 0x8000;
     pub const VRAM_END: u16 = 0x9FFF;
     pub const VRAM_SIZE: usize = 0x2000;
+    pub const CART_RAM_START: u16 = 0xA000;
+    pub const CART_RAM_END: u16 = 0xBFFF;
     pub const LO_RAM_START: u16 = 0xC000;
     pub const LO_RAM_END: u16 = 0xDFFF;
     pub const IO_START: u16 = 0xFF00;
+    pub const IO_REG_JOYP: u16 = 0xFF00;
+    pub const IO_REG_LCDC: u16 = 0xFF40;
     pub const IO_REG_LY: u16 = 0xFF44;
+    pub const IO_REG_VBK: u16 = 0xFF4F;
+    pub const IO_REG_HDMA1: u16 = 0xFF51;
+    pub const IO_REG_HDMA5: u16 = 0xFF55;
     pub const IO_END: u16 = 0xFF4B;
     pub const HI_RAM_START: u16 = 0xFF80;
     pub const HI_RAM_END: u16 = 0xFFFE;
     pub const INTERRUPT_ENABLE_REGISTER: u16 = 0xFFFF;
+    pub const SCREEN_WIDTH: usize = 160;
+    pub const SCREEN_HEIGHT: usize = 144;
+    pub const SGB_SCREEN_WIDTH: usize = 256;
+    pub const SGB_SCREEN_HEIGHT: usize = 224;
 }
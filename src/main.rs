@@ -1,33 +1,324 @@
 extern crate good_boi;
 
-use std::io::Write;
+use std::fs;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::collections::VecDeque;
 use std::error::Error;
-use std::collections::HashSet;
-use good_boi::{Cartridge, Core, Bus};
+use std::path::PathBuf;
+use std::net::{TcpListener, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+use rustyline::{Editor, Helper, Context};
+use rustyline::completion::{Completer, FilenameCompleter, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::FileHistory;
+use rustyline::validate::Validator;
+use good_boi::{Cartridge, Core, Bus, Timeline, Rewind, Symbols, BankAddr, parse_bank_addr, Watchpoint, WatchKind, Expr};
+use good_boi::{Disassembly, GdbStub, DapServer, IllegalOpcodePolicy, Tracer, spawn_reader, disassemble_before};
+use good_boi::{TRACE_REGISTERS, parse_trace_line, find_divergence};
+use good_boi::{Breakpoint, Breakpoints, Hit, HitCondition, RunMode};
 
-fn main() {
+const USAGE: &str = "Usage: good_boi [rom.gb]
+       good_boi gdb [--allow-remote] <host:port | unix:path> [rom.gb]
+       good_boi dap [[--allow-remote] host:port]
+       good_boi trace-diff <rom.gb> <reference.log>
+       good_boi blocks-diff <rom.gb> [instructions]";
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args = std::env::args().collect::<Vec<String>>();
+    let args = args.iter().map(String::as_str).collect::<Vec<&str>>();
+
+    match &args[1..] {
+        [] => Debugger::new(load_bus(None)?).run(),
+        ["dap"] => serve_dap(None, false)?,
+        [rom] if !rom.starts_with('-') => Debugger::new(load_bus(Some(rom))?).run(),
+        ["gdb", "--allow-remote", address] => serve_gdb(address, true, load_bus(None)?)?,
+        ["gdb", "--allow-remote", address, rom] => serve_gdb(address, true, load_bus(Some(rom))?)?,
+        ["gdb", address] => serve_gdb(address, false, load_bus(None)?)?,
+        ["gdb", address, rom] => serve_gdb(address, false, load_bus(Some(rom))?)?,
+        ["dap", "--allow-remote", address] => serve_dap(Some(address), true)?,
+        ["dap", address] => serve_dap(Some(address), false)?,
+        ["trace-diff", rom, reference] => trace_diff(rom, reference)?,
+        ["blocks-diff", rom] => blocks_diff(rom, BLOCKS_DIFF_INSTRUCTIONS)?,
+        ["blocks-diff", rom, instructions] => blocks_diff(rom, instructions.parse()?)?,
+        _ => return Err(USAGE.into()),
+    }
+
+    Ok(())
+}
+
+fn load_bus(path: Option<&str>) -> Result<Bus, Box<dyn Error>> {
     // let rom = include_bytes!("../gb-test-roms/cpu_instrs/cpu_instrs.gb");
     // let rom = include_bytes!("../gb-test-roms/cpu_instrs/individual/02-interrupts.gb");
     let rom = include_bytes!("../gb-test-roms/cpu_instrs/individual/04-op r,imm.gb");
     // let rom = include_bytes!("../gb-test-roms/cpu_instrs/individual/06-ld r,r.gb");
     // let rom = include_bytes!("/tmp/test.gb");
 
-    let cartridge = Cartridge::load(&rom[..]).unwrap();
-    let bus = Bus::new(cartridge);
+    let rom = match path {
+        Some(path) => fs::read(path)?,
+        None => rom.to_vec(),
+    };
+
+    let cartridge = Cartridge::load(rom).map_err(|err| err.to_string())?;
+
+    Ok(Bus::new(cartridge))
+}
+
+/// Waits for a single gdb connection on `address` and serves it
+fn serve_gdb(address: &str, allow_remote: bool, bus: Bus) -> Result<(), Box<dyn Error>> {
+    let mut core = Core::new(bus);
+
+    if let Some(path) = address.strip_prefix("unix:") {
+        #[cfg(unix)]
+        {
+            // A socket left over from an earlier session would make bind fail
+            remove_socket(path)?;
+
+            let listener = UnixListener::bind(path)?;
+            println!("Waiting for gdb on {}", path);
+
+            let (stream, _) = listener.accept()?;
+            GdbStub::new(&mut core, stream).serve().map_err(|err| err.to_string())?;
+            remove_socket(path)?;
+
+            return Ok(());
+        }
+
+        #[cfg(not(unix))]
+        return Err(format!("Unix sockets are not supported here: {}", path).into());
+    }
+
+    let listener = bind_tcp(address, allow_remote)?;
+    println!("Waiting for gdb on {}", listener.local_addr()?);
+
+    let (stream, peer) = listener.accept()?;
+    println!("gdb connected from {}", peer);
+    stream.set_nodelay(true)?;
+    GdbStub::new(&mut core, stream).serve().map_err(|err| err.to_string())?;
+
+    Ok(())
+}
+
+/// Serves a single debug adapter client on stdio, or on `address` if given.
+/// The ROM is chosen by the client's launch request.
+fn serve_dap(address: Option<&str>, allow_remote: bool) -> Result<(), Box<dyn Error>> {
+    let address = match address {
+        Some(address) => address,
+        None => {
+            let requests = spawn_reader(BufReader::new(io::stdin()));
+            DapServer::new(io::stdout()).serve(requests).map_err(|err| err.to_string())?;
+            return Ok(());
+        },
+    };
+
+    let listener = bind_tcp(address, allow_remote)?;
+    eprintln!("Waiting for a debug adapter client on {}", listener.local_addr()?);
+
+    let (stream, _) = listener.accept()?;
+    stream.set_nodelay(true)?;
+    let requests = spawn_reader(BufReader::new(stream.try_clone()?));
+    DapServer::new(stream).serve(requests).map_err(|err| err.to_string())?;
+
+    Ok(())
+}
+
+/// Clients of the debug servers can read and write all of the machine,
+/// so only local ones are accepted unless `allow_remote` is set
+fn bind_tcp(address: &str, allow_remote: bool) -> Result<TcpListener, Box<dyn Error>> {
+    let addresses = address.to_socket_addrs()?.collect::<Vec<_>>();
+
+    if !allow_remote {
+        if let Some(address) = addresses.iter().find(|address| !address.ip().is_loopback()) {
+            return Err(format!("{} is not a loopback address, pass --allow-remote to listen on it", address).into());
+        }
+    }
+
+    Ok(TcpListener::bind(&addresses[..])?)
+}
+
+/// Removes the socket at `path`, but never anything else
+#[cfg(unix)]
+fn remove_socket(path: &str) -> Result<(), Box<dyn Error>> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => Ok(fs::remove_file(path)?),
+        Ok(_) => Err(format!("{} exists and is not a socket", path).into()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Matching lines shown before the first difference by `trace-diff`
+const TRACE_DIFF_CONTEXT: usize = 5;
+
+/// Runs `rom` alongside a reference trace in Gameboy Doctor format
+/// and reports the first line whose registers differ.
+/// Starts from the registers of the first line, with LY stuck at $90 like Gameboy Doctor expects.
+fn trace_diff(rom: &str, reference: &str) -> Result<(), Box<dyn Error>> {
+    let mut bus = load_bus(Some(rom))?;
+    bus.stub_ly(Some(0x90));
+
+    let mut core = Core::new(bus);
+    let reference = BufReader::new(File::open(reference)?);
+    let mut context = VecDeque::new();
+    let mut previous_pc = None;
+    let mut matching = 0;
+    let mut seeded = false;
+
+    for (index, expected_line) in reference.lines().enumerate() {
+        let expected_line = expected_line?;
+
+        if expected_line.trim().is_empty() {
+            continue;
+        }
+
+        let expected = parse_trace_line(&expected_line).map_err(|err| format!("Line {}: {}", index + 1, err))?;
+
+        // The reference may have been made with other post-boot registers than ours
+        if !seeded {
+            let [a, f, b, c, d, e, h, l, sp, pc] = expected;
+            core.set_reg_af(a << 8 | f);
+            core.set_reg_bc(b << 8 | c);
+            core.set_reg_de(d << 8 | e);
+            core.set_reg_hl(h << 8 | l);
+            core.set_sp(sp);
+            core.set_pc(pc);
+            seeded = true;
+        }
+
+        let actual_line = core.trace_line();
+        let actual = parse_trace_line(&actual_line).map_err(|err| err.to_string())?;
+
+        if actual != expected {
+            println!("First difference at line {}, after {} matching instructions:", index + 1, matching);
+
+            for line in &context {
+                println!("  {}", line);
+            }
+
+            println!("- {}", expected_line);
+            println!("+ {}", actual_line);
+            println!();
+
+            for (register, (expected, actual)) in TRACE_REGISTERS.iter().zip(expected.iter().zip(&actual)) {
+                match *register {
+                    _ if expected == actual => {},
+                    "F" => println!("F: expected {:02X} ({}), got {:02X} ({})", expected, flags(*expected), actual, flags(*actual)),
+                    "SP" | "PC" => println!("{}: expected {:04X}, got {:04X}", register, expected, actual),
+                    _ => println!("{}: expected {:02X}, got {:02X}", register, expected, actual),
+                }
+            }
+
+            // The difference is caused by the instruction before
+            if let Some(pc) = previous_pc {
+                let disassembly = core.disassemble(pc);
+                println!("After {}  {}", core.format_addr(pc), core.render_instruction(&disassembly));
+            }
+
+            return Err("The traces differ".into());
+        }
+
+        if context.len() >= TRACE_DIFF_CONTEXT {
+            context.pop_front();
+        }
+
+        context.push_back(expected_line);
+        previous_pc = Some(core.pc());
+        matching += 1;
+
+        if let Err(err) = core.step() {
+            println!("Stopping after {} matching instructions: {}", matching, err);
+            return Err("The traces differ".into());
+        }
+    }
+
+    println!("All {} instructions match.", matching);
 
-    Debugger::new(bus).run();
+    Ok(())
 }
 
+/// Instructions run by `blocks-diff` by default
+const BLOCKS_DIFF_INSTRUCTIONS: u64 = 10_000_000;
+
+/// Runs `rom` in the interpreter and with the block cache side by side
+/// and reports the first point where they differ
+fn blocks_diff(rom: &str, instructions: u64) -> Result<(), Box<dyn Error>> {
+    let mut interpreter = Core::new(load_bus(Some(rom))?);
+    let mut blocks = Core::new(load_bus(Some(rom))?);
+
+    if let Some(divergence) = find_divergence(&mut interpreter, &mut blocks, instructions) {
+        println!("First difference after {} instructions:", divergence.instructions);
+        println!("- {}", divergence.interpreter);
+        println!("+ {}", divergence.blocks);
+
+        return Err("Block execution differs from the interpreter".into());
+    }
+
+    println!(
+        "All {} instructions match, using {} cached blocks.",
+        blocks.instruction_count(), blocks.cached_blocks(),
+    );
+
+    Ok(())
+}
+
+/// `F` as `ZNHC`, with `-` for cleared flags
+fn flags(f: u16) -> String {
+    "ZNHC".chars()
+        .enumerate()
+        .map(|(index, flag)| if f & (0x80 >> index) != 0 { flag } else { '-' })
+        .collect()
+}
+
+/// Instructions shown before and after pc by `d`
+const DISASSEMBLY_CONTEXT: usize = 5;
+const DEFAULT_DUMP_SIZE: usize = 64;
+
+// Checkpoint every 10k instructions, which keeps the last ~10M instructions reachable
+const CHECKPOINT_INTERVAL: u64 = 10_000;
+const MAX_CHECKPOINTS: usize = 1_000;
+/// Rewind snapshots are taken once a second, for the last half minute
+const REWIND_INTERVAL: usize = 60;
+const REWIND_CAPACITY: usize = 30;
+
+const HISTORY_FILE: &str = ".good_boi_history";
+/// Guards against scripts that source themselves
+const MAX_SOURCE_DEPTH: usize = 16;
+
+const COMMANDS: &[&str] = &[
+    "b", "t", "bl", "bd", "bt", "p", "pp", "r", "rp", "w", "ww", "n", "so", "fin", "set", "x",
+    "d", "sym", "watch", "rwatch", "awatch", "unwatch", "sb", "rc", "rw", "source", "trace", "illegal", "quit",
+];
+const ILLEGAL_OPCODE_POLICIES: &[&str] = &["error", "lockup"];
+const REGISTERS: &[&str] = &[
+    "a", "f", "b", "c", "d", "e", "h", "l", "af", "bc", "de", "hl", "sp", "pc", "zf", "nf", "hf", "cf",
+];
+
 struct Debugger {
     core: Core,
-    breakpoints: HashSet<u16>,
+    breakpoints: Breakpoints,
+    timeline: Timeline,
+    rewind: Rewind,
+    /// Frame count of the bus when `rewind` last saw it
+    frame: u64,
+    source_depth: usize,
+    quit: bool,
 }
 
 impl Debugger {
     fn new(bus: Bus) -> Self {
         Self {
             core: Core::new(bus),
-            breakpoints: HashSet::new(),
+            breakpoints: Breakpoints::default(),
+            timeline: Timeline::new(CHECKPOINT_INTERVAL, MAX_CHECKPOINTS),
+            rewind: Rewind::new(REWIND_INTERVAL, REWIND_CAPACITY),
+            frame: 0,
+            source_depth: 0,
+            quit: false,
         }
     }
 
@@ -39,39 +330,297 @@ impl Debugger {
         //     // core.print_state();
         // }
 
-        loop {
-            let mut input = String::new();
-
-            print!("> ");
-            std::io::stdout().flush().unwrap();
-            std::io::stdin().read_line(&mut input).unwrap();
-            let input = input.split_whitespace().collect::<Vec<&str>>();
-
-            let result = match &*input {
-                ["b", addr] => self.add_breakpoint(addr),
-                ["p", addr] => self.print_mem_u8(addr),
-                ["pp", addr] => self.print_mem_u16(addr),
-                ["r"] => self.run_forever(),
-                ["r", addr] => self.run_until(addr),
-                ["rp"] => self.run_past(&format!("{:x}", self.core.pc())),
-                ["rp", addr] => self.run_past(addr),
-                ["w", addr, value] => self.write_mem_u8(addr, value),
-                ["ww", addr, value] => self.write_mem_u16(addr, value),
-                [] | ["n"] => self.single_step(),
-                _ => Err("Unknown command".into()),
+        let mut editor = match Editor::<ReplHelper, FileHistory>::new() {
+            Ok(editor) => editor,
+            Err(err) => return println!("❌ {}", err),
+        };
+        let history = history_path();
+
+        editor.set_helper(Some(ReplHelper::new()));
+        // The history file doesn't exist on the first run
+        editor.load_history(&history).ok();
+
+        while !self.quit {
+            let line = match editor.readline("> ") {
+                Ok(line) => line,
+                Err(ReadlineError::Interrupted) => continue,
+                Err(ReadlineError::Eof) => break,
+                Err(err) => {
+                    println!("❌ {}", err);
+                    break;
+                },
             };
 
-            if let Err(err) = result {
+            if !line.trim().is_empty() {
+                editor.add_history_entry(line.as_str()).ok();
+            }
+
+            if let Err(err) = self.execute(&line) {
                 println!("❌ {}", err);
             }
+
+            // Make traces readable while the session continues
+            if let Some(tracer) = self.core.tracer_mut() {
+                tracer.flush();
+            }
+
+            // Loading symbols has to update completion
+            if let Some(helper) = editor.helper_mut() {
+                if matches!(line.split_whitespace().next(), Some("sym") | Some("source")) {
+                    helper.symbols = self.core.symbols().clone();
+                }
+            }
+        }
+
+        if let Err(err) = editor.save_history(&history) {
+            println!("❌ Failed to save history: {}", err);
         }
     }
 
+    fn execute(&mut self, line: &str) -> Result<(), Box<dyn Error>> {
+        let input = line.split_whitespace().collect::<Vec<&str>>();
+
+        match &*input {
+            ["b", addr, args @ ..] => self.add_breakpoint(addr, args),
+            ["t", addr, args @ ..] => self.add_tracepoint(addr, args),
+            ["bl"] => self.list_breakpoints(),
+            ["bd", index] => self.delete_breakpoint(index),
+            ["bt"] => self.print_call_stack(),
+            ["p", addr] => self.print_mem_u8(addr),
+            ["pp", addr] => self.print_mem_u16(addr),
+            ["r"] => self.run_forever(),
+            ["r", addr] => self.run_until(addr),
+            ["rp"] => self.run_past(&format!("{:x}", self.core.pc())),
+            ["rp", addr] => self.run_past(addr),
+            ["w", addr, value] => self.write_mem_u8(addr, value),
+            ["ww", addr, value] => self.write_mem_u16(addr, value),
+            [] | ["n"] => self.single_step(),
+            ["so"] => self.step_over(),
+            ["fin"] => self.step_out(),
+            ["set", register, value] => self.set_register(register, value),
+            ["x", addr] => self.dump_mem(addr, DEFAULT_DUMP_SIZE),
+            [x, addr] if x.starts_with("x/") => match x[2..].parse() {
+                Ok(len) => self.dump_mem(addr, len),
+                Err(_) => Err("Expected x/<length> <addr>".into()),
+            },
+            ["d"] => self.disassemble_around_pc(),
+            ["d", addr] => self.disassemble(addr),
+            ["sym", path] => self.load_symbols(path),
+            ["watch"] => self.list_watchpoints(),
+            ["watch", range, value @ ..] => self.add_watchpoint(WatchKind::Write, range, value),
+            ["rwatch", range, value @ ..] => self.add_watchpoint(WatchKind::Read, range, value),
+            ["awatch", range, value @ ..] => self.add_watchpoint(WatchKind::Access, range, value),
+            ["unwatch", index] => self.remove_watchpoint(index),
+            ["sb"] => self.step_back(),
+            ["rc"] => self.reverse_continue(),
+            ["rw"] => self.rewind(),
+            ["source", path] => self.source(path),
+            ["trace"] => self.print_tracer(),
+            ["trace", "off"] => {
+                self.core.set_tracer(None);
+                Ok(())
+            },
+            ["trace", "history", len] => self.set_trace_history(len),
+            ["trace", "to", path, filters @ ..] => self.start_trace(path, filters),
+            ["trace", ..] => Err("Expected trace [to <file|-> [start..end] [bank <bank>] | off | history <len>]".into()),
+            ["illegal"] => {
+                println!("Illegal opcodes: {:?}", self.core.illegal_opcode_policy());
+                Ok(())
+            },
+            ["illegal", policy] => self.set_illegal_opcode_policy(policy),
+            ["quit"] | ["q"] => {
+                self.quit = true;
+                Ok(())
+            },
+            _ => Err("Unknown command".into()),
+        }
+    }
+
+    /// Runs debugger commands from a file, skipping empty lines and `#` comments.
+    /// Stops at the first failing command.
+    fn source(&mut self, path: &str) -> Result<(), Box<dyn Error>> {
+        if self.source_depth >= MAX_SOURCE_DEPTH {
+            return Err("Scripts are nested too deeply".into());
+        }
+
+        let script = fs::read_to_string(path)?;
+
+        self.source_depth += 1;
+
+        let result = script.lines()
+            .enumerate()
+            .map(|(number, line)| (number, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+            .try_for_each(|(number, line)| {
+                if self.quit {
+                    return Ok(());
+                }
+
+                println!("> {}", line);
+                self.execute(line).map_err(|err| format!("{}:{}: {}", path, number + 1, err).into())
+            });
+
+        self.source_depth -= 1;
+
+        result
+    }
+
+    /// Parses `bank:addr`, a hex address, a symbol or `symbol+offset`.
+    /// Plain addresses refer to the currently mapped bank.
+    fn parse_location(&self, text: &str) -> Result<BankAddr, Box<dyn Error>> {
+        let (name, offset) = match text.split_once('+') {
+            Some((name, offset)) => (name, u16::from_str_radix(offset, 16)?),
+            None => (text, 0),
+        };
+
+        if let Some(location) = self.core.symbols().lookup(name) {
+            return Ok(BankAddr {
+                bank: location.bank,
+                addr: location.addr.wrapping_add(offset),
+            });
+        }
+
+        if text.contains(':') {
+            return parse_bank_addr(text).ok_or_else(|| format!("Invalid bank:addr: {}", text).into());
+        }
+
+        let addr = u16::from_str_radix(text, 16)
+            .map_err(|_| format!("Unknown address or symbol: {}", text))?;
+
+        Ok(self.core.bank_addr(addr))
+    }
+
+    /// Like `parse_location`, but the location has to be mapped right now
+    fn parse_addr(&self, text: &str) -> Result<u16, Box<dyn Error>> {
+        let location = self.parse_location(text)?;
+        let mapped = self.core.bank_addr(location.addr);
+
+        if mapped != location {
+            return Err(format!(
+                "{:02X}:{:04X} is not mapped (bank {:02X} is)",
+                location.bank, location.addr, mapped.bank,
+            ).into());
+        }
+
+        Ok(location.addr)
+    }
+
+    fn load_symbols(&mut self, path: &str) -> Result<(), Box<dyn Error>> {
+        let symbols = Symbols::load(path).map_err(|err| err.to_string())?;
+
+        println!("Loaded {} symbols.", symbols.len());
+        self.core.set_symbols(symbols);
+
+        Ok(())
+    }
+
+    /// Executes one instruction.
+    /// Returns `true` if it failed, triggered a watchpoint or arrived at a breakpoint.
+    fn step(&mut self) -> bool {
+        let pc = self.core.pc();
+
+        if let Err(err) = self.timeline.step(&mut self.core) {
+            println!("❌ {}", err);
+            return true;
+        }
+
+        if self.core.bus().frame_count() != self.frame {
+            self.frame = self.core.bus().frame_count();
+            self.rewind.end_frame(&self.core);
+        }
+
+        if self.core.is_locked_up() {
+            println!("CPU is locked up at {}.", self.core.format_addr(self.core.pc()));
+            return true;
+        }
+
+        if let Some(hit) = self.core.bus_mut().take_watch_hit() {
+            println!("Watchpoint ({}): {} at {}.", hit.watchpoint, hit, self.core.format_addr(pc));
+            return true;
+        }
+
+        self.check_breakpoints()
+    }
+
+    /// Prints tracepoints and counts hits at the current pc.
+    /// Returns `true` if a breakpoint wants to stop here.
+    fn check_breakpoints(&mut self) -> bool {
+        if self.breakpoints.is_empty() {
+            return false;
+        }
+
+        let addr = self.core.format_addr(self.core.pc());
+        let mut stop = false;
+
+        for hit in self.breakpoints.check(&self.core) {
+            stop |= hit.stops();
+
+            match hit {
+                Hit::Break(breakpoint) => println!("Stopping at breakpoint {} (hits: {}).", addr, breakpoint.hits),
+                Hit::Trace(breakpoint, value) => {
+                    let trace = breakpoint.trace.as_ref().unwrap();
+
+                    match value {
+                        Ok(value) => println!("{}: {} = {} (${:X})", addr, trace, value, value),
+                        Err(err) => println!("{}: {} = ❌ {}", addr, trace, err),
+                    }
+                },
+                Hit::Error(breakpoint, err) => println!("❌ Condition `{}`: {}", breakpoint.condition.as_ref().unwrap(), err),
+            }
+        }
+
+        stop
+    }
+
+    fn step_back(&mut self) -> Result<(), Box<dyn Error>> {
+        if !self.timeline.step_back(&mut self.core).map_err(|err| err.to_string())? {
+            println!("Can't go back any further.");
+        }
+
+        self.core.print_state();
+
+        Ok(())
+    }
+
+    /// Goes back to the newest rewind snapshot, one call per snapshot
+    fn rewind(&mut self) -> Result<(), Box<dyn Error>> {
+        if !self.rewind.step_back(&mut self.core).map_err(|err| err.to_string())? {
+            println!("Nothing to rewind to.");
+            return Ok(());
+        }
+
+        // The checkpoints are all from after the snapshot
+        self.timeline.clear();
+        self.frame = self.core.bus().frame_count();
+        println!("Rewound, {} snapshots left.", self.rewind.len());
+        self.core.print_state();
+
+        Ok(())
+    }
+
+    fn reverse_continue(&mut self) -> Result<(), Box<dyn Error>> {
+        let breakpoints = &self.breakpoints;
+        let found = self.timeline.reverse_until(&mut self.core, |core| breakpoints.would_stop(core))
+            .map_err(|err| err.to_string())?;
+
+        if found {
+            println!("Stopping at breakpoint {}.", self.core.format_addr(self.core.pc()));
+        } else {
+            println!("Reached the earliest recorded instruction.");
+        }
+
+        self.core.print_state();
+
+        Ok(())
+    }
+
     fn run_past(&mut self, addr: &str) -> Result<(), Box<dyn Error>> {
-        let addr = u16::from_str_radix(addr, 16)?;
+        let addr = self.parse_addr(addr)?;
 
         while self.core.pc() <= addr {
-            self.core.step();
+            if self.step() {
+                break;
+            }
         }
 
         self.core.print_state();
@@ -80,38 +629,268 @@ impl Debugger {
     }
 
     fn single_step(&mut self) -> Result<(), Box<dyn Error>> {
-        self.core.step();
+        self.step();
         self.core.print_state();
         Ok(())
     }
 
+    /// Runs until a `call` or `rst` at pc has returned
+    fn step_over(&mut self) -> Result<(), Box<dyn Error>> {
+        match RunMode::step_over(&self.core) {
+            Some(run_mode) => self.run_until_done(run_mode),
+            None => self.single_step(),
+        }
+    }
+
+    /// Runs until the innermost tracked call has returned
+    fn step_out(&mut self) -> Result<(), Box<dyn Error>> {
+        let run_mode = RunMode::step_out(&self.core).map_err(|err| err.to_string())?;
+
+        self.run_until_done(run_mode)
+    }
+
+    fn run_until_done(&mut self, run_mode: RunMode) -> Result<(), Box<dyn Error>> {
+        while !self.step() {
+            if run_mode.is_done(&self.core) {
+                break;
+            }
+        }
+
+        self.core.print_state();
+
+        Ok(())
+    }
+
+    fn print_call_stack(&mut self) -> Result<(), Box<dyn Error>> {
+        println!("#0  {}", self.core.format_addr(self.core.pc()));
+
+        for (index, frame) in self.core.call_stack().frames().iter().rev().enumerate() {
+            println!(
+                "#{}  {}  called {}",
+                index + 1, self.core.format_location(frame.call_site), self.core.format_location(frame.function),
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Registers and flags take hex values, e.g. `set hl c000` or `set zf 1`
+    fn set_register(&mut self, register: &str, value: &str) -> Result<(), Box<dyn Error>> {
+        let value = u16::from_str_radix(value, 16)?;
+
+        if register.len() == 1 && value > 0xFF {
+            return Err(format!("{:X} doesn't fit into {}", value, register).into());
+        }
+
+        let core = &mut self.core;
+        let high = |pair: u16| value << 8 | (pair & 0x00FF);
+        let low = |pair: u16| (pair & 0xFF00) | value;
+
+        match register.to_ascii_lowercase().as_str() {
+            "a" => core.set_reg_af(high(core.reg_af())),
+            "f" => core.set_reg_af(low(core.reg_af()) & 0xFFF0),
+            "b" => core.set_reg_bc(high(core.reg_bc())),
+            "c" => core.set_reg_bc(low(core.reg_bc())),
+            "d" => core.set_reg_de(high(core.reg_de())),
+            "e" => core.set_reg_de(low(core.reg_de())),
+            "h" => core.set_reg_hl(high(core.reg_hl())),
+            "l" => core.set_reg_hl(low(core.reg_hl())),
+            "af" => core.set_reg_af(value & 0xFFF0),
+            "bc" => core.set_reg_bc(value),
+            "de" => core.set_reg_de(value),
+            "hl" => core.set_reg_hl(value),
+            "sp" => core.set_sp(value),
+            "pc" => core.set_pc(value),
+            "zf" => core.set_flag_z(value != 0),
+            "nf" => core.set_flag_n(value != 0),
+            "hf" => core.set_flag_h(value != 0),
+            "cf" => core.set_flag_c(value != 0),
+            _ => return Err(format!("Unknown register: {}", register).into()),
+        }
+
+        self.timeline.truncate();
+        self.core.print_state();
+
+        Ok(())
+    }
+
+    fn print_tracer(&self) -> Result<(), Box<dyn Error>> {
+        let tracer = match self.core.tracer() {
+            Some(tracer) => tracer,
+            None => {
+                println!("Not tracing.");
+                return Ok(());
+            },
+        };
+
+        let output = if tracer.has_output() { "on" } else { "off" };
+        let pc_range = match tracer.pc_range() {
+            Some(range) => format!("{:04X}..{:04X}", range.start(), range.end()),
+            None => "any".into(),
+        };
+        let bank = match tracer.bank() {
+            Some(bank) => format!("{:02X}", bank),
+            None => "any".into(),
+        };
+
+        println!("Output: {}, pc: {}, bank: {}, history: {} lines", output, pc_range, bank, tracer.history_len());
+
+        Ok(())
+    }
+
+    /// Writes a Gameboy Doctor trace to `path` (`-` for stdout), optionally only for a pc range and bank
+    fn start_trace(&mut self, path: &str, filters: &[&str]) -> Result<(), Box<dyn Error>> {
+        let (range, bank) = match filters {
+            [] => (None, None),
+            ["bank", bank] => (None, Some(bank)),
+            [range] => (Some(range), None),
+            [range, "bank", bank] => (Some(range), Some(bank)),
+            _ => return Err("Expected trace to <file|-> [start..end] [bank <bank>]".into()),
+        };
+        let range = match range {
+            Some(range) => {
+                let (start, end) = self.parse_range(range)?;
+                Some(start..=end)
+            },
+            None => None,
+        };
+        let bank = match bank {
+            Some(bank) => Some(usize::from_str_radix(bank, 16)?),
+            None => None,
+        };
+        let output: Box<dyn Write + Send> = match path {
+            "-" => Box::new(io::stdout()),
+            path => Box::new(BufWriter::new(File::create(path)?)),
+        };
+
+        let tracer = self.tracer();
+        tracer.set_output(Some(output));
+        tracer.set_pc_range(range);
+        tracer.set_bank(bank);
+
+        Ok(())
+    }
+
+    /// Keeps the last `len` traced instructions, which are shown if an instruction fails
+    fn set_trace_history(&mut self, len: &str) -> Result<(), Box<dyn Error>> {
+        let len = len.parse()?;
+        self.tracer().set_history_len(len);
+        Ok(())
+    }
+
+    /// The current tracer, or a new one that doesn't log anything yet
+    fn tracer(&mut self) -> &mut Tracer {
+        if self.core.tracer().is_none() {
+            self.core.set_tracer(Some(Tracer::new()));
+        }
+
+        self.core.tracer_mut().unwrap()
+    }
+
+    /// `error` stops at illegal opcodes, `lockup` emulates the hardware
+    fn set_illegal_opcode_policy(&mut self, policy: &str) -> Result<(), Box<dyn Error>> {
+        let policy = match policy {
+            "error" => IllegalOpcodePolicy::Error,
+            "lockup" => IllegalOpcodePolicy::LockUp,
+            _ => return Err("Expected `error` or `lockup`".into()),
+        };
+
+        self.core.set_illegal_opcode_policy(policy);
+
+        Ok(())
+    }
+
     fn run_forever(&mut self) -> Result<(), Box<dyn Error>> {
         loop {
-            self.core.step();
+            if self.step() {
+                self.core.print_state();
+                break Ok(());
+            }
             // self.core.print_state();
+        }
+    }
 
-            if self.breakpoints.contains(&self.core.pc()) {
-                println!("Stopping at breakpoint.");
-                break Ok(());
+    fn list_breakpoints(&mut self) -> Result<(), Box<dyn Error>> {
+        for (index, breakpoint) in self.breakpoints.iter().enumerate() {
+            let location = self.format_breakpoint_location(breakpoint);
+            let mut line = format!("#{}: ", index);
+
+            match &breakpoint.trace {
+                Some(trace) => line += &format!("trace {} at {}", trace, location),
+                None => line += &format!("break at {}", location),
             }
+
+            match breakpoint.hit_condition {
+                Some(HitCondition::Greater(count)) => line += &format!(" after {}", count),
+                Some(hit_condition) => line += &format!(" when hits {}", hit_condition),
+                None => {},
+            }
+
+            if let Some(condition) = &breakpoint.condition {
+                line += &format!(" if {}", condition);
+            }
+
+            println!("{} (hits: {})", line, breakpoint.hits);
+        }
+
+        Ok(())
+    }
+
+    fn delete_breakpoint(&mut self, index: &str) -> Result<(), Box<dyn Error>> {
+        let index = index.parse::<usize>()?;
+
+        let breakpoint = self.breakpoints.remove(index).ok_or_else(|| format!("No breakpoint #{}", index))?;
+        println!("Deleted breakpoint at {}", self.format_breakpoint_location(&breakpoint));
+
+        Ok(())
+    }
+
+    fn format_breakpoint_location(&self, breakpoint: &Breakpoint) -> String {
+        match breakpoint.location() {
+            Some(location) => self.core.format_location(location),
+            None => self.core.format_addr(breakpoint.addr),
         }
     }
 
-    fn add_breakpoint(&mut self, addr: &str) -> Result<(), Box<dyn Error>> {
-        let addr = u16::from_str_radix(addr, 16)?;
+    /// `b <addr> [after <count>] [if <condition>]`
+    fn add_breakpoint(&mut self, addr: &str, args: &[&str]) -> Result<(), Box<dyn Error>> {
+        let mut breakpoint = Breakpoint::new(self.parse_location(addr)?);
+        let (args, condition) = split_condition(args);
 
-        self.breakpoints.insert(addr);
+        breakpoint.condition = condition.map(parse_expr).transpose()?;
+        breakpoint.hit_condition = match args {
+            [] => None,
+            ["after", count] => Some(HitCondition::Greater(count.parse()?)),
+            _ => return Err("Expected `after <count>` or `if <condition>`".into()),
+        };
+
+        self.breakpoints.add(breakpoint);
+
+        Ok(())
+    }
+
+    /// `t <addr> <expression> [if <condition>]`
+    fn add_tracepoint(&mut self, addr: &str, args: &[&str]) -> Result<(), Box<dyn Error>> {
+        let mut breakpoint = Breakpoint::new(self.parse_location(addr)?);
+        let (args, condition) = split_condition(args);
+
+        if args.is_empty() {
+            return Err("Expected an expression to trace".into());
+        }
+
+        breakpoint.condition = condition.map(parse_expr).transpose()?;
+        breakpoint.trace = Some(parse_expr(args)?);
+
+        self.breakpoints.add(breakpoint);
 
         Ok(())
     }
 
     fn run_until(&mut self, addr: &str) -> Result<(), Box<dyn Error>> {
-        let addr = u16::from_str_radix(addr, 16)?;
+        let addr = self.parse_addr(addr)?;
 
         loop {
-            self.core.step();
-
-            if addr == self.core.pc() {
+            if self.step() || addr == self.core.pc() {
                 break;
             }
         }
@@ -120,47 +899,245 @@ impl Debugger {
         Ok(())
     }
 
+    /// `start..end` (inclusive) or a single address
+    fn parse_range(&self, range: &str) -> Result<(u16, u16), Box<dyn Error>> {
+        let (start, end) = match range.split_once("..") {
+            Some((start, end)) => (self.parse_location(start)?.addr, self.parse_location(end)?.addr),
+            None => {
+                let addr = self.parse_location(range)?.addr;
+                (addr, addr)
+            },
+        };
+
+        if end < start {
+            return Err("The end of the range comes before its start".into());
+        }
+
+        Ok((start, end))
+    }
+
+    /// `range` is either a single address or `start..end` (inclusive),
+    /// `value` optionally restricts the watchpoint to a specific value.
+    fn add_watchpoint(&mut self, kind: WatchKind, range: &str, value: &[&str]) -> Result<(), Box<dyn Error>> {
+        let (start, end) = self.parse_range(range)?;
+        let value = match value {
+            [] => None,
+            [value] => Some(u8::from_str_radix(value, 16)?),
+            _ => return Err("Expected at most one value".into()),
+        };
+
+        let watchpoint = Watchpoint { kind, start, end, value };
+
+        println!("Watching {}", watchpoint);
+        self.core.bus_mut().add_watchpoint(watchpoint);
+
+        Ok(())
+    }
+
+    fn remove_watchpoint(&mut self, index: &str) -> Result<(), Box<dyn Error>> {
+        let index = index.parse::<usize>()?;
+
+        match self.core.bus_mut().remove_watchpoint(index) {
+            Some(watchpoint) => println!("Removed {}", watchpoint),
+            None => return Err(format!("No watchpoint #{}", index).into()),
+        }
+
+        Ok(())
+    }
+
+    fn list_watchpoints(&mut self) -> Result<(), Box<dyn Error>> {
+        for (index, watchpoint) in self.core.bus().watchpoints().iter().enumerate() {
+            println!("#{}: {}", index, watchpoint);
+        }
+
+        Ok(())
+    }
+
+    fn disassemble(&mut self, addr: &str) -> Result<(), Box<dyn Error>> {
+        let addr = self.parse_addr(addr)?;
+        let instructions = disasm_range(&self.core, addr, 2 * DISASSEMBLY_CONTEXT);
+
+        self.print_disassembly(&instructions);
+
+        Ok(())
+    }
+
+    fn disassemble_around_pc(&mut self) -> Result<(), Box<dyn Error>> {
+        let pc = self.core.pc();
+        let mut instructions = disassemble_before(pc, DISASSEMBLY_CONTEXT, |addr| self.core.peek_mem_u8(addr));
+
+        instructions.extend(disasm_range(&self.core, pc, DISASSEMBLY_CONTEXT + 1));
+        self.print_disassembly(&instructions);
+
+        Ok(())
+    }
+
+    fn print_disassembly(&self, instructions: &[Disassembly]) {
+        for disassembly in instructions {
+            let addr = disassembly.addr;
+
+            if let Some(label) = self.core.symbols().name(self.core.bank_addr(addr)) {
+                println!("{}:", label);
+            }
+
+            let bytes = disassembly.bytes.iter()
+                .map(|byte| format!("{:02X}", byte))
+                .collect::<Vec<String>>()
+                .join(" ");
+            let marker = if addr == self.core.pc() { "→" } else { " " };
+
+            println!("{} {:04X}  {:<8}  {}", marker, addr, bytes, self.core.render_instruction(disassembly));
+        }
+    }
+
+    /// Hexdump with 16 bytes per line
+    fn dump_mem(&mut self, addr: &str, len: usize) -> Result<(), Box<dyn Error>> {
+        let addr = self.parse_addr(addr)?;
+
+        for line_start in (0..len).step_by(16) {
+            let line_addr = addr.wrapping_add(line_start as u16);
+            let bytes = (0..(len - line_start).min(16))
+                .map(|offset| self.core.peek_mem_u8(line_addr.wrapping_add(offset as u16)))
+                .collect::<Vec<u8>>();
+            let hex = bytes.iter()
+                .map(|byte| format!("{:02X}", byte))
+                .collect::<Vec<String>>()
+                .join(" ");
+            let ascii = bytes.iter()
+                .map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' })
+                .collect::<String>();
+
+            println!("{:04X}  {:<47}  |{}|", line_addr, hex, ascii);
+        }
+
+        Ok(())
+    }
+
     fn print_mem_u8(&mut self, addr: &str) -> Result<(), Box<dyn Error>> {
-        let addr = u16::from_str_radix(addr, 16)?;
+        let addr = self.parse_addr(addr)?;
         let value = self.core.peek_mem_u8(addr);
 
-        println!("[{:04X}] = {:02X}", addr, value);
+        println!("[{}] = {:02X}", self.core.format_addr(addr), value);
 
         Ok(())
     }
 
     fn print_mem_u16(&mut self, addr: &str) -> Result<(), Box<dyn Error>> {
-        let addr = u16::from_str_radix(addr, 16)?;
+        let addr = self.parse_addr(addr)?;
         let value = self.core.peek_mem_u16(addr);
 
-        println!("[{:04X}] = {:04X}", addr, value);
+        println!("[{}] = {:04X}", self.core.format_addr(addr), value);
 
         Ok(())
     }
 
     fn write_mem_u8(&mut self, addr: &str, value: &str) -> Result<(), Box<dyn Error>> {
-        let addr = u16::from_str_radix(addr, 16)?;
+        let addr = self.parse_addr(addr)?;
         let value = u8::from_str_radix(value, 16)?;
 
         self.core.write_mem_u8(addr, value);
+        self.timeline.truncate();
 
-        dbg!(addr);
-
-        println!("[{:04X}] = {:02X}", addr, value);
+        println!("[{}] = {:02X}", self.core.format_addr(addr), value);
 
         Ok(())
     }
 
     fn write_mem_u16(&mut self, addr: &str, value: &str) -> Result<(), Box<dyn Error>> {
-        let addr = u16::from_str_radix(addr, 16)?;
+        let addr = self.parse_addr(addr)?;
         let value = u16::from_str_radix(value, 16)?;
 
         self.core.write_mem_u16(addr, value);
+        self.timeline.truncate();
 
-        dbg!(addr);
-
-        println!("[{:04X}] = {:02X}", addr, value);
+        println!("[{}] = {:04X}", self.core.format_addr(addr), value);
 
         Ok(())
     }
 }
+
+/// Splits `args` at `if`, everything after it is the condition
+fn split_condition<'a, 'b>(args: &'a [&'b str]) -> (&'a [&'b str], Option<&'a [&'b str]>) {
+    match args.iter().position(|&arg| arg == "if") {
+        Some(index) => (&args[..index], Some(&args[index + 1..])),
+        None => (args, None),
+    }
+}
+
+fn parse_expr(args: &[&str]) -> Result<Expr, Box<dyn Error>> {
+    Ok(Expr::parse(&args.join(" ")).map_err(|err| err.to_string())?)
+}
+
+fn disasm_range(core: &Core, addr: u16, count: usize) -> Vec<Disassembly> {
+    good_boi::disassemble_range(addr, count, |addr| core.peek_mem_u8(addr))
+}
+
+/// History is kept in the home directory, or the working directory if there is none
+fn history_path() -> PathBuf {
+    std::env::var_os("HOME")
+        .map(PathBuf::from)
+        .unwrap_or_default()
+        .join(HISTORY_FILE)
+}
+
+/// Completes commands, register names, symbols and file names
+struct ReplHelper {
+    symbols: Symbols,
+    filenames: FilenameCompleter,
+}
+
+impl ReplHelper {
+    fn new() -> Self {
+        Self {
+            symbols: Symbols::default(),
+            filenames: FilenameCompleter::new(),
+        }
+    }
+}
+
+impl Completer for ReplHelper {
+    type Candidate = Pair;
+
+    fn complete(&self, line: &str, pos: usize, ctx: &Context) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let start = line[..pos].rfind(char::is_whitespace).map_or(0, |index| index + 1);
+        let word = &line[start..pos];
+        let command = line.split_whitespace().next();
+        let pair = |name: &str| Pair {
+            display: name.to_owned(),
+            replacement: name.to_owned(),
+        };
+
+        let mut candidates = match command {
+            _ if start == 0 => COMMANDS.iter()
+                .filter(|name| name.starts_with(word))
+                .map(|name| pair(name))
+                .collect::<Vec<Pair>>(),
+            Some("sym") | Some("source") | Some("trace") => return self.filenames.complete(line, pos, ctx),
+            Some("set") => REGISTERS.iter()
+                .filter(|name| name.starts_with(word))
+                .map(|name| pair(name))
+                .collect(),
+            Some("illegal") => ILLEGAL_OPCODE_POLICIES.iter()
+                .filter(|name| name.starts_with(word))
+                .map(|name| pair(name))
+                .collect(),
+            _ => self.symbols.names_with_prefix(word)
+                .map(pair)
+                .collect(),
+        };
+
+        candidates.sort_by(|a, b| a.display.cmp(&b.display));
+
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for ReplHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ReplHelper {}
+
+impl Validator for ReplHelper {}
+
+impl Helper for ReplHelper {}
@@ -0,0 +1,214 @@
+use std::collections::VecDeque;
+use std::iter;
+use failure::{bail, format_err, Fallible};
+use crate::core::Core;
+use crate::callstack::CallStack;
+
+/// Records save states at a fixed frame interval so that emulation can be rewound.
+///
+/// Only the newest snapshot is kept in full. Every older snapshot is stored as the
+/// XOR against its successor, run-length encoded. Successive states mostly differ
+/// in a few bytes, so this keeps the memory usage low.
+///
+/// The call stack isn't part of save states, so it is kept next to each snapshot.
+pub struct Rewind {
+    interval: usize,
+    capacity: usize,
+    frames: usize,
+    newest: Option<(Vec<u8>, CallStack)>,
+    /// `deltas[i]` turns snapshot `i + 1` back into snapshot `i`
+    deltas: VecDeque<(Vec<u8>, CallStack)>,
+}
+
+impl Rewind {
+    /// Takes a snapshot every `interval` frames and keeps at most `capacity` snapshots.
+    /// An interval of 1 allows stepping back frame by frame.
+    pub fn new(interval: usize, capacity: usize) -> Self {
+        Self {
+            interval: interval.max(1),
+            capacity: capacity.max(1),
+            frames: 0,
+            newest: None,
+            deltas: VecDeque::new(),
+        }
+    }
+
+    /// Must be called once per emulated frame
+    pub fn end_frame(&mut self, core: &Core) {
+        self.frames += 1;
+
+        if self.frames >= self.interval {
+            self.record(core);
+        }
+    }
+
+    /// Takes a snapshot right away
+    pub fn record(&mut self, core: &Core) {
+        let state = core.save_state();
+
+        self.frames = 0;
+
+        if let Some((previous, call_stack)) = self.newest.take() {
+            self.deltas.push_back((encode_delta(&state, &previous), call_stack));
+
+            if self.deltas.len() >= self.capacity {
+                self.deltas.pop_front();
+            }
+        }
+
+        self.newest = Some((state, core.call_stack().clone()));
+    }
+
+    /// Restores the newest snapshot and removes it from the buffer,
+    /// so that the next call goes back further.
+    /// Returns `false` if there is nothing left to rewind to.
+    pub fn step_back(&mut self, core: &mut Core) -> Fallible<bool> {
+        let (state, call_stack) = match self.newest.take() {
+            Some(newest) => newest,
+            None => return Ok(false),
+        };
+
+        core.load_state(&state)?;
+        core.set_call_stack(call_stack);
+
+        self.newest = match self.deltas.pop_back() {
+            Some((delta, call_stack)) => Some((decode_delta(&state, &delta)?, call_stack)),
+            None => None,
+        };
+        self.frames = 0;
+
+        Ok(true)
+    }
+
+    /// Number of snapshots available
+    pub fn len(&self) -> usize {
+        match self.newest {
+            Some(_) => self.deltas.len() + 1,
+            None => 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.newest.is_none()
+    }
+
+    /// Bytes used by the stored snapshots
+    pub fn memory_usage(&self) -> usize {
+        let newest = self.newest.as_ref().map_or(0, |(state, _)| state.len());
+        let deltas = self.deltas.iter().map(|(delta, _)| delta.len()).sum::<usize>();
+
+        newest + deltas
+    }
+
+    pub fn clear(&mut self) {
+        self.newest = None;
+        self.deltas.clear();
+        self.frames = 0;
+    }
+}
+
+/// Encodes `target` relative to `base`.
+///
+/// Layout: target length, followed by pairs of
+/// (zero run length, literal length, literal XOR bytes), all lengths as LEB128.
+/// Bytes past the end of `base` are always literals, which bounds the decoded length.
+pub fn encode_delta(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let xor = target.iter().enumerate()
+        .map(|(i, byte)| byte ^ base.get(i).copied().unwrap_or(0))
+        .collect::<Vec<u8>>();
+
+    let mut delta = Vec::new();
+    let mut i = 0;
+
+    write_varint(&mut delta, target.len());
+
+    while i < xor.len() {
+        let zeros = xor[i..base.len().clamp(i, xor.len())].iter().take_while(|&&byte| byte == 0).count();
+        i += zeros;
+
+        let literals = xor[i..].iter().enumerate()
+            .take_while(|&(offset, &byte)| byte != 0 || i + offset >= base.len())
+            .count();
+
+        write_varint(&mut delta, zeros);
+        write_varint(&mut delta, literals);
+        delta.extend_from_slice(&xor[i..i + literals]);
+
+        i += literals;
+    }
+
+    delta
+}
+
+/// Applies a delta from `encode_delta` to the same `base`.
+/// Fails if the delta is malformed, rather than trusting its lengths.
+pub fn decode_delta(base: &[u8], delta: &[u8]) -> Fallible<Vec<u8>> {
+    let mut pos = 0;
+    let len = read_varint(delta, &mut pos)?;
+
+    if len > base.len().saturating_add(delta.len()) {
+        bail!("Delta of {} bytes can't decode to {} bytes", delta.len(), len);
+    }
+
+    let mut target = base.iter().copied().chain(iter::repeat(0)).take(len).collect::<Vec<u8>>();
+    let mut i = 0usize;
+
+    while pos < delta.len() {
+        i = i.saturating_add(read_varint(delta, &mut pos)?);
+        let literals = read_varint(delta, &mut pos)?;
+
+        let bytes = i.checked_add(literals).and_then(|end| target.get_mut(i..end));
+        let xor = pos.checked_add(literals).and_then(|end| delta.get(pos..end));
+
+        match (bytes, xor) {
+            (Some(bytes), Some(xor)) => bytes.iter_mut().zip(xor).for_each(|(byte, xor)| *byte ^= xor),
+            _ => bail!("Delta reaches past the end at offset {}", pos),
+        }
+
+        pos += literals;
+        i += literals;
+    }
+
+    if i != len {
+        bail!("Delta covers {} of {} bytes", i, len);
+    }
+
+    Ok(target)
+}
+
+fn write_varint(buffer: &mut Vec<u8>, mut value: usize) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+
+        if value == 0 {
+            buffer.push(byte);
+            break;
+        }
+
+        buffer.push(byte | 0x80);
+    }
+}
+
+fn read_varint(buffer: &[u8], pos: &mut usize) -> Fallible<usize> {
+    let mut value = 0usize;
+    let mut shift = 0;
+
+    loop {
+        let byte = *buffer.get(*pos).ok_or_else(|| format_err!("Delta ends within a length"))?;
+        *pos += 1;
+
+        let bits = (byte & 0x7F) as usize;
+
+        if shift >= usize::BITS || (bits << shift) >> shift != bits {
+            bail!("Length in delta is too large");
+        }
+
+        value |= bits << shift;
+        shift += 7;
+
+        if byte & 0x80 == 0 {
+            break Ok(value);
+        }
+    }
+}
@@ -0,0 +1,520 @@
+use failure::Fallible;
+use crate::constants::*;
+use crate::state::{SaveState, StateWriter, StateReader, StateError};
+
+const PACKET_SIZE: usize = 16;
+const PACKET_BITS: usize = PACKET_SIZE * 8;
+const ATTR_WIDTH: usize = SCREEN_WIDTH / 8;
+const ATTR_HEIGHT: usize = SCREEN_HEIGHT / 8;
+const BORDER_TILES: usize = 256;
+const BORDER_MAP_SIZE: usize = 32 * 32 * 2;
+const SYSTEM_PALETTES: usize = 512;
+/// Size of the VRAM transfer done by the *_TRN commands
+pub const SGB_TRANSFER_SIZE: usize = 0x1000;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Transfer {
+    /// PAL_TRN: 512 system palettes
+    Palettes,
+    /// CHR_TRN: border tiles 0x00-0x7F or 0x80-0xFF
+    BorderTiles { upper: bool },
+    /// PCT_TRN: border tile map and border palettes
+    Border,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Mask {
+    Cancel,
+    Freeze,
+    Black,
+    Color0,
+}
+
+/// Receives the 128 bit packets that are pulsed through P14/P15.
+struct PacketReceiver {
+    data: [u8; PACKET_SIZE],
+    bits: Option<usize>,
+    released: bool,
+}
+
+impl PacketReceiver {
+    fn new() -> Self {
+        Self {
+            data: [0; PACKET_SIZE],
+            bits: None,
+            released: false,
+        }
+    }
+
+    fn write(&mut self, value: u8) -> Option<[u8; PACKET_SIZE]> {
+        let bit = match value & 0x30 {
+            // Reset pulse, starts a new packet
+            0x00 => {
+                self.data = [0; PACKET_SIZE];
+                self.bits = Some(0);
+                self.released = false;
+                return None;
+            },
+            0x30 => {
+                self.released = true;
+                return None;
+            },
+            0x10 => 1,
+            _ => 0,
+        };
+
+        let bits = self.bits?;
+
+        // Each bit has to be followed by releasing both lines
+        if !self.released {
+            return None;
+        }
+
+        self.released = false;
+
+        // The 129th bit is the stop bit
+        if bits == PACKET_BITS {
+            self.bits = None;
+            return Some(self.data);
+        }
+
+        self.data[bits / 8] |= bit << (bits % 8);
+        self.bits = Some(bits + 1);
+
+        None
+    }
+}
+
+impl SaveState for PacketReceiver {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_raw(&self.data);
+        w.write_bool(self.bits.is_some());
+        w.write_usize(self.bits.unwrap_or(0));
+        w.write_bool(self.released);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Fallible<()> {
+        self.data.copy_from_slice(r.read_raw(PACKET_SIZE)?);
+        let receiving = r.read_bool()?;
+        let bits = r.read_usize()?.min(PACKET_BITS);
+        self.bits = if receiving { Some(bits) } else { None };
+        self.released = r.read_bool()?;
+        Ok(())
+    }
+}
+
+pub struct Sgb {
+    receiver: PacketReceiver,
+    command: Vec<u8>,
+    palettes: [[u16; 4]; 4],
+    system_palettes: Vec<u16>,
+    attributes: [u8; ATTR_WIDTH * ATTR_HEIGHT],
+    mask: Mask,
+    frozen_screen: Vec<u8>,
+    border_tiles: Vec<u8>,
+    border_map: Vec<u8>,
+    border_palettes: [[u16; 16]; 4],
+    player_count: u8,
+    pending_transfer: Option<Transfer>,
+}
+
+impl Sgb {
+    pub fn new() -> Self {
+        Self {
+            receiver: PacketReceiver::new(),
+            command: Vec::new(),
+            palettes: [[0x7FFF, 0x56B5, 0x294A, 0x0000]; 4],
+            system_palettes: vec![0; SYSTEM_PALETTES * 4],
+            attributes: [0; ATTR_WIDTH * ATTR_HEIGHT],
+            mask: Mask::Cancel,
+            frozen_screen: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            border_tiles: vec![0; BORDER_TILES * 32],
+            border_map: vec![0; BORDER_MAP_SIZE],
+            border_palettes: [[0; 16]; 4],
+            player_count: 1,
+            pending_transfer: None,
+        }
+    }
+
+    pub fn player_count(&self) -> u8 {
+        self.player_count
+    }
+
+    /// Feeds a write to the joypad register (0xFF00)
+    pub fn write_joypad(&mut self, value: u8) {
+        if let Some(packet) = self.receiver.write(value) {
+            self.receive_packet(packet);
+        }
+    }
+
+    /// Returns a VRAM transfer requested by the last command.
+    /// The caller is expected to answer with `complete_transfer`.
+    pub fn take_pending_transfer(&mut self) -> Option<Transfer> {
+        self.pending_transfer.take()
+    }
+
+    pub fn complete_transfer(&mut self, transfer: Transfer, data: &[u8]) {
+        match transfer {
+            Transfer::Palettes => {
+                for (color, bytes) in self.system_palettes.iter_mut().zip(data.chunks(2)) {
+                    *color = u16::from_le_bytes([bytes[0], bytes[1]]);
+                }
+            },
+            Transfer::BorderTiles { upper } => {
+                let start = if upper { SGB_TRANSFER_SIZE } else { 0 };
+                self.border_tiles[start..start + SGB_TRANSFER_SIZE].copy_from_slice(&data[..SGB_TRANSFER_SIZE]);
+            },
+            Transfer::Border => {
+                self.border_map.copy_from_slice(&data[..BORDER_MAP_SIZE]);
+
+                let colors = &data[BORDER_MAP_SIZE..BORDER_MAP_SIZE + 4 * 16 * 2];
+
+                for (index, bytes) in colors.chunks(2).enumerate() {
+                    self.border_palettes[index / 16][index % 16] = u16::from_le_bytes([bytes[0], bytes[1]]);
+                }
+            },
+        }
+    }
+
+    fn receive_packet(&mut self, packet: [u8; PACKET_SIZE]) {
+        if self.command.is_empty() && packet[0] & 0x07 == 0 {
+            // Packets with length 0 are not valid commands
+            return;
+        }
+
+        self.command.extend_from_slice(&packet);
+
+        let length = (self.command[0] & 0x07) as usize;
+
+        if self.command.len() < length * PACKET_SIZE {
+            return;
+        }
+
+        let command = std::mem::take(&mut self.command);
+        self.execute(&command);
+    }
+
+    fn execute(&mut self, command: &[u8]) {
+        let data = &command[1..];
+
+        match command[0] >> 3 {
+            0x00 => self.execute_pal(0, 1, data),
+            0x01 => self.execute_pal(2, 3, data),
+            0x02 => self.execute_pal(0, 3, data),
+            0x03 => self.execute_pal(1, 2, data),
+            0x04 => self.execute_attr_blk(data),
+            0x05 => self.execute_attr_lin(data),
+            0x06 => self.execute_attr_div(data),
+            0x07 => self.execute_attr_chr(data),
+            0x0A => self.execute_pal_set(data),
+            0x0B => self.pending_transfer = Some(Transfer::Palettes),
+            0x11 => {
+                self.player_count = match data[0] & 0x03 {
+                    1 => 2,
+                    3 => 4,
+                    _ => 1,
+                };
+            },
+            0x13 => self.pending_transfer = Some(Transfer::BorderTiles { upper: data[0] & 1 != 0 }),
+            0x14 => self.pending_transfer = Some(Transfer::Border),
+            0x17 => {
+                self.mask = match data[0] & 0x03 {
+                    0 => Mask::Cancel,
+                    1 => Mask::Freeze,
+                    2 => Mask::Black,
+                    _ => Mask::Color0,
+                };
+            },
+            code => eprintln!("Unimplemented SGB command 0x{:02X}", code),
+        }
+    }
+
+    fn execute_pal(&mut self, first: usize, second: usize, data: &[u8]) {
+        let color = |index: usize| u16::from_le_bytes([data[index * 2], data[index * 2 + 1]]);
+
+        // Color 0 is shared by all palettes
+        for palette in &mut self.palettes {
+            palette[0] = color(0);
+        }
+
+        for i in 1..4 {
+            self.palettes[first][i] = color(i);
+            self.palettes[second][i] = color(i + 3);
+        }
+    }
+
+    fn execute_pal_set(&mut self, data: &[u8]) {
+        for palette in 0..4 {
+            let index = u16::from_le_bytes([data[palette * 2], data[palette * 2 + 1]]) as usize & 0x1FF;
+            let colors = &self.system_palettes[index * 4..index * 4 + 4];
+
+            self.palettes[palette].copy_from_slice(colors);
+        }
+
+        // Bit 6 cancels the screen mask
+        if data[8] & 0x40 != 0 {
+            self.mask = Mask::Cancel;
+        }
+    }
+
+    fn execute_attr_blk(&mut self, data: &[u8]) {
+        let count = (data[0] & 0x1F) as usize;
+
+        for set in data[1..].chunks_exact(6).take(count) {
+            let mut control = set[0] & 0x07;
+            let inside = set[1] & 0x03;
+            let mut border = (set[1] >> 2) & 0x03;
+            let outside = (set[1] >> 4) & 0x03;
+            let (x1, y1, x2, y2) = (set[2] as usize, set[3] as usize, set[4] as usize, set[5] as usize);
+
+            // If only the inside or the outside is set,
+            // the border takes the same palette.
+            match control {
+                0b001 => { control |= 0b010; border = inside },
+                0b100 => { control |= 0b010; border = outside },
+                _ => {},
+            }
+
+            for y in 0..ATTR_HEIGHT {
+                for x in 0..ATTR_WIDTH {
+                    let within = x >= x1 && x <= x2 && y >= y1 && y <= y2;
+                    let on_border = within && (x == x1 || x == x2 || y == y1 || y == y2);
+
+                    let palette = if on_border {
+                        if control & 0b010 == 0 { continue }
+                        border
+                    } else if within {
+                        if control & 0b001 == 0 { continue }
+                        inside
+                    } else {
+                        if control & 0b100 == 0 { continue }
+                        outside
+                    };
+
+                    self.attributes[y * ATTR_WIDTH + x] = palette;
+                }
+            }
+        }
+    }
+
+    fn execute_attr_lin(&mut self, data: &[u8]) {
+        let count = data[0] as usize;
+
+        for &line in data[1..].iter().take(count) {
+            let index = (line & 0x1F) as usize;
+            let palette = (line >> 5) & 0x03;
+            let horizontal = line & 0x80 != 0;
+
+            if horizontal && index < ATTR_HEIGHT {
+                for x in 0..ATTR_WIDTH {
+                    self.attributes[index * ATTR_WIDTH + x] = palette;
+                }
+            } else if !horizontal && index < ATTR_WIDTH {
+                for y in 0..ATTR_HEIGHT {
+                    self.attributes[y * ATTR_WIDTH + index] = palette;
+                }
+            }
+        }
+    }
+
+    fn execute_attr_div(&mut self, data: &[u8]) {
+        let after = data[0] & 0x03;
+        let before = (data[0] >> 2) & 0x03;
+        let on_line = (data[0] >> 4) & 0x03;
+        let horizontal = data[0] & 0x40 != 0;
+        let line = data[1] as usize;
+
+        for y in 0..ATTR_HEIGHT {
+            for x in 0..ATTR_WIDTH {
+                let position = if horizontal { y } else { x };
+
+                self.attributes[y * ATTR_WIDTH + x] = match position {
+                    p if p < line => before,
+                    p if p == line => on_line,
+                    _ => after,
+                };
+            }
+        }
+    }
+
+    fn execute_attr_chr(&mut self, data: &[u8]) {
+        let mut x = data[0] as usize;
+        let mut y = data[1] as usize;
+        let count = u16::from_le_bytes([data[2], data[3]]) as usize;
+        let vertical = data[4] & 1 != 0;
+        let attributes = &data[5..];
+
+        for i in 0..count.min(ATTR_WIDTH * ATTR_HEIGHT) {
+            let Some(byte) = attributes.get(i / 4) else { break };
+            let palette = (byte >> (6 - (i % 4) * 2)) & 0x03;
+
+            if x >= ATTR_WIDTH || y >= ATTR_HEIGHT {
+                break;
+            }
+
+            self.attributes[y * ATTR_WIDTH + x] = palette;
+
+            if vertical {
+                y += 1;
+                if y == ATTR_HEIGHT { y = 0; x += 1 }
+            } else {
+                x += 1;
+                if x == ATTR_WIDTH { x = 0; y += 1 }
+            }
+        }
+    }
+
+    /// Renders the Game Boy screen (one shade 0-3 per pixel, 160x144)
+    /// into a 256x224 frame including the border. Pixels are 0x00RRGGBB.
+    /// Pixels missing from a short `screen` are shade 0.
+    pub fn render(&mut self, screen: &[u8]) -> Vec<u32> {
+        let mut frame = vec![rgb(self.palettes[0][0]); SGB_SCREEN_WIDTH * SGB_SCREEN_HEIGHT];
+
+        if self.mask != Mask::Freeze {
+            let len = screen.len().min(self.frozen_screen.len());
+
+            self.frozen_screen[..len].copy_from_slice(&screen[..len]);
+            self.frozen_screen[len..].iter_mut().for_each(|shade| *shade = 0);
+        }
+
+        let left = (SGB_SCREEN_WIDTH - SCREEN_WIDTH) / 2;
+        let top = (SGB_SCREEN_HEIGHT - SCREEN_HEIGHT) / 2;
+
+        for y in 0..SCREEN_HEIGHT {
+            for x in 0..SCREEN_WIDTH {
+                let shade = self.frozen_screen[y * SCREEN_WIDTH + x] as usize & 0x03;
+                let palette = self.attributes[(y / 8) * ATTR_WIDTH + x / 8] as usize;
+
+                let color = match self.mask {
+                    Mask::Cancel | Mask::Freeze => rgb(self.palettes[palette][shade]),
+                    Mask::Black => 0,
+                    Mask::Color0 => rgb(self.palettes[0][0]),
+                };
+
+                frame[(top + y) * SGB_SCREEN_WIDTH + left + x] = color;
+            }
+        }
+
+        self.render_border(&mut frame);
+
+        frame
+    }
+
+    fn render_border(&self, frame: &mut [u32]) {
+        for tile_y in 0..SGB_SCREEN_HEIGHT / 8 {
+            for tile_x in 0..SGB_SCREEN_WIDTH / 8 {
+                let index = (tile_y * 32 + tile_x) * 2;
+                let entry = u16::from_le_bytes([self.border_map[index], self.border_map[index + 1]]);
+                let tile = &self.border_tiles[(entry & 0xFF) as usize * 32..][..32];
+                let palette = &self.border_palettes[((entry >> 10) & 0x03) as usize];
+                let flip_x = entry & 0x4000 != 0;
+                let flip_y = entry & 0x8000 != 0;
+
+                for row in 0..8 {
+                    let y = if flip_y { 7 - row } else { row };
+
+                    for column in 0..8 {
+                        let bit = if flip_x { column } else { 7 - column };
+                        let color = snes_tile_pixel(tile, y, bit);
+
+                        // Color 0 is transparent
+                        if color == 0 {
+                            continue;
+                        }
+
+                        let pixel = (tile_y * 8 + row) * SGB_SCREEN_WIDTH + tile_x * 8 + column;
+                        frame[pixel] = rgb(palette[color]);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl SaveState for Sgb {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.receiver.save_state(w);
+        w.write_bytes(&self.command);
+
+        for &color in self.palettes.iter().flatten() {
+            w.write_u16(color);
+        }
+
+        for &color in &self.system_palettes {
+            w.write_u16(color);
+        }
+
+        w.write_raw(&self.attributes);
+        w.write_u8(self.mask as u8);
+        w.write_bytes(&self.frozen_screen);
+        w.write_bytes(&self.border_tiles);
+        w.write_bytes(&self.border_map);
+
+        for &color in self.border_palettes.iter().flatten() {
+            w.write_u16(color);
+        }
+
+        w.write_u8(self.player_count);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Fallible<()> {
+        self.receiver.load_state(r)?;
+        self.command = r.read_bytes()?.to_vec();
+
+        for color in self.palettes.iter_mut().flatten() {
+            *color = r.read_u16()?;
+        }
+
+        for color in &mut self.system_palettes {
+            *color = r.read_u16()?;
+        }
+
+        self.attributes.copy_from_slice(r.read_raw(ATTR_WIDTH * ATTR_HEIGHT)?);
+
+        for attribute in &mut self.attributes {
+            *attribute &= 0x03;
+        }
+
+        self.mask = match r.read_u8()? {
+            0 => Mask::Cancel,
+            1 => Mask::Freeze,
+            2 => Mask::Black,
+            3 => Mask::Color0,
+            mask => return Err(StateError::Corrupted(format!("invalid SGB mask {}", mask)).into()),
+        };
+
+        r.read_bytes_into(&mut self.frozen_screen)?;
+        r.read_bytes_into(&mut self.border_tiles)?;
+        r.read_bytes_into(&mut self.border_map)?;
+
+        for color in self.border_palettes.iter_mut().flatten() {
+            *color = r.read_u16()?;
+        }
+
+        self.player_count = r.read_u8()?;
+        self.pending_transfer = None;
+
+        Ok(())
+    }
+}
+
+/// Reads a pixel from a 4bpp SNES tile.
+/// Bitplanes 0 and 1 are interleaved in the first 16 bytes, planes 2 and 3 in the last.
+fn snes_tile_pixel(tile: &[u8], row: usize, bit: usize) -> usize {
+    let plane = |offset: usize| ((tile[offset] >> bit) & 1) as usize;
+
+    plane(row * 2)
+        | plane(row * 2 + 1) << 1
+        | plane(16 + row * 2) << 2
+        | plane(16 + row * 2 + 1) << 3
+}
+
+/// Converts a BGR555 color to 0x00RRGGBB
+fn rgb(color: u16) -> u32 {
+    let expand = |c: u16| {
+        let c = (c & 0x1F) as u32;
+        c << 3 | c >> 2
+    };
+
+    expand(color) << 16 | expand(color >> 5) << 8 | expand(color >> 10)
+}
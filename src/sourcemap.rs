@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use failure::Fallible;
+use crate::symbols::{Symbols, BankAddr};
+
+const SOURCE_EXTENSIONS: &[&str] = &["asm", "inc", "s", "z80", "sm83"];
+
+/// Maps between addresses and lines of assembly sources.
+///
+/// Neither `.sym` nor `.map` files carry line information,
+/// so labels are located in the sources and joined with the symbols by name.
+/// Addresses between two labels map to the line of the preceding label.
+#[derive(Debug, Default, Clone)]
+pub struct SourceMap {
+    /// Line (1-based) where each label is defined
+    labels: HashMap<String, (PathBuf, usize)>,
+    /// Labels of each file, ordered by line
+    files: HashMap<PathBuf, Vec<(usize, String)>>,
+}
+
+impl SourceMap {
+    /// Adds a source file, or all source files below a directory
+    pub fn add_path(&mut self, path: impl AsRef<Path>) -> Fallible<()> {
+        let path = path.as_ref();
+
+        if path.is_dir() {
+            for entry in fs::read_dir(path)? {
+                let path = entry?.path();
+                let is_source = path.extension()
+                    .and_then(|extension| extension.to_str())
+                    .is_some_and(|extension| SOURCE_EXTENSIONS.contains(&extension));
+
+                if path.is_dir() || is_source {
+                    self.add_path(&path)?;
+                }
+            }
+
+            return Ok(());
+        }
+
+        let text = fs::read_to_string(path)?;
+        self.add_source(path, &text);
+
+        Ok(())
+    }
+
+    pub fn add_source(&mut self, path: &Path, text: &str) {
+        let path = normalize(path);
+        let mut scope = String::new();
+        let mut labels = Vec::new();
+
+        for (index, line) in text.lines().enumerate() {
+            let name = match label_definition(line) {
+                Some(name) => name,
+                None => continue,
+            };
+
+            // Local labels belong to the last global label
+            let name = if name.starts_with('.') {
+                format!("{}{}", scope, name)
+            } else {
+                scope = name.split('.').next().unwrap_or(name).to_owned();
+                name.to_owned()
+            };
+
+            self.labels.insert(name.clone(), (path.clone(), index + 1));
+            labels.push((index + 1, name));
+        }
+
+        self.files.insert(path, labels);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// Source line of the closest label at or before `location`
+    pub fn line(&self, symbols: &Symbols, location: BankAddr) -> Option<(&Path, usize)> {
+        let (name, _offset) = symbols.nearest(location)?;
+        let (path, line) = self.labels.get(name)?;
+
+        Some((path, *line))
+    }
+
+    /// Address of the first label at or after `line` that has a symbol.
+    /// Also returns the line of that label.
+    pub fn location(&self, symbols: &Symbols, path: &Path, line: usize) -> Option<(BankAddr, usize)> {
+        self.files.get(&normalize(path))?
+            .iter()
+            .filter(|(label_line, _)| *label_line >= line)
+            .find_map(|(label_line, name)| Some((symbols.lookup(name)?, *label_line)))
+    }
+}
+
+/// Returns the label defined on this line, if any.
+/// Labels end with `:`, only local labels (`.name`) at the start of a line may omit it.
+fn label_definition(line: &str) -> Option<&str> {
+    let line = line.split(';').next().unwrap_or("");
+    let indented = line.starts_with(char::is_whitespace);
+    let line = line.trim_start();
+    let end = line.find(|c: char| !(c.is_ascii_alphanumeric() || "_.@#$".contains(c)))
+        .unwrap_or(line.len());
+    let (name, rest) = line.split_at(end);
+
+    if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+
+    if rest.starts_with(':') || (!indented && name.starts_with('.') && rest.trim().is_empty()) {
+        Some(name)
+    } else {
+        None
+    }
+}
+
+/// Editors may refer to the same file by different paths
+fn normalize(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_owned())
+}
@@ -0,0 +1,217 @@
+use std::fmt;
+use failure::Fallible;
+
+const MAGIC: &[u8; 8] = b"GOODBOI\0";
+
+/// Bump whenever the layout of any `SaveState` impl changes.
+pub const STATE_VERSION: u16 = 6;
+
+#[derive(Debug)]
+pub enum StateError {
+    InvalidMagic,
+    UnsupportedVersion { found: u16, expected: u16 },
+    RomMismatch {
+        state_title: String,
+        state_checksum: u16,
+        rom_title: String,
+        rom_checksum: u16,
+    },
+    UnexpectedEnd,
+    Corrupted(String),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::InvalidMagic => write!(f, "Not a save state"),
+            StateError::UnsupportedVersion { found, expected } => {
+                write!(f, "Unsupported save state version {} (expected {})", found, expected)
+            },
+            StateError::RomMismatch { state_title, state_checksum, rom_title, rom_checksum } => write!(
+                f,
+                "Save state belongs to {:?} (checksum {:04X}), but {:?} (checksum {:04X}) is loaded",
+                state_title, state_checksum, rom_title, rom_checksum,
+            ),
+            StateError::UnexpectedEnd => write!(f, "Save state is truncated"),
+            StateError::Corrupted(reason) => write!(f, "Save state is corrupted: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+/// Identifies the ROM a save state was made with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateHeader {
+    pub title: String,
+    pub global_checksum: u16,
+}
+
+impl StateHeader {
+    pub fn write(&self, w: &mut StateWriter) {
+        w.write_raw(MAGIC);
+        w.write_u16(STATE_VERSION);
+        w.write_bytes(self.title.as_bytes());
+        w.write_u16(self.global_checksum);
+    }
+
+    /// Reads the header and checks that it matches `self`
+    pub fn check(&self, r: &mut StateReader) -> Fallible<()> {
+        if r.read_raw(MAGIC.len())? != MAGIC {
+            return Err(StateError::InvalidMagic.into());
+        }
+
+        let version = r.read_u16()?;
+
+        if version != STATE_VERSION {
+            return Err(StateError::UnsupportedVersion {
+                found: version,
+                expected: STATE_VERSION,
+            }.into());
+        }
+
+        let title = String::from_utf8_lossy(r.read_bytes()?).into_owned();
+        let global_checksum = r.read_u16()?;
+
+        if title != self.title || global_checksum != self.global_checksum {
+            return Err(StateError::RomMismatch {
+                state_title: title,
+                state_checksum: global_checksum,
+                rom_title: self.title.clone(),
+                rom_checksum: self.global_checksum,
+            }.into());
+        }
+
+        Ok(())
+    }
+}
+
+pub trait SaveState {
+    fn save_state(&self, w: &mut StateWriter);
+    fn load_state(&mut self, r: &mut StateReader) -> Fallible<()>;
+}
+
+#[derive(Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn write_raw(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_u8(value as u8);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.write_raw(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.write_raw(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.write_raw(&value.to_le_bytes());
+    }
+
+    pub fn write_usize(&mut self, value: usize) {
+        self.write_u32(value as u32);
+    }
+
+    /// Writes a length prefixed byte slice
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.write_usize(bytes.len());
+        self.write_raw(bytes);
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn read_raw(&mut self, len: usize) -> Fallible<&'a [u8]> {
+        if self.data.len() < len {
+            return Err(StateError::UnexpectedEnd.into());
+        }
+
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+
+        Ok(bytes)
+    }
+
+    pub fn read_u8(&mut self) -> Fallible<u8> {
+        Ok(self.read_raw(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Fallible<bool> {
+        match self.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            value => Err(StateError::Corrupted(format!("invalid bool {}", value)).into()),
+        }
+    }
+
+    pub fn read_u16(&mut self) -> Fallible<u16> {
+        let bytes = self.read_raw(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn read_u32(&mut self) -> Fallible<u32> {
+        let bytes = self.read_raw(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn read_u64(&mut self) -> Fallible<u64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.read_raw(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub fn read_usize(&mut self) -> Fallible<usize> {
+        Ok(self.read_u32()? as usize)
+    }
+
+    /// Reads a length prefixed byte slice
+    pub fn read_bytes(&mut self) -> Fallible<&'a [u8]> {
+        let len = self.read_usize()?;
+        self.read_raw(len)
+    }
+
+    /// Reads a length prefixed byte slice into a buffer of the same length
+    pub fn read_bytes_into(&mut self, buffer: &mut [u8]) -> Fallible<()> {
+        let bytes = self.read_bytes()?;
+
+        if bytes.len() != buffer.len() {
+            return Err(StateError::Corrupted(format!("expected {} bytes, found {}", buffer.len(), bytes.len())).into());
+        }
+
+        buffer.copy_from_slice(bytes);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,170 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+use failure::{bail, format_err, Fallible};
+
+/// An address in a specific bank
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BankAddr {
+    pub bank: usize,
+    pub addr: u16,
+}
+
+/// Symbols as emitted by RGBDS (`bank:addr label`, one per line)
+#[derive(Debug, Default, Clone)]
+pub struct Symbols {
+    by_name: HashMap<String, BankAddr>,
+    by_addr: BTreeMap<BankAddr, String>,
+}
+
+impl Symbols {
+    /// Loads a `.sym` file, or an RGBDS `.map` file if it has that extension
+    pub fn load(path: impl AsRef<Path>) -> Fallible<Self> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)?;
+
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("map") => Self::parse_map(&text),
+            _ => Self::parse(&text),
+        }
+    }
+
+    pub fn parse(text: &str) -> Fallible<Self> {
+        let mut symbols = Self::default();
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split(';').next().unwrap_or("").trim();
+
+            if line.is_empty() {
+                continue;
+            }
+
+            let (location, name) = match line.split_once(char::is_whitespace) {
+                Some((location, name)) => (location, name.trim()),
+                None => bail!("Invalid symbol on line {}: {:?}", number + 1, line),
+            };
+
+            let location = parse_bank_addr(location)
+                .ok_or_else(|| format_err!("Invalid address on line {}: {:?}", number + 1, location))?;
+
+            symbols.insert(location, name);
+        }
+
+        Ok(symbols)
+    }
+
+    /// Parses an RGBDS map file, which lists `$addr = name` lines
+    /// below `<region> bank #<bank>:` headers
+    pub fn parse_map(text: &str) -> Fallible<Self> {
+        let mut symbols = Self::default();
+        let mut bank = 0;
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+
+            if let Some((_, header_bank)) = line.strip_suffix(':').and_then(|header| header.split_once(" bank #")) {
+                bank = header_bank.parse()
+                    .map_err(|_| format_err!("Invalid bank on line {}: {:?}", number + 1, line))?;
+                continue;
+            }
+
+            // Everything else describes sections and free space
+            let (addr, name) = match line.split_once(" = ") {
+                Some((addr, name)) if addr.starts_with('$') => (&addr[1..], name.trim()),
+                _ => continue,
+            };
+            let addr = u16::from_str_radix(addr, 16)
+                .map_err(|_| format_err!("Invalid address on line {}: {:?}", number + 1, addr))?;
+
+            symbols.insert(BankAddr { bank, addr }, name);
+        }
+
+        Ok(symbols)
+    }
+
+    pub fn insert(&mut self, location: BankAddr, name: &str) {
+        self.by_name.insert(name.to_owned(), location);
+
+        // Keep the first name for addresses with multiple labels
+        self.by_addr.entry(location).or_insert_with(|| name.to_owned());
+    }
+
+    /// Adds all symbols of `other`, e.g. from a second object's symbol file
+    pub fn extend(&mut self, other: Symbols) {
+        for (location, name) in other.by_addr {
+            self.by_addr.entry(location).or_insert(name);
+        }
+
+        self.by_name.extend(other.by_name);
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_name.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<BankAddr> {
+        self.by_name.get(name).copied()
+    }
+
+    /// Label at exactly this location
+    pub fn name(&self, location: BankAddr) -> Option<&str> {
+        self.by_addr.get(&location).map(String::as_str)
+    }
+
+    /// Closest label at or before the location, within the same bank and memory region
+    pub fn nearest(&self, location: BankAddr) -> Option<(&str, u16)> {
+        let region_start = region_start(location.addr);
+        let start = BankAddr { bank: location.bank, addr: region_start };
+
+        self.by_addr.range(start..=location)
+            .next_back()
+            .map(|(found, name)| (name.as_str(), location.addr - found.addr))
+    }
+
+    /// Formats the location as `label` or `label+offset`, if there is a label for it
+    pub fn format(&self, location: BankAddr) -> Option<String> {
+        self.nearest(location).map(|(name, offset)| match offset {
+            0 => name.to_owned(),
+            offset => format!("{}+{:X}", name, offset),
+        })
+    }
+
+    /// Names starting with `prefix`, for completion
+    pub fn names_with_prefix<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.by_name.keys()
+            .map(String::as_str)
+            .filter(move |name| name.starts_with(prefix))
+    }
+}
+
+/// Parses `bank:addr` or just `addr` (bank 0), both in hex
+pub fn parse_bank_addr(text: &str) -> Option<BankAddr> {
+    let (bank, addr) = match text.split_once(':') {
+        Some((bank, addr)) => (usize::from_str_radix(bank, 16).ok()?, addr),
+        None => (0, text),
+    };
+    let addr = u16::from_str_radix(addr.trim_start_matches('$'), 16).ok()?;
+
+    Some(BankAddr { bank, addr })
+}
+
+/// Labels never apply across memory regions, e.g. from ROM into WRAM
+fn region_start(addr: u16) -> u16 {
+    match addr {
+        0x0000..=0x3FFF => 0x0000,
+        0x4000..=0x7FFF => 0x4000,
+        0x8000..=0x9FFF => 0x8000,
+        0xA000..=0xBFFF => 0xA000,
+        0xC000..=0xCFFF => 0xC000,
+        0xD000..=0xDFFF => 0xD000,
+        0xE000..=0xEFFF => 0xE000,
+        0xF000..=0xFDFF => 0xF000,
+        0xFE00..=0xFEFF => 0xFE00,
+        0xFF00..=0xFF7F => 0xFF00,
+        0xFF80..=0xFFFF => 0xFF80,
+    }
+}
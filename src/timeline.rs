@@ -0,0 +1,229 @@
+use std::collections::VecDeque;
+use failure::{bail, Fallible};
+use crate::core::{Core, StepError};
+use crate::callstack::CallStack;
+use crate::rewind::{encode_delta, decode_delta};
+
+/// Every this many checkpoints one is stored in full,
+/// which bounds the deltas that have to be applied to restore one
+const KEYFRAME_INTERVAL: usize = 32;
+
+/// Counts executed instructions and takes periodic checkpoints,
+/// so that any earlier instruction can be revisited by restoring the closest
+/// checkpoint and deterministically re-executing up to it.
+///
+/// Checkpoints are stored as deltas against the previous one, see `encode_delta`.
+/// The call stack isn't part of save states, so each checkpoint keeps its own.
+/// Re-executed instructions were already traced the first time, so the tracer is suspended for them.
+pub struct Timeline {
+    interval: u64,
+    max_checkpoints: usize,
+    position: u64,
+    /// The position up to which instructions have been executed before
+    furthest: u64,
+    checkpoints: VecDeque<(u64, Checkpoint, CallStack)>,
+    /// The newest checkpoint in full, to encode the next one against
+    newest_state: Option<Vec<u8>>,
+}
+
+enum Checkpoint {
+    Full(Vec<u8>),
+    /// Turns the previous checkpoint into this one
+    Delta(Vec<u8>),
+}
+
+impl Timeline {
+    /// Takes a checkpoint every `interval` instructions, keeping at most `max_checkpoints`.
+    pub fn new(interval: u64, max_checkpoints: usize) -> Self {
+        Self {
+            interval: interval.max(1),
+            max_checkpoints: max_checkpoints.max(1),
+            position: 0,
+            furthest: 0,
+            checkpoints: VecDeque::new(),
+            newest_state: None,
+        }
+    }
+
+    /// Number of instructions executed so far
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Oldest position that can still be reached
+    pub fn earliest_position(&self) -> u64 {
+        self.checkpoints.front().map_or(self.position, |&(position, ..)| position)
+    }
+
+    /// Executes one instruction. The position only advances if it succeeded.
+    pub fn step(&mut self, core: &mut Core) -> Result<(), StepError> {
+        let due = self.position % self.interval == 0;
+
+        if due && self.newest_checkpoint().map_or(true, |newest| newest < self.position) {
+            self.push_checkpoint(core.save_state(), core.call_stack().clone());
+
+            if self.checkpoints.len() > self.max_checkpoints {
+                self.pop_checkpoint();
+            }
+        }
+
+        if self.position < self.furthest {
+            let tracer = core.take_tracer();
+            let result = core.step();
+            core.set_tracer(tracer);
+            result?;
+        } else {
+            core.step()?;
+        }
+
+        self.position += 1;
+        self.furthest = self.furthest.max(self.position);
+
+        Ok(())
+    }
+
+    /// Forgets everything after the current position.
+    /// Must be called whenever the machine state is modified from the outside,
+    /// since re-execution would no longer reproduce the same future.
+    pub fn truncate(&mut self) {
+        let position = self.position;
+        let len = self.checkpoints.len();
+        self.furthest = position;
+        self.checkpoints.retain(|&(checkpoint, ..)| checkpoint <= position);
+
+        // A checkpoint at the current position predates the modification
+        if self.newest_checkpoint() == Some(position) {
+            self.checkpoints.pop_back();
+        }
+
+        if self.checkpoints.len() != len {
+            self.newest_state = None;
+        }
+    }
+
+    /// Forgets all checkpoints, for when a state from elsewhere was loaded.
+    /// The position keeps counting from where it is.
+    pub fn clear(&mut self) {
+        self.furthest = self.position;
+        self.checkpoints.clear();
+        self.newest_state = None;
+    }
+
+    /// Moves to the given instruction count, going backwards or forwards
+    pub fn seek(&mut self, core: &mut Core, position: u64) -> Fallible<()> {
+        if position < self.position {
+            let index = match self.checkpoints.iter().rposition(|&(checkpoint, ..)| checkpoint <= position) {
+                Some(index) => index,
+                None => bail!("Can't go back before instruction {}", self.earliest_position()),
+            };
+
+            core.load_state(&self.checkpoint_state(index))?;
+            core.set_call_stack(self.checkpoints[index].2.clone());
+            self.position = self.checkpoints[index].0;
+        }
+
+        while self.position < position {
+            self.step(core)?;
+        }
+
+        Ok(())
+    }
+
+    /// Goes back by one instruction.
+    /// Returns `false` if already at the earliest reachable position.
+    pub fn step_back(&mut self, core: &mut Core) -> Fallible<bool> {
+        if self.position == 0 || self.position <= self.earliest_position() {
+            return Ok(false);
+        }
+
+        self.seek(core, self.position - 1)?;
+
+        Ok(true)
+    }
+
+    /// Goes back to the most recent earlier position where `predicate` holds.
+    /// If there is none, stops at the earliest reachable position and returns `false`.
+    pub fn reverse_until(&mut self, core: &mut Core, mut predicate: impl FnMut(&Core) -> bool) -> Fallible<bool> {
+        let mut segment_end = self.position;
+        let starts = self.checkpoints.iter()
+            .map(|&(position, ..)| position)
+            .filter(|&position| position < segment_end)
+            .collect::<Vec<u64>>();
+
+        for &start in starts.iter().rev() {
+            self.seek(core, start)?;
+
+            let mut found = None;
+
+            for position in start..segment_end {
+                if predicate(core) {
+                    found = Some(position);
+                }
+
+                if position + 1 < segment_end {
+                    self.step(core)?;
+                }
+            }
+
+            if let Some(position) = found {
+                self.seek(core, position)?;
+                return Ok(true);
+            }
+
+            segment_end = start;
+        }
+
+        let earliest = self.earliest_position();
+        self.seek(core, earliest)?;
+
+        Ok(false)
+    }
+
+    fn newest_checkpoint(&self) -> Option<u64> {
+        self.checkpoints.back().map(|&(position, ..)| position)
+    }
+
+    fn push_checkpoint(&mut self, state: Vec<u8>, call_stack: CallStack) {
+        let since_keyframe = self.checkpoints.iter().rev()
+            .position(|(_, checkpoint, _)| matches!(checkpoint, Checkpoint::Full(_)));
+        let previous = match since_keyframe {
+            Some(count) if count + 1 < KEYFRAME_INTERVAL => self.newest_state.take()
+                .or_else(|| Some(self.checkpoint_state(self.checkpoints.len() - 1))),
+            _ => None,
+        };
+
+        let checkpoint = match previous {
+            Some(previous) => Checkpoint::Delta(encode_delta(&previous, &state)),
+            None => Checkpoint::Full(state.clone()),
+        };
+
+        self.checkpoints.push_back((self.position, checkpoint, call_stack));
+        self.newest_state = Some(state);
+    }
+
+    /// Drops the oldest checkpoint. The next one is stored in full instead, so it doesn't need it.
+    fn pop_checkpoint(&mut self) {
+        let oldest = match self.checkpoints.pop_front() {
+            Some((_, Checkpoint::Full(state), _)) => state,
+            _ => return,
+        };
+
+        if let Some((_, next, _)) = self.checkpoints.front_mut() {
+            if let Checkpoint::Delta(delta) = next {
+                *next = Checkpoint::Full(decode_delta(&oldest, delta).expect("checkpoints are encoded by the timeline"));
+            }
+        }
+    }
+
+    /// Decodes the checkpoint at `index`, starting from the keyframe before it
+    fn checkpoint_state(&self, index: usize) -> Vec<u8> {
+        let keyframe = self.checkpoints.range(..=index).rposition(|(_, checkpoint, _)| matches!(checkpoint, Checkpoint::Full(_)))
+            .expect("the oldest checkpoint is always stored in full");
+
+        self.checkpoints.range(keyframe..=index)
+            .fold(Vec::new(), |state, (_, checkpoint, _)| match checkpoint {
+                Checkpoint::Full(full) => full.clone(),
+                Checkpoint::Delta(delta) => decode_delta(&state, delta).expect("checkpoints are encoded by the timeline"),
+            })
+    }
+}
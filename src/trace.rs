@@ -0,0 +1,165 @@
+use std::collections::VecDeque;
+use std::io::Write;
+use std::ops::RangeInclusive;
+use failure::{bail, format_err, Fallible};
+use crate::symbols::BankAddr;
+
+/// Registers of a trace line, in the order of the format
+pub const TRACE_REGISTERS: [&str; 10] = ["A", "F", "B", "C", "D", "E", "H", "L", "SP", "PC"];
+
+/// Parses the registers of a trace line (see `TRACE_REGISTERS`), ignoring `PCMEM`
+pub fn parse_trace_line(line: &str) -> Fallible<[u16; 10]> {
+    let mut registers = [None; 10];
+
+    for field in line.split_whitespace() {
+        let (name, value) = field.split_once(':')
+            .ok_or_else(|| format_err!("Invalid field: {:?}", field))?;
+
+        let index = match TRACE_REGISTERS.iter().position(|&register| register == name) {
+            Some(index) => index,
+            None => continue,
+        };
+
+        let value = u16::from_str_radix(value, 16)
+            .map_err(|_| format_err!("Invalid value of {}: {:?}", name, value))?;
+
+        registers[index] = Some(value);
+    }
+
+    let mut values = [0; 10];
+
+    for (index, register) in registers.iter().enumerate() {
+        match register {
+            Some(value) => values[index] = *value,
+            None => bail!("Missing {} in {:?}", TRACE_REGISTERS[index], line),
+        }
+    }
+
+    Ok(values)
+}
+
+/// Logs one line per executed instruction in the format used by Gameboy Doctor
+/// and many reference emulators, e.g.
+/// `A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02`.
+///
+/// Lines can be streamed to a writer and/or kept in a rolling history,
+/// which is dumped to stderr when an instruction fails.
+/// The pc range and bank filters only apply to the writer,
+/// so the history always shows what led up to a failure.
+pub struct Tracer {
+    output: Option<Box<dyn Write + Send>>,
+    history: VecDeque<String>,
+    history_len: usize,
+    pc_range: Option<RangeInclusive<u16>>,
+    bank: Option<usize>,
+}
+
+impl Tracer {
+    /// A tracer that neither writes nor remembers anything yet
+    pub fn new() -> Self {
+        Self {
+            output: None,
+            history: VecDeque::new(),
+            history_len: 0,
+            pc_range: None,
+            bank: None,
+        }
+    }
+
+    pub fn set_output(&mut self, output: Option<Box<dyn Write + Send>>) {
+        self.output = output;
+    }
+
+    pub fn has_output(&self) -> bool {
+        self.output.is_some()
+    }
+
+    /// Number of lines kept for `history`, 0 disables it
+    pub fn set_history_len(&mut self, len: usize) {
+        self.history_len = len;
+
+        while self.history.len() > len {
+            self.history.pop_front();
+        }
+    }
+
+    pub fn history_len(&self) -> usize {
+        self.history_len
+    }
+
+    /// Most recent lines, oldest first
+    pub fn history(&self) -> impl Iterator<Item = &str> {
+        self.history.iter().map(String::as_str)
+    }
+
+    /// Only write instructions within this range
+    pub fn set_pc_range(&mut self, range: Option<RangeInclusive<u16>>) {
+        self.pc_range = range;
+    }
+
+    pub fn pc_range(&self) -> Option<&RangeInclusive<u16>> {
+        self.pc_range.as_ref()
+    }
+
+    /// Only write instructions in this bank
+    pub fn set_bank(&mut self, bank: Option<usize>) {
+        self.bank = bank;
+    }
+
+    pub fn bank(&self) -> Option<usize> {
+        self.bank
+    }
+
+    /// Whether the instruction at `location` would be written or remembered
+    pub fn wants(&self, location: BankAddr) -> bool {
+        self.history_len > 0 || (self.output.is_some() && self.passes_filters(location))
+    }
+
+    fn passes_filters(&self, location: BankAddr) -> bool {
+        self.pc_range.as_ref().map_or(true, |range| range.contains(&location.addr))
+            && self.bank.map_or(true, |bank| bank == location.bank)
+    }
+
+    pub fn record(&mut self, location: BankAddr, line: String) {
+        let passes_filters = self.passes_filters(location);
+
+        if let Some(output) = self.output.as_mut().filter(|_| passes_filters) {
+            if let Err(err) = writeln!(output, "{}", line) {
+                eprintln!("Stopped tracing: {}", err);
+                self.output = None;
+            }
+        }
+
+        if self.history_len > 0 {
+            if self.history.len() >= self.history_len {
+                self.history.pop_front();
+            }
+
+            self.history.push_back(line);
+        }
+    }
+
+    pub fn dump_history(&self) {
+        if self.history.is_empty() {
+            return;
+        }
+
+        eprintln!("Last {} traced instructions:", self.history.len());
+
+        for line in &self.history {
+            eprintln!("{}", line);
+        }
+    }
+
+    pub fn flush(&mut self) {
+        if let Some(output) = &mut self.output {
+            output.flush().ok();
+        }
+    }
+}
+
+impl Default for Tracer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
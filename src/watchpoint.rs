@@ -0,0 +1,74 @@
+use std::fmt;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access,
+}
+
+impl WatchKind {
+    fn includes(self, access: WatchKind) -> bool {
+        self == WatchKind::Access || self == access
+    }
+}
+
+/// Stops execution when a memory range is accessed,
+/// optionally only if a specific value is read or written.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub kind: WatchKind,
+    pub start: u16,
+    /// Inclusive
+    pub end: u16,
+    pub value: Option<u8>,
+}
+
+impl Watchpoint {
+    pub fn matches(&self, access: WatchKind, addr: u16, value: u8) -> bool {
+        self.kind.includes(access)
+            && (self.start..=self.end).contains(&addr)
+            && self.value.map_or(true, |expected| expected == value)
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            WatchKind::Read => "read",
+            WatchKind::Write => "write",
+            WatchKind::Access => "access",
+        };
+
+        write!(f, "{} {:04X}", kind, self.start)?;
+
+        if self.end != self.start {
+            write!(f, "..{:04X}", self.end)?;
+        }
+
+        if let Some(value) = self.value {
+            write!(f, " == {:02X}", value)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The access that triggered a watchpoint
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct WatchHit {
+    pub watchpoint: Watchpoint,
+    /// Either `Read` or `Write`
+    pub access: WatchKind,
+    pub addr: u16,
+    pub value: u8,
+}
+
+impl fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.access {
+            WatchKind::Write => write!(f, "wrote {:02X} to {:04X}", self.value, self.addr),
+            _ => write!(f, "read {:02X} from {:04X}", self.value, self.addr),
+        }
+    }
+}
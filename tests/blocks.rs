@@ -0,0 +1,115 @@
+//! Runs the block cache against the interpreter with `find_divergence`,
+//! on code that switches banks and modifies itself.
+
+use good_boi::{Core, Bus, Cartridge, StepError, Watchpoint, WatchKind, find_divergence};
+
+const BANK_SIZE: usize = 0x4000;
+
+/// Runs from 0100 in bank 0 and ends with an illegal opcode
+const MAIN: &[u8] = &[
+    0x31, 0x00, 0xD0, // 0100: ld sp, $D000
+    0x16, 0x00,       // 0103: ld d, $00
+    0x3E, 0x01,       // 0105: ld a, $01
+    0xEA, 0x00, 0x20, // 0107: ld [$2000], a
+    0xCD, 0x00, 0x40, // 010A: call $4000
+    // Copy `inc c; ret` to WRAM and run it
+    0x21, 0x00, 0xC0, // 010D: ld hl, $C000
+    0x36, 0x0C,       // 0110: ld [hl], $0C
+    0x23,             // 0112: inc hl
+    0x36, 0xC9,       // 0113: ld [hl], $C9
+    0xCD, 0x00, 0xC0, // 0115: call $C000
+    // Patch it to `inc b`
+    0x3E, 0x04,       // 0118: ld a, $04
+    0xEA, 0x00, 0xC0, // 011A: ld [$C000], a
+    0xCD, 0x00, 0xC0, // 011D: call $C000
+    // Patch it to `inc d` through echo RAM
+    0x3E, 0x14,       // 0120: ld a, $14
+    0xEA, 0x00, 0xE0, // 0122: ld [$E000], a
+    0xCD, 0x00, 0xC0, // 0125: call $C000
+    // Copy code to C010 that replaces its `nop` with `inc e` before reaching it,
+    // `ld a, $1C; ld [$C015], a; nop; ret`, and run it twice
+    0x21, 0x10, 0xC0, // 0128: ld hl, $C010
+    0x36, 0x3E,       // 012B: ld [hl], $3E
+    0x23,             // 012D: inc hl
+    0x36, 0x1C,       // 012E: ld [hl], $1C
+    0x23,             // 0130: inc hl
+    0x36, 0xEA,       // 0131: ld [hl], $EA
+    0x23,             // 0133: inc hl
+    0x36, 0x15,       // 0134: ld [hl], $15
+    0x23,             // 0136: inc hl
+    0x36, 0xC0,       // 0137: ld [hl], $C0
+    0x23,             // 0139: inc hl
+    0x36, 0x00,       // 013A: ld [hl], $00
+    0x23,             // 013C: inc hl
+    0x36, 0xC9,       // 013D: ld [hl], $C9
+    0xCD, 0x10, 0xC0, // 013F: call $C010
+    0xCD, 0x10, 0xC0, // 0142: call $C010
+    0xD3,             // 0145: illegal
+];
+
+/// At 4000 in bank 1. Switches to bank 2, which continues at 4005.
+const BANK_1: &[u8] = &[
+    0x3E, 0x02,       // 4000: ld a, $02
+    0xEA, 0x00, 0x20, // 4002: ld [$2000], a
+    0x06, 0x11,       // 4005: ld b, $11
+    0xC9,             // 4007: ret
+];
+
+const BANK_2: &[u8] = &[
+    0x3E, 0x22,       // 4005: ld a, $22
+    0x47,             // 4007: ld b, a
+    0xC9,             // 4008: ret
+];
+
+fn rom() -> Vec<u8> {
+    let mut rom = vec![0; 4 * BANK_SIZE];
+
+    rom[0x100..0x100 + MAIN.len()].copy_from_slice(MAIN);
+    rom[BANK_SIZE..BANK_SIZE + BANK_1.len()].copy_from_slice(BANK_1);
+    rom[2 * BANK_SIZE + 5..2 * BANK_SIZE + 5 + BANK_2.len()].copy_from_slice(BANK_2);
+
+    rom
+}
+
+fn load() -> Core {
+    Core::new(Bus::new(Cartridge::load(rom()).unwrap()))
+}
+
+#[test]
+fn blocks_match_interpreter() {
+    let mut interpreter = load();
+    let mut blocks = load();
+
+    assert_eq!(find_divergence(&mut interpreter, &mut blocks, 1000), None);
+
+    match blocks.run_block(1) {
+        Err(StepError::IllegalOpcode { location, opcode: 0xD3 }) => assert_eq!(location.addr, 0x145),
+        result => panic!("Expected to stop at the illegal opcode, got {:?}", result),
+    }
+
+    assert!(blocks.cached_blocks() > 0);
+    // Set after the bank switch
+    assert_eq!(blocks.reg_bc() >> 8, 0x23);
+    assert_eq!(blocks.reg_bc() & 0xFF, 0x01);
+    assert_eq!(blocks.reg_de() >> 8, 0x01);
+    // Once per call of the code at C010
+    assert_eq!(blocks.reg_de() & 0xFF, 0x58);
+}
+
+#[test]
+fn opcode_fetches_trigger_read_watchpoints() {
+    let mut blocks = load();
+    blocks.set_block_cache(true);
+    // Only the opcode of `ld a, $01`, not its operand
+    blocks.bus_mut().add_watchpoint(Watchpoint { kind: WatchKind::Read, start: 0x105, end: 0x105, value: None });
+
+    // Like the debugger, which checks for hits after every block
+    let hit = (0..10).find_map(|_| {
+        blocks.run_block(100).unwrap();
+        blocks.bus_mut().take_watch_hit()
+    });
+
+    let hit = hit.expect("Expected the opcode fetch to hit");
+    assert_eq!((hit.access, hit.addr, hit.value), (WatchKind::Read, 0x105, 0x3E));
+    assert_eq!(blocks.pc(), 0x107);
+}
@@ -0,0 +1,173 @@
+//! The breakpoints and stepping shared by the debugger, the gdb stub and the debug adapter.
+
+use std::cell::RefCell;
+use std::fs;
+use std::io::{self, Cursor, Write};
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::mpsc;
+use serde_json::{json, Value};
+use good_boi::{Core, Bus, Cartridge, BankAddr, Expr, Breakpoint, Breakpoints, HitCondition, Hit, RunMode, DapServer, spawn_reader};
+
+/// 0100: `call $0108; jr -5` and at 0108: `inc b; ret`
+fn rom() -> Vec<u8> {
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x105].copy_from_slice(&[0xCD, 0x08, 0x01, 0x18, 0xFB]);
+    rom[0x108..0x10A].copy_from_slice(&[0x04, 0xC9]);
+    rom
+}
+
+fn load() -> Core {
+    Core::new(Bus::new(Cartridge::load(rom()).unwrap()))
+}
+
+/// Steps until pc is at `addr` and checks the breakpoints there
+fn check_at(core: &mut Core, breakpoints: &mut Breakpoints, addr: u16) -> Vec<(u64, bool)> {
+    core.step().unwrap();
+
+    while core.pc() != addr {
+        core.step().unwrap();
+    }
+
+    breakpoints.check(core).iter().map(|hit| (hit.breakpoint().id, hit.stops())).collect()
+}
+
+#[test]
+fn hit_conditions_count_met_conditions() {
+    let mut core = load();
+    let mut breakpoints = Breakpoints::default();
+    let location = BankAddr { bank: 0, addr: 0x108 };
+
+    let mut after = Breakpoint::new(location);
+    after.hit_condition = Some(HitCondition::Greater(1));
+    let after = breakpoints.add(after);
+
+    let mut odd_b = Breakpoint::new(location);
+    odd_b.condition = Some(Expr::parse("b & 1").unwrap());
+    let odd_b = breakpoints.add(odd_b);
+
+    // B is incremented at 0108, so it is 0 on the first visit
+    assert_eq!(check_at(&mut core, &mut breakpoints, 0x108), []);
+    assert_eq!(check_at(&mut core, &mut breakpoints, 0x108), [(after, true), (odd_b, true)]);
+    assert_eq!(check_at(&mut core, &mut breakpoints, 0x108), [(after, true)]);
+    assert_eq!(breakpoints.iter().map(|breakpoint| breakpoint.hits).collect::<Vec<_>>(), [3, 1]);
+}
+
+#[test]
+fn tracepoints_and_broken_conditions() {
+    let mut core = load();
+    let mut breakpoints = Breakpoints::default();
+
+    let mut trace = Breakpoint::at_addr(0x108);
+    trace.trace = Some(Expr::parse("b + 1").unwrap());
+    breakpoints.add(trace);
+
+    // In another bank, so it never matches
+    breakpoints.add(Breakpoint::new(BankAddr { bank: 1, addr: 0x108 }));
+
+    let mut broken = Breakpoint::at_addr(0x108);
+    broken.condition = Some(Expr::parse("1 / (b - b)").unwrap());
+    breakpoints.add(broken);
+
+    core.step().unwrap();
+    let hits = breakpoints.check(&core);
+
+    assert_eq!(hits.len(), 2);
+    assert!(matches!(hits[0], Hit::Trace(_, Ok(1))));
+    assert!(matches!(hits[1], Hit::Error(..)));
+    assert!(hits[1].stops());
+}
+
+#[test]
+fn replace_keeps_other_breakpoints() {
+    let mut breakpoints = Breakpoints::default();
+
+    breakpoints.add(Breakpoint::at_addr(0x100));
+    let mut sourced = Breakpoint::at_addr(0x108);
+    sourced.source = Some("main.asm".into());
+    breakpoints.add(sourced);
+
+    let ids = breakpoints.replace(|breakpoint| breakpoint.source.is_none(), vec![Breakpoint::at_addr(0x103)]);
+
+    assert_eq!(ids, [3]);
+    assert_eq!(breakpoints.iter().map(|breakpoint| breakpoint.addr).collect::<Vec<_>>(), [0x108, 0x103]);
+}
+
+#[test]
+fn step_over_and_out() {
+    let mut core = load();
+
+    let step_over = RunMode::step_over(&core).unwrap();
+    assert_eq!(step_over, RunMode::StepOver { return_addr: 0x103, sp: core.sp() });
+
+    core.step().unwrap();
+    assert!(RunMode::step_over(&core).is_none());
+    assert!(!step_over.is_done(&core));
+
+    let step_out = RunMode::step_out(&core).unwrap();
+    core.step().unwrap();
+    assert!(!step_out.is_done(&core));
+    core.step().unwrap();
+    assert!(step_out.is_done(&core));
+    assert!(step_over.is_done(&core));
+
+    assert!(RunMode::step_out(&core).is_err());
+}
+
+struct Output(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Sends `requests` to a debug adapter and returns everything it sent back
+fn dap_session(requests: &[(&str, Value)]) -> Vec<Value> {
+    let (sender, receiver) = mpsc::channel();
+    let output = Rc::new(RefCell::new(Vec::new()));
+
+    for (seq, (command, arguments)) in requests.iter().enumerate() {
+        sender.send(json!({ "seq": seq + 1, "type": "request", "command": command, "arguments": arguments })).unwrap();
+    }
+
+    drop(sender);
+    DapServer::new(Output(output.clone())).serve(receiver).unwrap();
+
+    spawn_reader(Cursor::new(output.take())).iter().collect()
+}
+
+#[test]
+fn dap_rejects_bad_breakpoints_without_changing_any() {
+    let program = Path::new(env!("CARGO_TARGET_TMPDIR")).join("breakpoints.gb");
+    fs::write(&program, rom()).unwrap();
+
+    let breakpoints = |breakpoints: Value| ("setInstructionBreakpoints", json!({ "breakpoints": breakpoints }));
+    let messages = dap_session(&[
+        ("launch", json!({ "program": program, "stopOnEntry": true })),
+        breakpoints(json!([{ "instructionReference": "0x0108" }])),
+        ("configurationDone", json!({})),
+        breakpoints(json!([{ "instructionReference": "0x0103" }, { "instructionReference": "0x0100", "condition": "b +" }])),
+        ("continue", json!({})),
+    ]);
+
+    let response = |seq: u64| messages.iter()
+        .find(|message| message["type"] == "response" && message["request_seq"] == seq)
+        .unwrap();
+
+    assert_eq!(response(2)["body"]["breakpoints"][0]["id"], 1);
+    assert_eq!(response(4)["success"], false);
+
+    // Still stops at the breakpoint set before the bad request
+    let stopped = messages.iter()
+        .filter(|message| message["event"] == "stopped")
+        .collect::<Vec<_>>();
+
+    assert_eq!(stopped.len(), 2);
+    assert_eq!(stopped[1]["body"]["reason"], "breakpoint");
+    assert_eq!(stopped[1]["body"]["hitBreakpointIds"], json!([1]));
+}
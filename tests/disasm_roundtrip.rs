@@ -0,0 +1,397 @@
+//! Runs good_boi-disasm on a ROM and assembles its output back into the same ROM.
+//!
+//! Uses rgbasm and rgblink when they are installed. Otherwise the output is
+//! re-encoded with an opcode table written out in this file, so the
+//! disassembler isn't checked against its own rendering.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+const BANK_SIZE: usize = 0x4000;
+
+/// Opcodes that jump, return or don't exist, which `rom` places by hand
+const NOT_STRAIGHT_LINE: &[u8] = &[
+    0x10, 0x18, 0x20, 0x28, 0x30, 0x38, 0xC0, 0xC2, 0xC3, 0xC4, 0xC7, 0xC8, 0xC9, 0xCA, 0xCB, 0xCC,
+    0xCD, 0xCF, 0xD0, 0xD2, 0xD3, 0xD4, 0xD7, 0xD8, 0xD9, 0xDA, 0xDB, 0xDC, 0xDD, 0xDF, 0xE3, 0xE4,
+    0xE7, 0xE9, 0xEB, 0xEC, 0xED, 0xEF, 0xF4, 0xF7, 0xFC, 0xFD, 0xFF,
+];
+
+/// Two banks: every straight-line opcode, every CB opcode, then jumps, calls and a bank switch
+fn rom(assembler: &Assembler) -> Vec<u8> {
+    let mut rom = vec![0; 2 * BANK_SIZE];
+    let mut code = Vec::new();
+
+    for opcode in (0..=0xFF).filter(|opcode| !NOT_STRAIGHT_LINE.contains(opcode)) {
+        if let Some(len) = assembler.len(opcode) {
+            code.extend_from_slice(&[opcode, 0x42, 0x13][..len]);
+        }
+    }
+
+    for opcode in 0..=0xFF {
+        code.extend_from_slice(&[0xCB, opcode]);
+    }
+
+    let start = 0x150;
+    let sub = (start + code.len() + 64) as u16;
+
+    code.extend_from_slice(&[
+        0xFA, 0x44, 0xFF,       // ld a, [$FF44], which RGBDS could turn into ldh
+        0xEA, 0x40, 0xFF,       // ld [$FF40], a
+        0x10, 0x00,             // stop
+        0x10, 0x01,             // stop with an operand RGBDS doesn't emit
+        0x20, 0x02,             // jr nz, +2
+        0x00, 0x00,
+        0x38, 0xFC,             // jr c, -4
+        0xCD, sub as u8, (sub >> 8) as u8, // call sub
+        0xDC, sub as u8, (sub >> 8) as u8, // call c, sub
+        0x3E, 0x01,             // ld a, 1
+        0xEA, 0x00, 0x20,       // ld [$2000], a
+        0xCD, 0x00, 0x40,       // call $4000
+        0xEF,                   // rst $28
+        0xC2, 0x50, 0x01,       // jp nz, $0150
+        0x18, 0xFE,             // jr @
+        0xD3,                   // illegal
+    ]);
+
+    rom[start..start + code.len()].copy_from_slice(&code);
+    rom[sub as usize] = 0xC9;               // ret
+    rom[0x100..0x103].copy_from_slice(&[0xC3, 0x50, 0x01]);
+    rom[0x28] = 0xD9;                       // reti
+    rom[BANK_SIZE..BANK_SIZE + 4].copy_from_slice(&[0x06, 0x01, 0xC8, 0xC9]);
+
+    rom
+}
+
+#[test]
+fn disassembly_reassembles_byte_identically() {
+    let out_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("disasm_roundtrip");
+    let assembler = Assembler::new();
+    let rom = rom(&assembler);
+    fs::create_dir_all(&out_dir).unwrap();
+    fs::write(out_dir.join("game.gb"), &rom).unwrap();
+
+    let status = Command::new(env!("CARGO_BIN_EXE_good_boi-disasm"))
+        .arg(out_dir.join("game.gb"))
+        .arg(&out_dir)
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let reassembled = match rgbds(&out_dir) {
+        Some(reassembled) => reassembled,
+        None => {
+            eprintln!("rgbasm not found, reassembling with the opcode table in this test instead");
+            assembler.assemble(&out_dir)
+        },
+    };
+
+    assert_eq!(reassembled.len(), rom.len());
+
+    if let Some(offset) = (0..rom.len()).find(|&offset| reassembled[offset] != rom[offset]) {
+        panic!("Differs at ${:05X}: {:02X} instead of {:02X}", offset, reassembled[offset], rom[offset]);
+    }
+}
+
+#[test]
+fn opcode_table_covers_every_opcode() {
+    let table = opcode_table();
+    let mut opcodes = table.values().map(|(bytes, _)| bytes.clone()).collect::<Vec<_>>();
+    opcodes.sort();
+    opcodes.dedup();
+
+    // 256 opcodes less the prefix and the 11 that don't exist, and 256 CB opcodes
+    assert_eq!(opcodes.len(), 244 + 256);
+}
+
+/// Assembles with RGBDS, if it is installed
+fn rgbds(dir: &Path) -> Option<Vec<u8>> {
+    Command::new("rgbasm").arg("--version").output().ok()?;
+
+    let run = |program: &str, args: &[&str]| {
+        let status = Command::new(program).args(args).current_dir(dir).status().unwrap();
+        assert!(status.success(), "{} failed", program);
+    };
+
+    run("rgbasm", &["-o", "game.o", "game.asm"]);
+    run("rgblink", &["-o", "reassembled.gb", "game.o"]);
+
+    Some(fs::read(dir.join("reassembled.gb")).unwrap())
+}
+
+/// How the operand that follows an opcode is encoded
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Imm {
+    None,
+    /// `$XX`
+    U8,
+    /// `$FFXX`, encoded as XX
+    High,
+    /// `$XXXX` or a label
+    U16,
+    /// A jump target as `$XXXX` or a label, encoded relative to the next instruction
+    Relative,
+    /// A signed decimal
+    Signed,
+}
+
+impl Imm {
+    fn len(self) -> usize {
+        match self {
+            Imm::None => 0,
+            Imm::U16 => 2,
+            _ => 1,
+        }
+    }
+}
+
+const R8: [&str; 8] = ["b", "c", "d", "e", "h", "l", "[hl]", "a"];
+const R16: [&str; 4] = ["bc", "de", "hl", "sp"];
+const R16_STACK: [&str; 4] = ["bc", "de", "hl", "af"];
+const CONDS: [&str; 4] = ["nz", "z", "nc", "c"];
+const ALU: [&str; 8] = ["add", "adc", "sub", "sbc", "and", "xor", "or", "cp"];
+const SHIFTS: [&str; 8] = ["rlc", "rrc", "rl", "rr", "sla", "sra", "swap", "srl"];
+
+/// Operands that are spelled out rather than being a number or a label
+const NAMED_OPERANDS: &[&str] = &[
+    "a", "b", "c", "d", "e", "h", "l", "af", "bc", "de", "hl", "sp", "nz", "z", "nc",
+    "[bc]", "[de]", "[hl]", "[hl+]", "[hl-]", "[c]",
+];
+
+/// Every SM83 instruction in RGBDS syntax, written out from the opcode map
+/// rather than taken from good_boi's disassembler. Numbers and labels are `n`.
+fn opcode_table() -> HashMap<String, (Vec<u8>, Imm)> {
+    let mut table = HashMap::new();
+    let mut add = |text: String, opcode: &[u8], imm: Imm| {
+        assert!(table.insert(text.clone(), (opcode.to_vec(), imm)).is_none(), "`{}` is listed twice", text);
+    };
+
+    for (i, name) in ["nop", "rlca", "rrca", "rla", "rra", "daa", "cpl", "scf", "ccf"].iter().enumerate() {
+        let opcode = [0x00, 0x07, 0x0F, 0x17, 0x1F, 0x27, 0x2F, 0x37, 0x3F][i];
+        add(name.to_string(), &[opcode], Imm::None);
+    }
+
+    // RGBDS always emits `stop` as 10 00
+    add("stop".into(), &[0x10, 0x00], Imm::None);
+    add("halt".into(), &[0x76], Imm::None);
+    add("di".into(), &[0xF3], Imm::None);
+    add("ei".into(), &[0xFB], Imm::None);
+    add("ret".into(), &[0xC9], Imm::None);
+    add("reti".into(), &[0xD9], Imm::None);
+
+    add("ld [bc], a".into(), &[0x02], Imm::None);
+    add("ld [de], a".into(), &[0x12], Imm::None);
+    add("ld [hl+], a".into(), &[0x22], Imm::None);
+    add("ld [hl-], a".into(), &[0x32], Imm::None);
+    add("ld a, [bc]".into(), &[0x0A], Imm::None);
+    add("ld a, [de]".into(), &[0x1A], Imm::None);
+    add("ld a, [hl+]".into(), &[0x2A], Imm::None);
+    add("ld a, [hl-]".into(), &[0x3A], Imm::None);
+    add("ld [n], sp".into(), &[0x08], Imm::U16);
+    add("ld [n], a".into(), &[0xEA], Imm::U16);
+    add("ld a, [n]".into(), &[0xFA], Imm::U16);
+    add("ldh [n], a".into(), &[0xE0], Imm::High);
+    add("ldh a, [n]".into(), &[0xF0], Imm::High);
+    add("ldh [c], a".into(), &[0xE2], Imm::None);
+    add("ldh a, [c]".into(), &[0xF2], Imm::None);
+    add("ld hl, sp+n".into(), &[0xF8], Imm::Signed);
+    add("ld sp, hl".into(), &[0xF9], Imm::None);
+    add("add sp, n".into(), &[0xE8], Imm::Signed);
+    add("jr n".into(), &[0x18], Imm::Relative);
+    add("jp n".into(), &[0xC3], Imm::U16);
+    add("jp hl".into(), &[0xE9], Imm::None);
+    add("call n".into(), &[0xCD], Imm::U16);
+
+    for (i, reg) in R16.iter().enumerate() {
+        let i = i as u8 * 0x10;
+        add(format!("ld {}, n", reg), &[0x01 + i], Imm::U16);
+        add(format!("inc {}", reg), &[0x03 + i], Imm::None);
+        add(format!("add hl, {}", reg), &[0x09 + i], Imm::None);
+        add(format!("dec {}", reg), &[0x0B + i], Imm::None);
+    }
+
+    for (i, reg) in R16_STACK.iter().enumerate() {
+        let i = i as u8 * 0x10;
+        add(format!("pop {}", reg), &[0xC1 + i], Imm::None);
+        add(format!("push {}", reg), &[0xC5 + i], Imm::None);
+    }
+
+    for (i, cond) in CONDS.iter().enumerate() {
+        let i = i as u8 * 8;
+        add(format!("jr {}, n", cond), &[0x20 + i], Imm::Relative);
+        add(format!("ret {}", cond), &[0xC0 + i], Imm::None);
+        add(format!("jp {}, n", cond), &[0xC2 + i], Imm::U16);
+        add(format!("call {}, n", cond), &[0xC4 + i], Imm::U16);
+    }
+
+    for (y, target) in R8.iter().enumerate() {
+        let y = y as u8;
+        add(format!("inc {}", target), &[0x04 + y * 8], Imm::None);
+        add(format!("dec {}", target), &[0x05 + y * 8], Imm::None);
+        add(format!("ld {}, n", target), &[0x06 + y * 8], Imm::U8);
+        add(format!("rst ${:02X}", y * 8), &[0xC7 + y * 8], Imm::None);
+
+        for (z, source) in R8.iter().enumerate() {
+            if (y, z) != (6, 6) {
+                add(format!("ld {}, {}", target, source), &[0x40 + y * 8 + z as u8], Imm::None);
+            }
+        }
+
+        // RGBDS takes the `a, ` of every ALU instruction as optional
+        let alu = ALU[y as usize];
+        for (z, source) in R8.iter().copied().chain(Some("n")).enumerate() {
+            let (opcode, imm) = if source == "n" { (0xC6 + y * 8, Imm::U8) } else { (0x80 + y * 8 + z as u8, Imm::None) };
+            add(format!("{} {}", alu, source), &[opcode], imm);
+            add(format!("{} a, {}", alu, source), &[opcode], imm);
+        }
+
+        for (z, source) in R8.iter().enumerate() {
+            let z = z as u8;
+            add(format!("{} {}", SHIFTS[y as usize], source), &[0xCB, y * 8 + z], Imm::None);
+            add(format!("bit {}, {}", y, source), &[0xCB, 0x40 + y * 8 + z], Imm::None);
+            add(format!("res {}, {}", y, source), &[0xCB, 0x80 + y * 8 + z], Imm::None);
+            add(format!("set {}, {}", y, source), &[0xCB, 0xC0 + y * 8 + z], Imm::None);
+        }
+    }
+
+    table
+}
+
+struct Assembler {
+    table: HashMap<String, (Vec<u8>, Imm)>,
+}
+
+impl Assembler {
+    fn new() -> Self {
+        Self { table: opcode_table() }
+    }
+
+    /// Length of the instruction starting with `opcode`, or `None` if it doesn't exist
+    fn len(&self, opcode: u8) -> Option<usize> {
+        self.table.values()
+            .find(|(bytes, _)| bytes[0] == opcode)
+            .map(|(bytes, imm)| bytes.len() + imm.len())
+    }
+
+    /// Assembles the output of good_boi-disasm in `dir`
+    fn assemble(&self, dir: &Path) -> Vec<u8> {
+        let main = fs::read_to_string(dir.join("game.asm")).unwrap();
+        let lines = main.lines()
+            .filter_map(|line| line.strip_prefix("INCLUDE \""))
+            .flat_map(|file| {
+                let source = fs::read_to_string(dir.join(file.trim_end_matches('"'))).unwrap();
+                source.lines().map(String::from).collect::<Vec<_>>()
+            })
+            .collect::<Vec<String>>();
+
+        let labels = find_labels(&lines);
+        let mut rom = Vec::new();
+        let mut addr = 0;
+
+        for line in &lines {
+            if let Some(section) = line.strip_prefix("SECTION ") {
+                let bank = match section.find("BANK[$") {
+                    Some(start) => usize::from_str_radix(&section[start + 6..section.len() - 1], 16).unwrap(),
+                    None => 0,
+                };
+
+                assert_eq!(rom.len(), bank * BANK_SIZE, "Sections must be in bank order");
+                addr = if bank == 0 { 0x0000 } else { 0x4000 };
+                continue;
+            }
+
+            let (text, comment) = match line.split_once(';') {
+                Some((text, comment)) => (text.trim(), comment.trim()),
+                None => continue,
+            };
+
+            assert_eq!(comment, format!("${:04X}", addr), "{}", line);
+
+            let bytes = match text.strip_prefix("db ") {
+                Some(data) => data.split(", ").map(|byte| u8::from_str_radix(&byte[1..], 16).unwrap()).collect(),
+                None => self.encode(text, addr, &labels),
+            };
+
+            addr += bytes.len() as u16;
+            rom.extend(bytes);
+        }
+
+        rom
+    }
+
+    fn encode(&self, text: &str, addr: u16, labels: &HashMap<String, u16>) -> Vec<u8> {
+        assert!(
+            !(text.starts_with("ld ") && text.contains("[$FF")),
+            "`{}` may be assembled into `ldh` by RGBDS", text,
+        );
+
+        let (mnemonic, operands) = text.split_once(' ').unwrap_or((text, ""));
+        let mut key = vec![mnemonic.to_string()];
+        let mut value = None;
+
+        for (i, operand) in operands.split(", ").filter(|operand| !operand.is_empty()).enumerate() {
+            let literal = NAMED_OPERANDS.contains(&operand)
+                || (i == 0 && ["bit", "res", "set", "rst"].contains(&mnemonic));
+
+            key.push(if literal {
+                operand.to_string()
+            } else if let Some(inner) = operand.strip_prefix('[').and_then(|operand| operand.strip_suffix(']')) {
+                value = Some(inner);
+                "[n]".into()
+            } else if let Some(offset) = operand.strip_prefix("sp") {
+                value = Some(offset.trim_start_matches('+'));
+                "sp+n".into()
+            } else {
+                value = Some(operand);
+                "n".into()
+            });
+        }
+
+        let key = format!("{} {}", key[0], key[1..].join(", "));
+        let (opcode, imm) = self.table.get(key.trim_end())
+            .unwrap_or_else(|| panic!("`{}` doesn't assemble", text));
+        let mut bytes = opcode.clone();
+
+        let number = || {
+            let token = value.unwrap_or_else(|| panic!("`{}` is missing its operand", text));
+            match token.strip_prefix('$') {
+                Some(hex) => i64::from_str_radix(hex, 16).ok(),
+                None => token.parse::<i64>().ok().or_else(|| labels.get(token).map(|&addr| addr as i64)),
+            }
+            .unwrap_or_else(|| panic!("`{}` has an unknown operand", text))
+        };
+        let fits = |value: i64, range: std::ops::RangeInclusive<i64>| {
+            assert!(range.contains(&value), "`{}` is out of range", text);
+            value
+        };
+
+        match imm {
+            Imm::None => {},
+            Imm::U8 => bytes.push(fits(number(), 0..=0xFF) as u8),
+            Imm::High => bytes.push(fits(number(), 0xFF00..=0xFFFF) as u8),
+            Imm::U16 => bytes.extend_from_slice(&(fits(number(), 0..=0xFFFF) as u16).to_le_bytes()),
+            Imm::Relative => bytes.push(fits(number() - (addr as i64 + 2), -128..=127) as u8),
+            Imm::Signed => bytes.push(fits(number(), -128..=127) as u8),
+        }
+
+        bytes
+    }
+}
+
+/// Labels are at the address in the comment of the line that follows them
+fn find_labels(lines: &[String]) -> HashMap<String, u16> {
+    let mut labels = HashMap::new();
+    let mut pending = Vec::new();
+
+    for line in lines {
+        if let Some(label) = line.strip_suffix(':') {
+            pending.push(label.to_string());
+        } else if let Some((_, comment)) = line.split_once("; $") {
+            let addr = u16::from_str_radix(comment.trim(), 16).unwrap();
+            labels.extend(pending.drain(..).map(|label| (label, addr)));
+        }
+    }
+
+    labels
+}
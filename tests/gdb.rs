@@ -0,0 +1,70 @@
+//! The gdb stub driven by a scripted client.
+
+use std::cell::RefCell;
+use std::io::{self, Cursor, Read, Write};
+use std::rc::Rc;
+use good_boi::{Core, Bus, Cartridge, GdbStub, Connection};
+
+/// Replays `input` and collects everything the stub sends
+struct Client {
+    input: Cursor<Vec<u8>>,
+    output: Rc<RefCell<Vec<u8>>>,
+}
+
+impl Read for Client {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl Write for Client {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Connection for Client {
+    fn set_nonblocking(&self, _nonblocking: bool) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Runs `input` against `jr -2` and returns the packets sent back
+fn serve(input: &[u8]) -> Vec<String> {
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x102].copy_from_slice(&[0x18, 0xFE]);
+
+    let mut core = Core::new(Bus::new(Cartridge::load(rom).unwrap()));
+    let output = Rc::new(RefCell::new(Vec::new()));
+    let client = Client { input: Cursor::new(input.to_vec()), output: output.clone() };
+
+    GdbStub::new(&mut core, client).serve().unwrap();
+
+    let output = String::from_utf8(output.take()).unwrap();
+    // Acks are sent between packets, the checksum follows the `#`
+    output.split('$').skip(1).map(|packet| packet.split('#').next().unwrap().to_string()).collect()
+}
+
+#[test]
+fn multibyte_commands_are_unsupported() {
+    assert_eq!(serve("$é#00$qC#00".as_bytes()), ["", "QC1"]);
+}
+
+#[test]
+fn packets_sent_while_running_are_kept() {
+    // The client asks for the thread while the target runs, then interrupts it
+    let replies = serve(b"$c#00$qC#00\x03$k#00");
+
+    assert_eq!(replies, ["S02", "QC1", ""]);
+}
+
+#[test]
+fn multibyte_characters_in_hex_are_errors() {
+    let replies = serve("$Mc000,2:aé0#00$P0=aé0#00$G#00$qC#00".as_bytes());
+
+    assert_eq!(replies, ["E01", "E01", "E01", "QC1"]);
+}
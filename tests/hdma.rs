@@ -0,0 +1,135 @@
+//! HBlank DMA driven by the LCD line timing the bus is ticked with, and general purpose DMA.
+
+use good_boi::{Core, Bus, Cartridge, MemoryBus, Watchpoint, WatchKind};
+
+const LINE_CYCLES: u32 = 456;
+const HBLANK_START: u32 = 252;
+const BLOCK_CYCLES: u32 = 32;
+
+/// A ROM of `nop`s, with two blocks of data in WRAM queued for HBlank DMA to 8000
+fn load() -> Bus {
+    let mut bus = load_data();
+    bus.write(0xFF55, 0x80 | 1);
+    bus
+}
+
+/// Two blocks of data in WRAM, with the DMA source and destination set but not started
+fn load_data() -> Bus {
+    let mut bus = Bus::new(Cartridge::load(vec![0; 0x8000]).unwrap());
+
+    for offset in 0..0x20 {
+        bus.write(0xC000 + offset, data(offset));
+    }
+
+    bus.write(0xFF51, 0xC0);
+    bus.write(0xFF52, 0x00);
+    bus.write(0xFF53, 0x00);
+    bus.write(0xFF54, 0x00);
+
+    bus
+}
+
+fn data(offset: u16) -> u8 {
+    0x40 + offset as u8
+}
+
+fn copied_blocks(bus: &Bus) -> usize {
+    (0..2)
+        .take_while(|block| (block * 0x10..(block + 1) * 0x10).all(|offset| bus.peek(0x8000 + offset) == data(offset)))
+        .count()
+}
+
+#[test]
+fn hblank_copies_one_block_per_line() {
+    let mut bus = load();
+
+    MemoryBus::tick(&mut bus, HBLANK_START - 1);
+    assert_eq!(copied_blocks(&bus), 0);
+
+    MemoryBus::tick(&mut bus, 1);
+    assert_eq!(copied_blocks(&bus), 1);
+    assert_eq!(bus.take_stall_cycles(), BLOCK_CYCLES);
+
+    MemoryBus::tick(&mut bus, LINE_CYCLES);
+    assert_eq!(copied_blocks(&bus), 2);
+    // Done
+    assert_eq!(bus.peek(0xFF55), 0xFF);
+}
+
+#[test]
+fn stall_cycles_advance_the_clock() {
+    let mut core = Core::new(load());
+    let steps_to = |cycles: u32| cycles / 4;
+
+    for _ in 0..steps_to(HBLANK_START) {
+        core.step().unwrap();
+    }
+
+    assert_eq!(copied_blocks(core.bus()), 1);
+
+    // The next step is stalled for the block, which gets the second HBlank closer
+    let second_hblank = LINE_CYCLES + HBLANK_START - BLOCK_CYCLES;
+
+    for _ in steps_to(HBLANK_START)..steps_to(second_hblank) - 1 {
+        core.step().unwrap();
+    }
+
+    assert_eq!(copied_blocks(core.bus()), 1);
+    assert_eq!(core.bus_mut().take_stall_cycles(), 0);
+
+    core.step().unwrap();
+    assert_eq!(copied_blocks(core.bus()), 2);
+}
+
+#[test]
+fn general_purpose_dma_copies_everything_at_once() {
+    let mut bus = load_data();
+    bus.write(0xFF55, 1);
+
+    assert_eq!(copied_blocks(&bus), 2);
+    assert_eq!(bus.take_stall_cycles(), 2 * BLOCK_CYCLES);
+    assert_eq!(bus.peek(0xFF55), 0xFF);
+}
+
+#[test]
+fn cancelled_hblank_dma_reports_the_remaining_blocks() {
+    let mut bus = load_data();
+    bus.write(0xFF55, 0x80 | 2);
+    // Active, 3 blocks to go
+    assert_eq!(bus.peek(0xFF55), 2);
+
+    MemoryBus::tick(&mut bus, HBLANK_START);
+    assert_eq!(copied_blocks(&bus), 1);
+    assert_eq!(bus.peek(0xFF55), 1);
+
+    bus.write(0xFF55, 0);
+    assert_eq!(bus.peek(0xFF55), 0x80 | 1);
+
+    MemoryBus::tick(&mut bus, LINE_CYCLES);
+    assert_eq!(copied_blocks(&bus), 1);
+}
+
+#[test]
+fn hblank_dma_with_the_lcd_off_copies_the_first_block_right_away() {
+    let mut bus = load_data();
+    bus.write(0xFF40, 0x11);
+    bus.write(0xFF55, 0x80 | 1);
+
+    assert_eq!(copied_blocks(&bus), 1);
+    assert_eq!(bus.take_stall_cycles(), BLOCK_CYCLES);
+
+    // Without HBlanks nothing more is copied
+    MemoryBus::tick(&mut bus, 2 * LINE_CYCLES);
+    assert_eq!(copied_blocks(&bus), 1);
+    assert_eq!(bus.peek(0xFF55), 0);
+}
+
+#[test]
+fn dma_reads_dont_trigger_watchpoints() {
+    let mut bus = load_data();
+    bus.add_watchpoint(Watchpoint { kind: WatchKind::Read, start: 0xC000, end: 0xC01F, value: None });
+    bus.write(0xFF55, 1);
+
+    assert_eq!(copied_blocks(&bus), 2);
+    assert_eq!(bus.take_watch_hit(), None);
+}
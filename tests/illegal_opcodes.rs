@@ -0,0 +1,71 @@
+//! What happens on the opcodes that don't exist, under both policies.
+
+use good_boi::{Core, Bus, Cartridge, FlatBus, MemoryBus, StepError, IllegalOpcodePolicy};
+
+/// `nop; db $D3`
+fn load() -> Core<FlatBus> {
+    let mut bus = FlatBus::new();
+    bus.try_write(0x100, 0x00);
+    bus.try_write(0x101, 0xD3);
+
+    Core::new(bus)
+}
+
+#[test]
+fn error_policy_fails_without_clocking() {
+    let mut core = load();
+    core.step().unwrap();
+    let cycles = core.bus().cycles();
+
+    match core.step() {
+        Err(StepError::IllegalOpcode { location, opcode }) => {
+            assert_eq!(location.addr, 0x101);
+            assert_eq!(opcode, 0xD3);
+        },
+        result => panic!("Expected an illegal opcode, got {:?}", result),
+    }
+
+    assert_eq!(core.pc(), 0x101);
+    assert!(!core.is_locked_up());
+    assert_eq!(core.bus().cycles(), cycles);
+
+    // `run` stops at the same error
+    assert!(core.run(10).is_err());
+    assert_eq!(core.instruction_count(), 1);
+}
+
+#[test]
+fn lock_up_keeps_clocking_the_bus() {
+    let mut core = load();
+    core.set_illegal_opcode_policy(IllegalOpcodePolicy::LockUp);
+
+    core.step().unwrap();
+    core.step().unwrap();
+    assert!(core.is_locked_up());
+    assert_eq!(core.pc(), 0x101);
+
+    let cycles = core.bus().cycles();
+    core.step().unwrap();
+    assert_eq!(core.bus().cycles(), cycles + 4);
+
+    // `run` keeps going until the limit instead of returning right away
+    core.run(100).unwrap();
+    assert_eq!(core.bus().cycles(), cycles + 4 + 400);
+    assert_eq!(core.pc(), 0x101);
+}
+
+#[test]
+fn locked_up_frames_keep_coming() {
+    let mut rom = vec![0; 0x8000];
+    rom[0x100] = 0xD3;
+
+    let mut core = Core::new(Bus::new(Cartridge::load(rom).unwrap()));
+    core.set_illegal_opcode_policy(IllegalOpcodePolicy::LockUp);
+    core.set_block_cache(true);
+
+    // A frame is 70224 T-cycles
+    core.run(2 * 70224 / 4).unwrap();
+
+    assert!(core.is_locked_up());
+    assert!(core.bus().frame_count() >= 1);
+}
@@ -0,0 +1,227 @@
+//! Checks the decode tables against `opcodes.json`,
+//! which uses the format of the gbdev opcode reference (https://gbdev.io/gb-opcodes/Opcodes.json).
+
+use good_boi::{Instruction, ExtendedInstruction, Operand, Reg8, Reg16, Cond};
+use serde_json::Value;
+
+#[test]
+fn decode_matches_reference() {
+    let reference: Value = serde_json::from_str(include_str!("opcodes.json")).unwrap();
+    let mut mismatches = Vec::new();
+
+    for code in 0..=0xFF {
+        let expected = &reference["unprefixed"][format!("0x{:02X}", code)];
+        let instruction = Instruction::decode(code);
+        // The CB prefix is decoded together with the extended opcode
+        let len = if instruction == Instruction::Extended { 1 } else { instruction.len() };
+        let actual = Actual {
+            syntax: describe(code, instruction),
+            len,
+            cycles: [instruction.cycles(true), instruction.cycles(false)],
+        };
+
+        actual.compare(&format!("{:02X}", code), expected, &mut mismatches);
+    }
+
+    for code in 0..=0xFF {
+        let expected = &reference["cbprefixed"][format!("0x{:02X}", code)];
+        let instruction = ExtendedInstruction::decode(code);
+        let actual = Actual {
+            syntax: describe_extended(instruction),
+            len: Instruction::Extended.len(),
+            cycles: [instruction.cycles(), instruction.cycles()],
+        };
+
+        actual.compare(&format!("CB {:02X}", code), expected, &mut mismatches);
+    }
+
+    assert!(mismatches.is_empty(), "{} mismatches:\n{}", mismatches.len(), mismatches.join("\n"));
+}
+
+struct Actual {
+    syntax: String,
+    len: u16,
+    /// Taken and not taken
+    cycles: [u8; 2],
+}
+
+impl Actual {
+    fn compare(&self, code: &str, expected: &Value, mismatches: &mut Vec<String>) {
+        let syntax = expected_syntax(expected);
+        let len = expected["bytes"].as_u64().unwrap() as u16;
+        let cycles = expected["cycles"].as_array().unwrap();
+        let taken = cycles[0].as_u64().unwrap() as u8;
+        let not_taken = cycles.get(1).map_or(taken, |cycles| cycles.as_u64().unwrap() as u8);
+
+        if self.syntax != syntax {
+            mismatches.push(format!("{}: decoded as {}, expected {}", code, self.syntax, syntax));
+        }
+
+        if self.len != len {
+            mismatches.push(format!("{} ({}): {} bytes, expected {}", code, syntax, self.len, len));
+        }
+
+        if self.cycles != [taken, not_taken] {
+            mismatches.push(format!("{} ({}): {:?} cycles, expected {:?}", code, syntax, self.cycles, [taken, not_taken]));
+        }
+    }
+}
+
+/// e.g. `LD A,[HL-]`
+fn expected_syntax(expected: &Value) -> String {
+    let mnemonic = expected["mnemonic"].as_str().unwrap();
+    let operands = expected["operands"].as_array().unwrap().iter().map(|operand| {
+        let mut name = operand["name"].as_str().unwrap().to_owned();
+
+        if operand["increment"] == Value::Bool(true) {
+            name.push('+');
+        }
+
+        if operand["decrement"] == Value::Bool(true) {
+            name.push('-');
+        }
+
+        if operand["immediate"] == Value::Bool(false) {
+            name = format!("[{}]", name);
+        }
+
+        name
+    }).collect::<Vec<_>>();
+
+    syntax(mnemonic, &operands)
+}
+
+fn syntax(mnemonic: &str, operands: &[String]) -> String {
+    if operands.is_empty() {
+        mnemonic.into()
+    } else {
+        format!("{} {}", mnemonic, operands.join(","))
+    }
+}
+
+fn describe(code: u8, instruction: Instruction) -> String {
+    use Instruction::*;
+
+    let a = || "A".to_owned();
+    let op = operand_name;
+    let with_cond = |mnemonic, cond, target| match cond_name(cond) {
+        Some(cond) => syntax(mnemonic, &[cond.into(), target]),
+        None => syntax(mnemonic, &[target]),
+    };
+
+    match instruction {
+        Nop => "NOP".into(),
+        Ld(target @ Operand::RegRef8(_), source) | Ld(target, source @ Operand::RegRef8(_)) => {
+            syntax("LDH", &[op(target, "n"), op(source, "n")])
+        },
+        Ld(target, source) => syntax("LD", &[op(target, "n"), op(source, "n")]),
+        Ldh(target, source) => syntax("LDH", &[op(target, "n"), op(source, "n")]),
+        Ldhl => "LD HL,SP+,e8".into(),
+        Inc(target) => syntax("INC", &[op(target, "n")]),
+        Dec(target) => syntax("DEC", &[op(target, "n")]),
+        Add(target @ Operand::Reg16(Reg16::SP), value) => syntax("ADD", &[op(target, "e"), op(value, "e")]),
+        Add(target, value) => syntax("ADD", &[op(target, "n"), op(value, "n")]),
+        Adc(value) => syntax("ADC", &[a(), op(value, "n")]),
+        Sub(value) => syntax("SUB", &[a(), op(value, "n")]),
+        Sbc(target, value) => syntax("SBC", &[op(target, "n"), op(value, "n")]),
+        Mul(..) | Div(..) => format!("{:?}", instruction),
+        And(value) => syntax("AND", &[a(), op(value, "n")]),
+        Xor(value) => syntax("XOR", &[a(), op(value, "n")]),
+        Or(value) => syntax("OR", &[a(), op(value, "n")]),
+        Cp(value) => syntax("CP", &[a(), op(value, "n")]),
+        Jr(cond, target) => with_cond("JR", cond, op(target, "e")),
+        Jp(cond, target) => with_cond("JP", cond, op(target, "a")),
+        Call(cond, target) => with_cond("CALL", cond, op(target, "a")),
+        Ret(cond) => match cond_name(cond) {
+            Some(cond) => syntax("RET", &[cond.into()]),
+            None => "RET".into(),
+        },
+        Reti => "RETI".into(),
+        Pop(reg) => syntax("POP", &[reg16_name(reg).into()]),
+        Push(reg) => syntax("PUSH", &[reg16_name(reg).into()]),
+        Stop => "STOP n8".into(),
+        Halt => "HALT".into(),
+        Rlca => "RLCA".into(),
+        Rrca => "RRCA".into(),
+        Rla => "RLA".into(),
+        Rra => "RRA".into(),
+        Daa => "DAA".into(),
+        Cpl => "CPL".into(),
+        Scf => "SCF".into(),
+        Ccf => "CCF".into(),
+        Rst(addr) => format!("RST ${:02X}", addr),
+        Extended => "PREFIX".into(),
+        Invalid => format!("ILLEGAL_{:02X}", code),
+        Di => "DI".into(),
+        Ei => "EI".into(),
+    }
+}
+
+fn describe_extended(instruction: ExtendedInstruction) -> String {
+    use ExtendedInstruction::*;
+
+    let op = |target| operand_name(target, "n");
+
+    match instruction {
+        Rlc(target) => syntax("RLC", &[op(target)]),
+        Rrc(target) => syntax("RRC", &[op(target)]),
+        Rl(target) => syntax("RL", &[op(target)]),
+        Rr(target) => syntax("RR", &[op(target)]),
+        Sla(target) => syntax("SLA", &[op(target)]),
+        Sra(target) => syntax("SRA", &[op(target)]),
+        Swap(target) => syntax("SWAP", &[op(target)]),
+        Srl(target) => syntax("SRL", &[op(target)]),
+        Bit(bit, target) => syntax("BIT", &[bit.to_string(), op(target)]),
+        Res(bit, target) => syntax("RES", &[bit.to_string(), op(target)]),
+        Set(bit, target) => syntax("SET", &[bit.to_string(), op(target)]),
+    }
+}
+
+/// `kind` is the reference's prefix for immediates: `n`umber, `a`ddress or signed offs`e`t
+fn operand_name(operand: Operand, kind: &str) -> String {
+    match operand {
+        Operand::Reg8(reg) => reg8_name(reg).into(),
+        Operand::RegRef8(reg) => format!("[{}]", reg8_name(reg)),
+        Operand::Reg16(reg) => reg16_name(reg).into(),
+        Operand::RegRef16(reg) => format!("[{}]", reg16_name(reg)),
+        Operand::Imm8 => format!("{}8", kind),
+        Operand::Imm8Ref => "[a8]".into(),
+        Operand::Imm16 => format!("{}16", kind),
+        Operand::Imm16Ref => "[a16]".into(),
+        Operand::Cond(cond) => cond_name(cond).unwrap_or_default().into(),
+    }
+}
+
+fn reg8_name(reg: Reg8) -> &'static str {
+    match reg {
+        Reg8::A => "A",
+        Reg8::B => "B",
+        Reg8::C => "C",
+        Reg8::D => "D",
+        Reg8::E => "E",
+        Reg8::H => "H",
+        Reg8::L => "L",
+    }
+}
+
+fn reg16_name(reg: Reg16) -> &'static str {
+    match reg {
+        Reg16::AF => "AF",
+        Reg16::BC => "BC",
+        Reg16::DE => "DE",
+        Reg16::HL => "HL",
+        Reg16::HLInc => "HL+",
+        Reg16::HLDec => "HL-",
+        Reg16::SP => "SP",
+    }
+}
+
+fn cond_name(cond: Cond) -> Option<&'static str> {
+    match cond {
+        Cond::ZSet => Some("Z"),
+        Cond::ZReset => Some("NZ"),
+        Cond::CSet => Some("C"),
+        Cond::CReset => Some("NC"),
+        Cond::Always => None,
+    }
+}
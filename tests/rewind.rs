@@ -0,0 +1,128 @@
+//! The rewind buffer and the state deltas it is built on.
+
+use good_boi::{Core, Bus, Cartridge, Rewind, encode_delta, decode_delta};
+
+fn round_trip(base: &[u8], target: &[u8]) {
+    let delta = encode_delta(base, target);
+    assert_eq!(decode_delta(base, &delta).unwrap(), target, "base {:?}", base);
+}
+
+#[test]
+fn deltas_round_trip() {
+    let base = (0..=255).collect::<Vec<u8>>();
+
+    // All equal
+    round_trip(&base, &base);
+    // The length and a single run of 256 equal bytes
+    assert_eq!(encode_delta(&base, &base).len(), 5);
+
+    // All different
+    let inverted = base.iter().map(|byte| !byte).collect::<Vec<u8>>();
+    round_trip(&base, &inverted);
+
+    // Single changes at the start, the middle and the end
+    for &i in &[0, 100, 255] {
+        let mut target = base.clone();
+        target[i] ^= 0x80;
+        round_trip(&base, &target);
+    }
+
+    // Longer and shorter than the base, and empty
+    round_trip(&base, &[base.as_slice(), &[1, 2, 3]].concat());
+    round_trip(&base, &[base.as_slice(), &[0, 0, 0]].concat());
+    round_trip(&base, &base[..10]);
+    round_trip(&base, &[]);
+    round_trip(&[], &base);
+    round_trip(&[], &[]);
+}
+
+#[test]
+fn deltas_round_trip_at_varint_boundaries() {
+    for &len in &[127, 128, 129, 16383, 16384, 16385] {
+        let base = vec![0x55; len + 1];
+
+        // Runs of equal bytes of `len`
+        let mut target = base.clone();
+        target[len] = 0xAA;
+        round_trip(&base, &target);
+
+        // Runs of different bytes of `len`
+        let mut target = vec![0xAA; len];
+        target.push(0x55);
+        round_trip(&base, &target);
+    }
+}
+
+#[test]
+fn malformed_deltas_are_rejected() {
+    let base = [1, 2, 3, 4];
+    let delta = encode_delta(&base, &[1, 2, 0xFF, 4, 5]);
+    assert!(decode_delta(&base, &delta).is_ok());
+
+    // Truncated anywhere
+    for len in 0..delta.len() {
+        assert!(decode_delta(&base, &delta[..len]).is_err(), "truncated to {}", len);
+    }
+
+    let malformed: &[&[u8]] = &[
+        // Literals past the target length
+        &[2, 0, 3, 1, 1, 1],
+        // A zero run past the target length
+        &[2, 5, 1, 1],
+        // A huge target length
+        &[0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x7F],
+        // A length that doesn't fit
+        &[0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x7F],
+        // A length that never ends
+        &[0x80],
+    ];
+
+    for delta in malformed {
+        assert!(decode_delta(&base, delta).is_err(), "{:?}", delta);
+    }
+}
+
+/// `inc b; jr -3`
+fn load() -> Core {
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x103].copy_from_slice(&[0x04, 0x18, 0xFD]);
+
+    Core::new(Bus::new(Cartridge::load(rom).unwrap()))
+}
+
+/// Runs until the LCD completes a frame and tells `rewind`
+fn run_frame(core: &mut Core, rewind: &mut Rewind) {
+    let frame = core.bus().frame_count();
+
+    while core.bus().frame_count() == frame {
+        core.step().unwrap();
+    }
+
+    rewind.end_frame(core);
+}
+
+#[test]
+fn rewind_steps_back_through_snapshots() {
+    let mut core = load();
+    let mut rewind = Rewind::new(2, 3);
+    let mut snapshots = Vec::new();
+
+    for frame in 1..=8 {
+        run_frame(&mut core, &mut rewind);
+
+        if frame % 2 == 0 {
+            snapshots.push(core.trace_line());
+        }
+    }
+
+    // Only the last 3 of the 4 snapshots are kept
+    assert_eq!(rewind.len(), 3);
+
+    for expected in snapshots.iter().rev().take(3) {
+        assert!(rewind.step_back(&mut core).unwrap());
+        assert_eq!(&core.trace_line(), expected);
+    }
+
+    assert!(!rewind.step_back(&mut core).unwrap());
+    assert!(rewind.is_empty());
+}
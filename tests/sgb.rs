@@ -0,0 +1,140 @@
+//! SGB commands sent as packets through the joypad register, like games do.
+
+use good_boi::{Bus, Cartridge, Button};
+
+const SCREEN_SIZE: usize = 160 * 144;
+const FRAME_WIDTH: usize = 256;
+/// Where the Game Boy screen starts in the SGB frame
+const SCREEN_LEFT: usize = 48;
+const SCREEN_TOP: usize = 40;
+
+const WHITE: u16 = 0x7FFF;
+const RED: u16 = 0x001F;
+const GREEN: u16 = 0x03E0;
+const BLUE: u16 = 0x7C00;
+
+fn load() -> Bus {
+    let mut rom = vec![0; 0x8000];
+    rom[0x146] = 0x03;
+    rom[0x14B] = 0x33;
+
+    let bus = Bus::new(Cartridge::load(rom).unwrap());
+    assert!(bus.sgb_enabled());
+    bus
+}
+
+/// Pulses a packet through P14/P15, least significant bit first
+fn send_packet(bus: &mut Bus, packet: &[u8; 16]) {
+    bus.write(0xFF00, 0x00);
+    bus.write(0xFF00, 0x30);
+
+    for bit in 0..128 {
+        let one = packet[bit / 8] >> (bit % 8) & 1 != 0;
+        bus.write(0xFF00, if one { 0x10 } else { 0x20 });
+        bus.write(0xFF00, 0x30);
+    }
+
+    // Stop bit
+    bus.write(0xFF00, 0x20);
+    bus.write(0xFF00, 0x30);
+}
+
+/// A single packet command
+fn command(code: u8, data: &[u8]) -> [u8; 16] {
+    let mut packet = [0; 16];
+    packet[0] = code << 3 | 1;
+    packet[1..1 + data.len()].copy_from_slice(data);
+    packet
+}
+
+/// PAL01/PAL23/PAL03/PAL12 data: the shared color 0, then colors 1-3 of both palettes
+fn palette_data(colors: [u16; 7]) -> Vec<u8> {
+    colors.iter().flat_map(|color| color.to_le_bytes()).collect()
+}
+
+/// Color of screen pixel x, y in the SGB frame
+fn pixel(frame: &[u32], x: usize, y: usize) -> u32 {
+    frame[(SCREEN_TOP + y) * FRAME_WIDTH + SCREEN_LEFT + x]
+}
+
+#[test]
+fn pal_sets_palette_colors() {
+    let mut bus = load();
+
+    send_packet(&mut bus, &command(0x00, &palette_data([WHITE, RED, GREEN, BLUE, 0, 0, 0])));
+
+    let frame = bus.render_sgb(&[1; SCREEN_SIZE]).unwrap();
+    assert_eq!(pixel(&frame, 0, 0), 0xFF0000);
+
+    let frame = bus.render_sgb(&[3; SCREEN_SIZE]).unwrap();
+    assert_eq!(pixel(&frame, 159, 143), 0x0000FF);
+    // Outside the screen, the transparent border shows color 0
+    assert_eq!(frame[0], 0xFFFFFF);
+}
+
+#[test]
+fn attr_blk_assigns_palettes_to_blocks() {
+    let mut bus = load();
+
+    send_packet(&mut bus, &command(0x00, &palette_data([WHITE, RED, 0, 0, 0, 0, 0])));
+    send_packet(&mut bus, &command(0x01, &palette_data([WHITE, GREEN, 0, 0, 0, 0, 0])));
+
+    // Palette 2 inside (and on the border of) the 8x8 pixel blocks 0-1, 0-1.
+    // The count asks for more sets than the packet holds, which must be ignored.
+    send_packet(&mut bus, &command(0x04, &[3, 0b001, 2, 0, 0, 1, 1]));
+
+    let frame = bus.render_sgb(&[1; SCREEN_SIZE]).unwrap();
+    assert_eq!(pixel(&frame, 0, 0), 0x00FF00);
+    assert_eq!(pixel(&frame, 15, 15), 0x00FF00);
+    assert_eq!(pixel(&frame, 16, 0), 0xFF0000);
+    assert_eq!(pixel(&frame, 0, 16), 0xFF0000);
+}
+
+#[test]
+fn mlt_req_switches_between_joypads() {
+    let mut bus = load();
+
+    bus.set_pressed(1, Button::A, true);
+    // There are only 4 joypads
+    bus.set_pressed(4, Button::A, true);
+
+    send_packet(&mut bus, &command(0x11, &[1]));
+
+    // With nothing selected, the low nibble is 0x0F - the current joypad
+    assert_eq!(bus.read(0xFF00) & 0x0F, 0x0F);
+
+    // Pulsing P15 selects the next joypad
+    bus.write(0xFF00, 0x10);
+    bus.write(0xFF00, 0x30);
+    assert_eq!(bus.read(0xFF00) & 0x0F, 0x0E);
+
+    bus.write(0xFF00, 0x10);
+    assert_eq!(bus.read(0xFF00) & 0x0F, 0x0E, "A of joypad 2 should be pressed");
+
+    bus.write(0xFF00, 0x30);
+    bus.write(0xFF00, 0x10);
+    assert_eq!(bus.read(0xFF00) & 0x0F, 0x0F, "nothing of joypad 1 should be pressed");
+}
+
+#[test]
+fn packets_without_length_are_ignored() {
+    let mut bus = load();
+
+    let mut packet = command(0x00, &palette_data([WHITE, RED, 0, 0, 0, 0, 0]));
+    packet[0] &= !0x07;
+    send_packet(&mut bus, &packet);
+
+    let frame = bus.render_sgb(&[1; SCREEN_SIZE]).unwrap();
+    assert_ne!(pixel(&frame, 0, 0), 0xFF0000);
+}
+
+#[test]
+fn short_screens_render_as_shade_0() {
+    let mut bus = load();
+
+    send_packet(&mut bus, &command(0x00, &palette_data([WHITE, RED, 0, 0, 0, 0, 0])));
+
+    let frame = bus.render_sgb(&[1; 160]).unwrap();
+    assert_eq!(pixel(&frame, 159, 0), 0xFF0000);
+    assert_eq!(pixel(&frame, 0, 1), 0xFFFFFF);
+}
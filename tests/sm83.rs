@@ -0,0 +1,187 @@
+//! Runs single instruction test vectors in the format of SingleStepTests
+//! (https://github.com/SingleStepTests/sm83) against the CPU, on a `FlatBus`.
+//!
+//! `tests/sm83/upstream` holds a subset of the SingleStepTests vectors, see its README.
+//! `tests/sm83/generated` supplements them with cases for every opcode the CPU implements,
+//! made by `tests/sm83/generated/generate.py`. The full suite can be run with
+//! `SM83_TESTS=path/to/sm83/v1 cargo test --test sm83 -- --ignored`.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use good_boi::{Core, FlatBus, WatchKind};
+use serde_json::Value;
+
+/// Failing cases that are printed per file
+const REPORTED_FAILURES: usize = 3;
+
+fn vectors(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/sm83").join(name)
+}
+
+#[test]
+fn checked_in_vectors() {
+    let dir = vectors("upstream");
+
+    if json_files(&dir).is_empty() {
+        eprintln!("No SingleStepTests vectors in {}, run fetch.py there", dir.display());
+        return;
+    }
+
+    run_dir(&dir);
+}
+
+#[test]
+fn generated_vectors() {
+    run_dir(&vectors("generated"));
+}
+
+#[test]
+#[ignore]
+fn single_step_tests() {
+    let dir = std::env::var("SM83_TESTS").expect("SM83_TESTS must point to the sm83 test vectors");
+
+    run_dir(Path::new(&dir));
+}
+
+fn json_files(dir: &Path) -> Vec<PathBuf> {
+    let mut paths = fs::read_dir(dir).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "json"))
+        .collect::<Vec<_>>();
+    paths.sort();
+    paths
+}
+
+fn run_dir(dir: &Path) {
+    let paths = json_files(dir);
+    let mut core = Core::new(FlatBus::new());
+    let mut failed_files = Vec::new();
+
+    for path in &paths {
+        let cases: Vec<Value> = serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+        let failures = cases.iter()
+            .filter_map(|case| run_case(&mut core, case).err().map(|err| format!("{}: {}", case["name"], err)))
+            .collect::<Vec<_>>();
+
+        if !failures.is_empty() {
+            eprintln!("{}: {} of {} cases failed", path.display(), failures.len(), cases.len());
+
+            for failure in failures.iter().take(REPORTED_FAILURES) {
+                eprintln!("    {}", failure);
+            }
+
+            failed_files.push(path.file_name().unwrap().to_string_lossy().into_owned());
+        }
+    }
+
+    assert!(!paths.is_empty(), "No test vectors in {}", dir.display());
+    assert!(failed_files.is_empty(), "{} of {} files failed: {}", failed_files.len(), paths.len(), failed_files.join(", "));
+}
+
+fn run_case(core: &mut Core<FlatBus>, case: &Value) -> Result<(), String> {
+    load_state(core, &case["initial"]);
+    core.bus_mut().take_accesses();
+
+    let start_cycles = core.bus().cycles();
+
+    core.step().map_err(|err| err.to_string())?;
+
+    let mut mismatches = compare_state(core, &case["final"]);
+    let cycles = core.bus().cycles() - start_cycles;
+    let expected_cycles = case["cycles"].as_array().unwrap();
+
+    // Listed per M-cycle
+    if cycles != expected_cycles.len() as u64 * 4 {
+        mismatches.push(format!("cycles {} != {}", cycles, expected_cycles.len() * 4));
+    }
+
+    let accesses = core.bus_mut().take_accesses();
+    let expected_accesses = expected_cycles.iter().filter_map(access).collect::<Vec<_>>();
+
+    if accesses != expected_accesses {
+        mismatches.push(format!("bus {} != {}", format_accesses(&accesses), format_accesses(&expected_accesses)));
+    }
+
+    if mismatches.is_empty() {
+        Ok(())
+    } else {
+        Err(mismatches.join(", "))
+    }
+}
+
+fn load_state(core: &mut Core<FlatBus>, state: &Value) {
+    let reg = |name: &str| state[name].as_u64().unwrap() as u16;
+
+    core.set_pc(reg("pc"));
+    core.set_sp(reg("sp"));
+    core.set_reg_af(reg("a") << 8 | reg("f"));
+    core.set_reg_bc(reg("b") << 8 | reg("c"));
+    core.set_reg_de(reg("d") << 8 | reg("e"));
+    core.set_reg_hl(reg("h") << 8 | reg("l"));
+    core.set_interrupts_enabled(reg("ime") != 0);
+    core.write_mem_u8(0xFFFF, reg("ie") as u8);
+
+    for (addr, value) in ram(state) {
+        core.write_mem_u8(addr, value);
+    }
+}
+
+fn compare_state(core: &Core<FlatBus>, state: &Value) -> Vec<String> {
+    let reg = |name: &str| state[name].as_u64().unwrap() as u16;
+    let mut mismatches = Vec::new();
+    let mut compare = |name: &str, actual: u16, expected: u16| {
+        if actual != expected {
+            mismatches.push(format!("{} {:02X} != {:02X}", name, actual, expected));
+        }
+    };
+
+    compare("pc", core.pc(), reg("pc"));
+    compare("sp", core.sp(), reg("sp"));
+
+    for &(name, pair, value) in &[
+        ("a", "f", core.reg_af()),
+        ("b", "c", core.reg_bc()),
+        ("d", "e", core.reg_de()),
+        ("h", "l", core.reg_hl()),
+    ] {
+        compare(name, value >> 8, reg(name));
+        compare(pair, value & 0xFF, reg(pair));
+    }
+
+    compare("ime", core.interrupts_enabled() as u16, reg("ime"));
+    compare("ie", core.peek_mem_u8(0xFFFF) as u16, reg("ie"));
+
+    for (addr, value) in ram(state) {
+        compare(&format!("[{:04X}]", addr), core.peek_mem_u8(addr) as u16, value as u16);
+    }
+
+    mismatches
+}
+
+fn ram(state: &Value) -> impl Iterator<Item = (u16, u8)> + '_ {
+    state["ram"].as_array().unwrap().iter().map(|entry| {
+        (entry[0].as_u64().unwrap() as u16, entry[1].as_u64().unwrap() as u8)
+    })
+}
+
+/// The read or write in a cycle like `[addr, value, "r-m"]`, `None` for internal cycles
+fn access(cycle: &Value) -> Option<(WatchKind, u16, u8)> {
+    let kind = match cycle[2].as_str()? {
+        kind if kind.starts_with('r') => WatchKind::Read,
+        kind if kind.contains('w') => WatchKind::Write,
+        _ => return None,
+    };
+
+    Some((kind, cycle[0].as_u64()? as u16, cycle[1].as_u64()? as u8))
+}
+
+fn format_accesses(accesses: &[(WatchKind, u16, u8)]) -> String {
+    let accesses = accesses.iter()
+        .map(|&(kind, addr, value)| match kind {
+            WatchKind::Write => format!("[{:04X}]<-{:02X}", addr, value),
+            _ => format!("[{:04X}]->{:02X}", addr, value),
+        })
+        .collect::<Vec<_>>();
+
+    format!("[{}]", accesses.join(" "))
+}
@@ -0,0 +1,340 @@
+#!/usr/bin/env python3
+"""Generates the SM83 vectors in this directory, in the format of SingleStepTests.
+
+These are a supplement to the upstream vectors in ../upstream, not a substitute:
+the instructions are modelled here from the SM83 documentation, independently of the
+emulator, one M-cycle at a time, but by the same project that wrote the CPU.
+Only opcodes the CPU implements are generated.
+
+    python3 tests/sm83/generated/generate.py
+"""
+
+import json
+import os
+import random
+
+CASES = 10
+SEED = 0x5383
+
+# The order of the register encodings in opcodes
+R8 = ["b", "c", "d", "e", "h", "l", "(hl)", "a"]
+R16 = ["bc", "de", "hl", "sp"]
+R16_STACK = ["bc", "de", "hl", "af"]
+
+Z, N, H, C = 0x80, 0x40, 0x20, 0x10
+
+
+class Cpu:
+    def __init__(self, rng, state):
+        self.rng = rng
+        self.r = dict(state)
+        self.initial_ram = {}
+        self.ram = {}
+        self.cycles = []
+
+    def value(self, addr):
+        if addr not in self.ram:
+            self.ram[addr] = self.initial_ram[addr] = self.rng.randrange(256)
+        return self.ram[addr]
+
+    def read(self, addr):
+        addr &= 0xFFFF
+        value = self.value(addr)
+        self.cycles.append([addr, value, "r-m"])
+        return value
+
+    def write(self, addr, value):
+        addr &= 0xFFFF
+        self.value(addr)
+        self.ram[addr] = value
+        self.cycles.append([addr, value, "-wm"])
+
+    def internal(self):
+        self.cycles.append([None, None, "---"])
+
+    def fetch(self):
+        value = self.read(self.r["pc"])
+        self.r["pc"] = (self.r["pc"] + 1) & 0xFFFF
+        return value
+
+    def fetch16(self):
+        lo = self.fetch()
+        return self.fetch() << 8 | lo
+
+    def get16(self, name):
+        if name == "sp":
+            return self.r["sp"]
+        return self.r[name[0]] << 8 | self.r[name[1]]
+
+    def set16(self, name, value):
+        value &= 0xFFFF
+        if name == "sp":
+            self.r["sp"] = value
+        else:
+            self.r[name[0]] = value >> 8
+            self.r[name[1]] = value & 0xFF
+            if name == "af":
+                self.r["f"] &= 0xF0
+
+    def get8(self, name):
+        if name == "(hl)":
+            return self.read(self.get16("hl"))
+        return self.r[name]
+
+    def set8(self, name, value):
+        if name == "(hl)":
+            self.write(self.get16("hl"), value & 0xFF)
+        else:
+            self.r[name] = value & 0xFF
+
+    def flag(self, mask):
+        return self.r["f"] & mask != 0
+
+    def set_flags(self, z=None, n=None, h=None, c=None):
+        for mask, value in ((Z, z), (N, n), (H, h), (C, c)):
+            if value is not None:
+                self.r["f"] = self.r["f"] | mask if value else self.r["f"] & ~mask
+
+    def cond(self, index):
+        return [not self.flag(Z), self.flag(Z), not self.flag(C), self.flag(C)][index]
+
+    def push(self, value):
+        self.r["sp"] = (self.r["sp"] - 1) & 0xFFFF
+        self.write(self.r["sp"], value >> 8)
+        self.r["sp"] = (self.r["sp"] - 1) & 0xFFFF
+        self.write(self.r["sp"], value & 0xFF)
+
+    def pop(self):
+        lo = self.read(self.r["sp"])
+        self.r["sp"] = (self.r["sp"] + 1) & 0xFFFF
+        hi = self.read(self.r["sp"])
+        self.r["sp"] = (self.r["sp"] + 1) & 0xFFFF
+        return hi << 8 | lo
+
+    def alu(self, op, value):
+        a = self.r["a"]
+        carry = int(self.flag(C))
+
+        if op == "add" or op == "adc":
+            c = carry if op == "adc" else 0
+            result = a + value + c
+            self.set_flags(result & 0xFF == 0, False, (a & 0xF) + (value & 0xF) + c > 0xF, result > 0xFF)
+        elif op == "sub" or op == "cp":
+            result = a - value
+            self.set_flags(result & 0xFF == 0, True, (a & 0xF) < (value & 0xF), result < 0)
+            if op == "cp":
+                return
+        elif op == "and":
+            result = a & value
+            self.set_flags(result == 0, False, True, False)
+        elif op == "xor":
+            result = a ^ value
+            self.set_flags(result == 0, False, False, False)
+        elif op == "or":
+            result = a | value
+            self.set_flags(result == 0, False, False, False)
+
+        self.r["a"] = result & 0xFF
+
+    def execute(self, opcode):
+        """Executes `opcode`, after it was fetched"""
+        x, y, z = opcode >> 6, opcode >> 3 & 7, opcode & 7
+
+        if opcode == 0x00:
+            pass
+        elif opcode == 0x08:
+            addr = self.fetch16()
+            self.write(addr, self.r["sp"] & 0xFF)
+            self.write(addr + 1, self.r["sp"] >> 8)
+        elif opcode in (0x17, 0x1F):
+            a = self.r["a"]
+            if opcode == 0x17:
+                result = a << 1 | self.flag(C)
+                carry = a & 0x80
+            else:
+                result = a >> 1 | self.flag(C) << 7
+                carry = a & 1
+            self.r["a"] = result & 0xFF
+            self.set_flags(False, False, False, carry != 0)
+        elif opcode == 0x18 or opcode in (0x20, 0x28, 0x30, 0x38):
+            offset = self.fetch()
+            if opcode == 0x18 or self.cond(y - 4):
+                self.internal()
+                self.r["pc"] = (self.r["pc"] + offset - (256 if offset >= 128 else 0)) & 0xFFFF
+        elif x == 0 and z == 1:
+            if y & 1:
+                a, b = self.get16("hl"), self.get16(R16[y >> 1])
+                result = a + b
+                self.internal()
+                self.set_flags(None, False, (a & 0xFFF) + (b & 0xFFF) > 0xFFF, result > 0xFFFF)
+                self.set16("hl", result)
+            else:
+                self.set16(R16[y >> 1], self.fetch16())
+        elif x == 0 and z == 2:
+            name = ["bc", "de", "hl", "hl"][y >> 1]
+            addr = self.get16(name)
+            if y & 1:
+                self.r["a"] = self.read(addr)
+            else:
+                self.write(addr, self.r["a"])
+            if y >= 4:
+                self.set16("hl", addr + (1 if y < 6 else -1))
+        elif x == 0 and z == 3:
+            name = R16[y >> 1]
+            self.internal()
+            self.set16(name, self.get16(name) + (-1 if y & 1 else 1))
+        elif x == 0 and z in (4, 5):
+            value = self.get8(R8[y])
+            if z == 4:
+                result = (value + 1) & 0xFF
+                self.set_flags(result == 0, False, value & 0xF == 0xF)
+            else:
+                result = (value - 1) & 0xFF
+                self.set_flags(result == 0, True, value & 0xF == 0)
+            self.set8(R8[y], result)
+        elif x == 0 and z == 6:
+            self.set8(R8[y], self.fetch())
+        elif x == 1:
+            self.set8(R8[y], self.get8(R8[z]))
+        elif x == 2:
+            self.alu(["add", "adc", "sub", "sbc", "and", "xor", "or", "cp"][y], self.get8(R8[z]))
+        elif x == 3 and z == 6:
+            self.alu(["add", "adc", "sub", "sbc", "and", "xor", "or", "cp"][y], self.fetch())
+        elif opcode in (0xC0, 0xC8, 0xD0, 0xD8):
+            self.internal()
+            if self.cond(y):
+                self.r["pc"] = self.pop()
+                self.internal()
+        elif opcode in (0xC9, 0xD9):
+            self.r["pc"] = self.pop()
+            self.internal()
+            if opcode == 0xD9:
+                self.r["ime"] = 1
+        elif opcode == 0xE9:
+            self.r["pc"] = self.get16("hl")
+        elif opcode == 0xF9:
+            self.internal()
+            self.r["sp"] = self.get16("hl")
+        elif x == 3 and z == 1:
+            self.set16(R16_STACK[y >> 1], self.pop())
+        elif opcode in (0xC2, 0xC3, 0xCA, 0xD2, 0xDA):
+            addr = self.fetch16()
+            if opcode == 0xC3 or self.cond(y):
+                self.internal()
+                self.r["pc"] = addr
+        elif opcode in (0xC4, 0xCC, 0xCD, 0xD4, 0xDC):
+            addr = self.fetch16()
+            if opcode == 0xCD or self.cond(y):
+                self.internal()
+                self.push(self.r["pc"])
+                self.r["pc"] = addr
+        elif x == 3 and z == 5:
+            self.internal()
+            self.push(self.get16(R16_STACK[y >> 1]))
+        elif x == 3 and z == 7:
+            self.internal()
+            self.push(self.r["pc"])
+            self.r["pc"] = y * 8
+        elif opcode == 0xE0:
+            self.write(0xFF00 + self.fetch(), self.r["a"])
+        elif opcode == 0xF0:
+            self.r["a"] = self.read(0xFF00 + self.fetch())
+        elif opcode == 0xE2:
+            self.write(0xFF00 + self.r["c"], self.r["a"])
+        elif opcode == 0xEA:
+            self.write(self.fetch16(), self.r["a"])
+        elif opcode == 0xFA:
+            self.r["a"] = self.read(self.fetch16())
+        elif opcode == 0xF3:
+            self.r["ime"] = 0
+        elif opcode == 0xCB:
+            self.execute_cb(self.fetch())
+        else:
+            raise ValueError("Opcode {:02X} isn't modelled".format(opcode))
+
+    def execute_cb(self, opcode):
+        x, y, z = opcode >> 6, opcode >> 3 & 7, opcode & 7
+        value = self.get8(R8[z])
+
+        if x == 1:
+            self.set_flags(value & 1 << y == 0, False, True)
+            return
+
+        if opcode >> 3 == 2:
+            result, carry = value << 1 | self.flag(C), value & 0x80
+        elif opcode >> 3 == 3:
+            result, carry = value >> 1 | self.flag(C) << 7, value & 1
+        elif opcode >> 3 == 7:
+            result, carry = value >> 1, value & 1
+        else:
+            raise ValueError("Opcode CB {:02X} isn't modelled".format(opcode))
+
+        result &= 0xFF
+        self.set_flags(result == 0, False, False, carry != 0)
+        self.set8(R8[z], result)
+
+
+# Implemented by the CPU, see `Core::execute`
+OPCODES = (
+    [0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x08, 0x09, 0x0A, 0x0B, 0x0C, 0x0D, 0x0E]
+    + [opcode for opcode in range(0x11, 0x40) if opcode not in (0x27, 0x2F, 0x37, 0x3F)]
+    + [opcode for opcode in range(0x40, 0x98) if opcode != 0x76]
+    + list(range(0xA0, 0xC0))
+    + [0xC0, 0xC1, 0xC2, 0xC3, 0xC4, 0xC5, 0xC6, 0xC7, 0xC8, 0xC9, 0xCA, 0xCC, 0xCD, 0xCE, 0xCF]
+    + [0xD0, 0xD1, 0xD2, 0xD4, 0xD5, 0xD6, 0xD7, 0xD8, 0xD9, 0xDA, 0xDC, 0xDF]
+    + [0xE0, 0xE1, 0xE2, 0xE5, 0xE6, 0xE7, 0xE9, 0xEA, 0xEE, 0xEF]
+    + [0xF0, 0xF1, 0xF3, 0xF5, 0xF6, 0xF7, 0xF9, 0xFA, 0xFE, 0xFF]
+)
+CB_OPCODES = list(range(0x10, 0x20)) + list(range(0x38, 0x80))
+
+
+def random_state(rng):
+    state = {name: rng.randrange(256) for name in "abcdehl"}
+    state.update(pc=rng.randrange(0x10000), sp=rng.randrange(0x10000), f=rng.randrange(16) << 4,
+                 ime=rng.randrange(2), ie=0)
+    return state
+
+
+def generate_case(rng, opcode, name):
+    while True:
+        cpu = Cpu(rng, random_state(rng))
+        initial = dict(cpu.r)
+
+        for i, byte in enumerate(opcode):
+            cpu.ram[(initial["pc"] + i) & 0xFFFF] = cpu.initial_ram[(initial["pc"] + i) & 0xFFFF] = byte
+
+        cpu.execute(cpu.fetch())
+
+        # IE is part of the state, not of the RAM
+        if 0xFFFF not in cpu.ram:
+            break
+
+    order = ["pc", "sp", "a", "b", "c", "d", "e", "f", "h", "l", "ime", "ie"]
+    return {
+        "name": name,
+        "initial": dict({key: initial[key] for key in order}, ram=sorted([addr, value] for addr, value in cpu.initial_ram.items())),
+        "final": dict({key: cpu.r[key] for key in order}, ram=sorted([addr, value] for addr, value in cpu.ram.items())),
+        "cycles": cpu.cycles,
+    }
+
+
+def write(dir, file_name, cases):
+    with open(os.path.join(dir, file_name), "w") as file:
+        file.write("[\n" + ",\n".join(json.dumps(case) for case in cases) + "\n]\n")
+
+
+def main():
+    dir = os.path.dirname(os.path.abspath(__file__))
+    rng = random.Random(SEED)
+
+    for opcode in OPCODES:
+        prefix = "{:02x}".format(opcode)
+        write(dir, prefix + ".json", [generate_case(rng, [opcode], "{} {:04}".format(prefix, i)) for i in range(CASES)])
+
+    for opcode in CB_OPCODES:
+        prefix = "cb {:02x}".format(opcode)
+        write(dir, prefix + ".json", [generate_case(rng, [0xCB, opcode], "{} {:04}".format(prefix, i)) for i in range(CASES)])
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,50 @@
+#!/usr/bin/env python3
+"""Downloads the subset of the SingleStepTests SM83 vectors that is checked in here,
+together with their license.
+
+    python3 tests/sm83/upstream/fetch.py [ref]
+
+`ref` is a commit or branch of https://github.com/SingleStepTests/sm83 and defaults to
+`main`. Record the commit in README.md when updating the files.
+"""
+
+import os
+import sys
+import urllib.parse
+import urllib.request
+
+REPOSITORY = "https://raw.githubusercontent.com/SingleStepTests/sm83"
+
+# At least one opcode of every kind of instruction the CPU implements, see OPCODES in
+# ../generated/generate.py: loads, 16 bit loads and arithmetic, ALU with registers,
+# memory and immediates, jumps, calls, returns, the stack and the CB prefixed rotates and bits
+FILES = [
+    "00", "01", "02", "03", "06", "08", "09", "0a", "0e", "12", "18", "1f", "20", "22",
+    "31", "34", "36", "3a", "41", "46", "70", "7e", "80", "88", "8e", "90", "a0", "a8",
+    "b0", "b8", "be", "c0", "c1", "c3", "c4", "c5", "c6", "c9", "cd", "ce", "d6", "d9",
+    "e0", "e2", "e6", "e9", "ea", "ee", "f0", "f1", "f5", "f6", "f9", "fa", "fe",
+    "cb 10", "cb 11", "cb 19", "cb 1e", "cb 38", "cb 3e", "cb 46", "cb 7f",
+]
+
+
+def fetch(ref, path):
+    url = "{}/{}/{}".format(REPOSITORY, ref, urllib.parse.quote(path))
+
+    with urllib.request.urlopen(url) as response:
+        return response.read()
+
+
+def main():
+    ref = sys.argv[1] if len(sys.argv) > 1 else "main"
+    dir = os.path.dirname(os.path.abspath(__file__))
+
+    with open(os.path.join(dir, "LICENSE"), "wb") as file:
+        file.write(fetch(ref, "LICENSE"))
+
+    for name in FILES:
+        with open(os.path.join(dir, name + ".json"), "wb") as file:
+            file.write(fetch(ref, "v1/{}.json".format(name)))
+
+
+if __name__ == "__main__":
+    main()
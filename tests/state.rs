@@ -0,0 +1,128 @@
+//! Save states: round trips and the states that have to be rejected.
+
+use good_boi::{Core, Bus, Cartridge, StateError};
+
+const PROGRAM: &[u8] = &[
+    0x31, 0x00, 0xD0, // 0100: ld sp, $D000
+    0x3E, 0x42,       // 0103: ld a, $42
+    0xEA, 0x00, 0xC0, // 0105: ld [$C000], a
+    0x3C,             // 0108: inc a
+    0xEA, 0x01, 0xC0, // 0109: ld [$C001], a
+    0x18, 0xF5,       // 010C: jr $0103
+];
+
+/// Offset of the version in the header, after the magic
+const VERSION_OFFSET: usize = 8;
+
+fn load(title: &str) -> Core {
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x100 + PROGRAM.len()].copy_from_slice(PROGRAM);
+    rom[0x134..0x134 + title.len()].copy_from_slice(title.as_bytes());
+
+    Core::new(Bus::new(Cartridge::load(rom).unwrap()))
+}
+
+fn run(core: &mut Core, instructions: usize) {
+    for _ in 0..instructions {
+        core.step().unwrap();
+    }
+}
+
+fn state_error(result: failure::Fallible<()>) -> StateError {
+    match result {
+        Ok(()) => panic!("State should have been rejected"),
+        Err(err) => err.downcast::<StateError>().unwrap(),
+    }
+}
+
+#[test]
+fn load_restores_saved_state() {
+    let mut core = load("STATE");
+    run(&mut core, 3);
+    let state = core.save_state();
+    let trace = core.trace_line();
+
+    run(&mut core, 3);
+    assert_eq!(core.peek_mem_u8(0xC001), 0x43);
+
+    core.load_state(&state).unwrap();
+    assert_eq!(core.trace_line(), trace);
+    assert_eq!(core.peek_mem_u8(0xC000), 0x42);
+    assert_ne!(core.peek_mem_u8(0xC001), 0x43);
+    assert_eq!(core.save_state(), state);
+
+    // Runs on like the first time
+    run(&mut core, 2);
+    assert_eq!(core.peek_mem_u8(0xC001), 0x43);
+}
+
+#[test]
+fn states_of_other_roms_are_rejected() {
+    let mut core = load("STATE");
+    let state = load("OTHER").save_state();
+
+    match state_error(core.load_state(&state)) {
+        StateError::RomMismatch { state_title, rom_title, .. } => {
+            assert_eq!(state_title, "OTHER");
+            assert_eq!(rom_title, "STATE");
+        },
+        err => panic!("Expected a ROM mismatch, got {:?}", err),
+    }
+}
+
+#[test]
+fn other_versions_are_rejected() {
+    let mut core = load("STATE");
+    let mut state = core.save_state();
+    state[VERSION_OFFSET] = state[VERSION_OFFSET].wrapping_add(1);
+
+    match state_error(core.load_state(&state)) {
+        StateError::UnsupportedVersion { found, .. } => assert_eq!(found, good_boi::STATE_VERSION + 1),
+        err => panic!("Expected an unsupported version, got {:?}", err),
+    }
+}
+
+#[test]
+fn bad_states_change_nothing() {
+    let mut core = load("STATE");
+    let state = core.save_state();
+    run(&mut core, 3);
+    let trace = core.trace_line();
+
+    let mut truncated = state.clone();
+    truncated.pop();
+    assert!(matches!(state_error(core.load_state(&truncated)), StateError::UnexpectedEnd));
+
+    let mut trailing = state.clone();
+    trailing.push(0);
+    assert!(matches!(state_error(core.load_state(&trailing)), StateError::Corrupted(_)));
+
+    assert_eq!(core.trace_line(), trace);
+    assert_eq!(core.peek_mem_u8(0xC000), 0x42);
+}
+
+#[test]
+fn invalid_player_count_is_rejected() {
+    let mut core = load("STATE");
+    let mut state = core.save_state();
+
+    // Magic, version, title, checksum, the CPU registers and flags,
+    // then the joypad's select and pressed buttons
+    let player_count = 8 + 2 + 4 + "STATE".len() + 2 + 6 * 2 + 2 + 1 + 4;
+    assert_eq!(state[player_count], 1);
+    state[player_count] = 3;
+
+    assert!(matches!(state_error(core.load_state(&state)), StateError::Corrupted(_)));
+}
+
+#[test]
+fn loading_keeps_the_ly_stub() {
+    let mut core = load("STATE");
+    core.bus_mut().stub_ly(Some(0x90));
+    let state = core.save_state();
+
+    run(&mut core, 3);
+    core.load_state(&state).unwrap();
+
+    assert_eq!(core.peek_mem_u8(0xFF44), 0x90);
+}
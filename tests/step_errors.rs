@@ -0,0 +1,53 @@
+//! Instructions that fail must not have any effect.
+
+use good_boi::{Core, Bus, Cartridge, StepError, WatchKind, Timeline};
+
+fn load(program: &[u8]) -> Core {
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x100 + program.len()].copy_from_slice(program);
+
+    Core::new(Bus::new(Cartridge::load(rom).unwrap()))
+}
+
+fn unmapped_addr(err: StepError) -> (WatchKind, u16) {
+    match err {
+        StepError::UnmappedAccess { access, addr, .. } => (access, addr),
+        err => panic!("Expected an unmapped access, got {:?}", err),
+    }
+}
+
+#[test]
+fn failed_writes_leave_registers_alone() {
+    // ld [hl+], a
+    let mut core = load(&[0x22]);
+    core.set_reg_hl(0xFEA0);
+    let trace = core.trace_line();
+
+    assert_eq!(unmapped_addr(core.step().unwrap_err()), (WatchKind::Write, 0xFEA0));
+    assert_eq!(core.trace_line(), trace);
+}
+
+#[test]
+fn failed_writes_write_nothing() {
+    // ld [$FDFF], sp: $FDFF mirrors $DDFF, but nothing is mapped at $FE00
+    let mut core = load(&[0x08, 0xFF, 0xFD]);
+    let before = core.peek_mem_u8(0xDDFF);
+    core.set_sp(0x1234);
+
+    assert_eq!(unmapped_addr(core.step().unwrap_err()), (WatchKind::Write, 0xFE00));
+    assert_eq!(core.peek_mem_u8(0xDDFF), before);
+    assert_eq!(core.pc(), 0x100);
+}
+
+#[test]
+fn unmapped_opcodes_are_not_executed() {
+    let mut core = load(&[]);
+    core.set_pc(0xFEA0);
+    let mut timeline = Timeline::new(10, 10);
+
+    // Used to be read as open bus 0xFF, which is `rst $38`
+    assert_eq!(unmapped_addr(timeline.step(&mut core).unwrap_err()), (WatchKind::Read, 0xFEA0));
+    assert_eq!(core.pc(), 0xFEA0);
+    assert_eq!(core.sp(), 0xFFFE);
+    assert_eq!(timeline.position(), 0);
+}
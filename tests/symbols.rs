@@ -0,0 +1,49 @@
+//! Symbols in the upper half of WRAM, which RGBDS puts in bank 1.
+
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use good_boi::{Core, Bus, Cartridge, Symbols, BankAddr};
+
+const SYMBOLS: &str = "\
+00:C000 wLow
+01:D000 wHigh
+";
+
+#[test]
+fn upper_wram_symbols_are_in_bank_1() {
+    let mut core = Core::new(Bus::new(Cartridge::load(vec![0; 0x8000]).unwrap()));
+    core.set_symbols(Symbols::parse(SYMBOLS).unwrap());
+
+    assert_eq!(core.bank_addr(0xC000), BankAddr { bank: 0, addr: 0xC000 });
+    assert_eq!(core.bank_addr(0xD000), BankAddr { bank: 1, addr: 0xD000 });
+    assert_eq!(core.bank_addr(0xF000), BankAddr { bank: 1, addr: 0xF000 });
+    assert_eq!(core.symbols().name(core.bank_addr(0xD000)), Some("wHigh"));
+    assert_eq!(core.format_addr(0xD002), "01:D002 <wHigh+2>");
+    assert_eq!(core.format_addr(0xC000), "00:C000 <wLow>");
+}
+
+#[test]
+fn debugger_accepts_upper_wram_symbols() {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("symbols");
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("test.gb"), vec![0; 0x8000]).unwrap();
+    fs::write(dir.join("test.sym"), SYMBOLS).unwrap();
+
+    let mut debugger = Command::new(env!("CARGO_BIN_EXE_good_boi"))
+        .arg(dir.join("test.gb"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let commands = format!("sym {}\nw wHigh 42\np 01:D000\nq\n", dir.join("test.sym").display());
+    debugger.stdin.take().unwrap().write_all(commands.as_bytes()).unwrap();
+
+    let output = debugger.wait_with_output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert!(!stdout.contains("is not mapped"), "{}", stdout);
+    assert!(stdout.contains("[01:D000 <wHigh>] = 42"), "{}", stdout);
+}
@@ -0,0 +1,92 @@
+//! Going back and forth on the timeline, across its delta-encoded checkpoints.
+
+use good_boi::{Core, Bus, Cartridge, Timeline, Tracer};
+
+/// `inc b; ld [hl+], a; inc a; jr -5`
+fn load() -> Core {
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x105].copy_from_slice(&[0x04, 0x22, 0x3C, 0x18, 0xFB]);
+
+    let mut core = Core::new(Bus::new(Cartridge::load(rom).unwrap()));
+    core.set_reg_hl(0xC000);
+    core
+}
+
+#[test]
+fn seek_restores_every_position() {
+    let mut core = load();
+    // More checkpoints than kept, so the oldest ones are dropped
+    let mut timeline = Timeline::new(3, 40);
+    let mut traces = Vec::new();
+
+    for _ in 0..300 {
+        traces.push((core.trace_line(), core.peek_mem_u8(0xC000 + traces.len() as u16 / 4)));
+        timeline.step(&mut core).unwrap();
+    }
+
+    let earliest = timeline.earliest_position();
+    assert!(earliest > 0);
+
+    for position in (earliest..300).rev().step_by(7).chain(earliest..earliest + 5) {
+        timeline.seek(&mut core, position).unwrap();
+
+        let (trace, memory) = &traces[position as usize];
+        assert_eq!(&core.trace_line(), trace, "at {}", position);
+        assert_eq!(core.peek_mem_u8(0xC000 + position as u16 / 4), *memory, "at {}", position);
+    }
+
+    assert!(timeline.seek(&mut core, earliest - 1).is_err());
+}
+
+/// `call $0108; jr -5` and at $0108 `inc b; ret`
+#[test]
+fn seek_restores_the_call_stack() {
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x105].copy_from_slice(&[0xCD, 0x08, 0x01, 0x18, 0xFB]);
+    rom[0x108..0x10A].copy_from_slice(&[0x04, 0xC9]);
+
+    let mut core = Core::new(Bus::new(Cartridge::load(rom).unwrap()));
+    let mut timeline = Timeline::new(4, 10);
+    let mut call_stacks = Vec::new();
+
+    for _ in 0..30 {
+        call_stacks.push(core.call_stack().frames().to_vec());
+        timeline.step(&mut core).unwrap();
+    }
+
+    for position in (0..30).rev() {
+        timeline.seek(&mut core, position).unwrap();
+        assert_eq!(core.call_stack().frames(), call_stacks[position as usize].as_slice(), "at {}", position);
+    }
+
+    // Save states don't carry debugger state, so loading one forgets the calls
+    timeline.seek(&mut core, 1).unwrap();
+    assert_eq!(core.call_stack().depth(), 1);
+    let state = core.save_state();
+    core.load_state(&state).unwrap();
+    assert_eq!(core.call_stack().depth(), 0);
+}
+
+#[test]
+fn replayed_instructions_are_traced_once() {
+    let mut core = load();
+    let mut timeline = Timeline::new(4, 10);
+    let mut tracer = Tracer::new();
+    tracer.set_history_len(100);
+    core.set_tracer(Some(tracer));
+
+    for _ in 0..20 {
+        timeline.step(&mut core).unwrap();
+    }
+
+    let traced = core.tracer().unwrap().history().map(String::from).collect::<Vec<_>>();
+
+    timeline.seek(&mut core, 5).unwrap();
+    timeline.step_back(&mut core).unwrap();
+    timeline.seek(&mut core, 20).unwrap();
+    assert_eq!(core.tracer().unwrap().history().collect::<Vec<_>>(), traced);
+
+    // Only new instructions are traced
+    timeline.step(&mut core).unwrap();
+    assert_eq!(core.tracer().unwrap().history().count(), 21);
+}
@@ -0,0 +1,54 @@
+//! `good_boi trace-diff` against hand-written Gameboy Doctor logs.
+
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+/// Starts from the DMG post-boot registers, which differ from ours,
+/// and reads LY, which Gameboy Doctor expects to be $90
+const REFERENCE: &str = "\
+A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:FA,44,FF,04
+A:90 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0103 PCMEM:04,18,FA,00
+A:90 F:10 B:01 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0104 PCMEM:18,FA,00,00
+A:90 F:10 B:01 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:FA,44,FF,04
+A:90 F:10 B:01 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0103 PCMEM:04,18,FA,00
+A:90 F:10 B:02 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0104 PCMEM:18,FA,00,00
+";
+
+/// Runs trace-diff on `ld a, [$FF44]; inc b; jr -6` against `reference`
+fn trace_diff(name: &str, reference: &str) -> Output {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("trace_diff");
+    fs::create_dir_all(&dir).unwrap();
+
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x106].copy_from_slice(&[0xFA, 0x44, 0xFF, 0x04, 0x18, 0xFA]);
+    fs::write(dir.join("test.gb"), rom).unwrap();
+    fs::write(dir.join(name), reference).unwrap();
+
+    Command::new(env!("CARGO_BIN_EXE_good_boi"))
+        .arg("trace-diff")
+        .arg(dir.join("test.gb"))
+        .arg(dir.join(name))
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn matching_trace_passes() {
+    let output = trace_diff("matching.log", REFERENCE);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert!(output.status.success(), "{}", stdout);
+    assert!(stdout.contains("All 6 instructions match."), "{}", stdout);
+}
+
+#[test]
+fn first_difference_is_reported() {
+    let reference = REFERENCE.replacen("F:10 B:01", "F:10 B:03", 1);
+    let output = trace_diff("differing.log", &reference);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert!(!output.status.success(), "{}", stdout);
+    assert!(stdout.contains("First difference at line 3, after 2 matching instructions:"), "{}", stdout);
+    assert!(stdout.contains("B: expected 03, got 01"), "{}", stdout);
+}